@@ -0,0 +1,370 @@
+use chrono::Utc;
+use log::{debug, info, warn};
+use teloxide::{
+    prelude::*,
+    types::{ChatMember, ChatMemberKind, Message},
+};
+
+use crate::{
+    buttons::MessageButtons,
+    routes::{self, StoredRoute},
+    sinks::OutgoingMessage,
+    types::{MessageKind, MessageSource, MyResult, ProtectedContentPolicy},
+    utils::make_error,
+    BOT, MIRROR_ADMINS,
+};
+
+const HELP: &str = "/mirror_add <webhook url> [protected=mirror|text-only|skip] - mirror this \
+    chat to a Discord webhook, optionally saying what to do with protected posts\n\
+    /mirror_list - show the added routes\n\
+    /mirror_remove <route> - stop mirroring through a route\n\
+    /mirror_pause <route> - pause a route, or resume it if it's paused\n\
+    /mirror_test <route> - send a test message through a route\n\
+    /mirror_help - show this\n\n\
+    In a DM, /mirror_add also takes the ID of the chat to mirror after the URL, \
+    and /mirror_list can take one to only show that chat's routes. \
+    In a channel, posts have to be signed to run anything but /mirror_list.";
+
+/// Every command there is, an unknown `/mirror_` word is just a post
+const COMMANDS: [&str; 6] = [
+    "/mirror_add",
+    "/mirror_list",
+    "/mirror_remove",
+    "/mirror_pause",
+    "/mirror_test",
+    "/mirror_help",
+];
+
+/// The commands that only look at routes, which is all a channel post nobody signed can run
+const READ_ONLY_COMMANDS: [&str; 2] = ["/mirror_list", "/mirror_help"];
+
+/// Whether a message is one of the `/mirror_` commands, which never get mirrored themselves
+pub fn is_mirror_command(m: &Message) -> bool {
+    m.text().and_then(command_name).is_some()
+}
+
+/// The command a message starts with, without the bot's username groups can add to it
+fn command_name(text: &str) -> Option<&str> {
+    let command = text.split_whitespace().next()?.split('@').next()?;
+    COMMANDS.contains(&command).then(|| command)
+}
+
+/// How much of the commands the sender of one gets to run
+#[derive(Debug, PartialEq)]
+enum Access {
+    /// Someone in `MIRROR_ADMINS`
+    Full(u64),
+    /// An unsigned channel post in a channel one of `MIRROR_ADMINS` administers. Any of the
+    /// channel's admins could have posted it, so it only gets to look.
+    ReadOnly,
+    None,
+}
+
+impl Access {
+    fn allows(&self, command: &str) -> bool {
+        match self {
+            Access::Full(_) => true,
+            Access::ReadOnly => READ_ONLY_COMMANDS.contains(&command),
+            Access::None => false,
+        }
+    }
+}
+
+/// A chat administrator, by the names a channel post could be signed with
+#[derive(Debug)]
+struct Signer {
+    user_id: u64,
+    names: Vec<String>,
+}
+
+impl From<&ChatMember> for Signer {
+    fn from(member: &ChatMember) -> Self {
+        let custom_title = match &member.kind {
+            ChatMemberKind::Owner(owner) => owner.custom_title.clone(),
+            ChatMemberKind::Administrator(admin) => admin.custom_title.clone(),
+            _ => None,
+        };
+        Signer {
+            user_id: member.user.id.0,
+            names: std::iter::once(member.user.full_name())
+                .chain(custom_title)
+                .collect(),
+        }
+    }
+}
+
+/// Work out who sent a command. Channel posts only say who wrote them through their signature,
+/// so one counts as a mirror admin's when it's signed with a name only that admin goes by.
+fn access(
+    from: Option<u64>,
+    signature: Option<&str>,
+    channel_admins: &[Signer],
+    mirror_admins: &[u64],
+) -> Access {
+    if let Some(user_id) = from {
+        return if mirror_admins.contains(&user_id) {
+            Access::Full(user_id)
+        } else {
+            Access::None
+        };
+    }
+
+    if let Some(signature) = signature {
+        let mut signers = channel_admins
+            .iter()
+            .filter(|admin| admin.names.iter().any(|name| name == signature));
+        if let (Some(signer), None) = (signers.next(), signers.next()) {
+            if mirror_admins.contains(&signer.user_id) {
+                return Access::Full(signer.user_id);
+            }
+        }
+    }
+    if channel_admins
+        .iter()
+        .any(|admin| mirror_admins.contains(&admin.user_id))
+    {
+        Access::ReadOnly
+    } else {
+        Access::None
+    }
+}
+
+/// Route management commands, for the admins in `MIRROR_ADMINS`.
+///
+/// Sent in a DM they can manage any chat's routes, sent in a group or channel they only touch that
+/// chat's.
+pub async fn command_handler(m: Message) -> MyResult<()> {
+    let text = m.text().unwrap_or_default();
+    let command = match command_name(text) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    let bot = BOT
+        .get()
+        .ok_or_else(|| make_error("Failed to get ref to Bot"))?;
+
+    // Only channel posts need the admin list, anything else says who sent it
+    let channel_admins: Vec<Signer> = if m.from().is_none() && m.chat.is_channel() {
+        bot.get_chat_administrators(m.chat.id)
+            .await?
+            .iter()
+            .map(Signer::from)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let access = access(
+        m.from().map(|user| user.id.0),
+        m.author_signature(),
+        &channel_admins,
+        &MIRROR_ADMINS,
+    );
+    if !access.allows(command) {
+        debug!("Ignoring {} from someone who isn't an admin", command);
+        return Ok(());
+    }
+    let sender = match access {
+        Access::Full(user_id) => user_id.to_string(),
+        _ => format!("An unsigned post in {}", m.chat.id),
+    };
+
+    let args: Vec<&str> = text.split_whitespace().skip(1).collect();
+    info!("{} ran {} {:?}", sender, command, args);
+
+    let reply = run_command(&m, command, &args)
+        .await
+        .unwrap_or_else(|err| format!("That didn't work: {}", err));
+
+    bot.send_message(m.chat.id, reply)
+        .reply_to_message_id(m.id)
+        .await?;
+
+    // Anyone holding the webhook URL can post through it, so don't leave it in the chat
+    if command == "/mirror_add" && !m.chat.is_private() {
+        if let Err(err) = bot.delete_message(m.chat.id, m.id).await {
+            warn!(
+                "Couldn't delete the /mirror_add in {}, the webhook URL is still there: {}",
+                m.chat.id, err
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn run_command(m: &Message, command: &str, args: &[&str]) -> MyResult<String> {
+    // Outside of DMs, commands only ever apply to the chat they're sent in
+    let scope = if m.chat.is_private() {
+        None
+    } else {
+        Some(m.chat.id)
+    };
+
+    match command {
+        "/mirror_add" => {
+            // `protected=<policy>` can go anywhere after the command
+            let mut protected_content = ProtectedContentPolicy::default();
+            let mut rest = Vec::new();
+            for arg in args {
+                match arg.strip_prefix("protected=") {
+                    Some(policy) => protected_content = policy.parse()?,
+                    None => rest.push(*arg),
+                }
+            }
+            let args = rest;
+            let webhook_url = args
+                .first()
+                .ok_or_else(|| make_error("Which webhook URL should it mirror to?"))?;
+            let chat_id = match scope {
+                Some(chat_id) => chat_id,
+                None => ChatId(
+                    args.get(1)
+                        .ok_or_else(|| make_error("Which chat ID should be mirrored?"))?
+                        .parse()?,
+                ),
+            };
+            let route = routes::add(chat_id, webhook_url, false, protected_content, false).await?;
+            Ok(format!("Added {}", describe(&route)))
+        }
+        "/mirror_list" => {
+            let scope = match (scope, args.first()) {
+                (None, Some(chat_id)) => Some(ChatId(chat_id.parse()?)),
+                (scope, _) => scope,
+            };
+            let routes = routes::list(scope);
+            if routes.is_empty() {
+                return Ok("No routes have been added".to_string());
+            }
+            Ok(routes.iter().map(describe).collect::<Vec<_>>().join("\n"))
+        }
+        "/mirror_remove" => {
+            let route = routes::remove(route_id(args)?, scope)?;
+            Ok(format!("Removed {}", describe(&route)))
+        }
+        "/mirror_pause" => {
+            let id = route_id(args)?;
+            let paused = routes::list(scope)
+                .iter()
+                .find(|route| route.id == id)
+                .map_or(false, |route| route.paused);
+            let route = routes::set_paused(id, scope, !paused)?;
+            Ok(format!(
+                "{} {}",
+                if route.paused { "Paused" } else { "Resumed" },
+                describe(&route)
+            ))
+        }
+        "/mirror_test" => {
+            let id = route_id(args)?;
+            let sink = routes::sink(id, scope)?;
+            let source = MessageSource {
+                chat_id: m.chat.id,
+                chat_title: m.chat.title().map(|s| s.to_string()),
+                chat_username: m.chat.username().map(|s| s.to_string()),
+                message_id: m.id,
+                date: Utc::now(),
+                kind: MessageKind::Text,
+                forwarded: false,
+                author_signature: None,
+                silent: false,
+                protected: false,
+                edited_at: None,
+            };
+            let buttons = MessageButtons::default();
+            sink.send(&OutgoingMessage {
+                text: Some(format!("Test message for route #{} of the mirror", id)),
+                html: None,
+                attachments: Vec::new(),
+                entities: &[],
+                buttons: &buttons,
+                source: &source,
+            })
+            .await?;
+            Ok(format!("Sent a test message through route #{}", id))
+        }
+        _ => Ok(HELP.to_string()),
+    }
+}
+
+fn route_id(args: &[&str]) -> MyResult<u32> {
+    let id = args
+        .first()
+        .ok_or_else(|| make_error("Which route? /mirror_list shows their numbers"))?;
+    Ok(id.trim_start_matches('#').parse()?)
+}
+
+fn describe(route: &StoredRoute) -> String {
+    let protected = match route.protected_content {
+        ProtectedContentPolicy::Mirror => "",
+        ProtectedContentPolicy::TextOnly => ", protected posts without media",
+        ProtectedContentPolicy::Skip => ", skipping protected posts",
+    };
+    format!(
+        "route #{}: {} to {}{}{}",
+        route.id,
+        route.chat_id,
+        route.name,
+        protected,
+        if route.paused { " (paused)" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(user_id: u64, names: &[&str]) -> Signer {
+        Signer {
+            user_id,
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(command_name("/mirror_add https://x"), Some("/mirror_add"));
+        assert_eq!(
+            command_name("/mirror_list@mirror_bot"),
+            Some("/mirror_list")
+        );
+        assert_eq!(command_name("  /mirror_help"), Some("/mirror_help"));
+        assert_eq!(command_name("/mirrors are great"), None);
+        assert_eq!(command_name("/mirror"), None);
+        assert_eq!(command_name("/mirror_addx"), None);
+        assert_eq!(command_name("check /mirror_add"), None);
+        assert_eq!(command_name(""), None);
+    }
+
+    #[test]
+    fn senders_need_to_be_mirror_admins() {
+        assert_eq!(access(Some(1), None, &[], &[1]), Access::Full(1));
+        assert_eq!(access(Some(2), None, &[], &[1]), Access::None);
+        // A user can't pass for an admin by signing as one
+        let admins = [signer(1, &["Alice"])];
+        assert_eq!(access(Some(2), Some("Alice"), &admins, &[1]), Access::None);
+    }
+
+    #[test]
+    fn channel_posts_go_by_signature() {
+        let admins = [signer(1, &["Alice", "Editor"]), signer(2, &["Bob"])];
+        assert_eq!(access(None, Some("Alice"), &admins, &[1]), Access::Full(1));
+        assert_eq!(access(None, Some("Editor"), &admins, &[1]), Access::Full(1));
+        // Other admins of the channel only get to look
+        assert_eq!(access(None, Some("Bob"), &admins, &[1]), Access::ReadOnly);
+        assert_eq!(access(None, None, &admins, &[1]), Access::ReadOnly);
+        // A name two admins share doesn't say which one posted
+        let shared = [signer(1, &["Sam"]), signer(2, &["Sam"])];
+        assert_eq!(access(None, Some("Sam"), &shared, &[1]), Access::ReadOnly);
+        // Nobody vouches for channels without a mirror admin
+        assert_eq!(access(None, Some("Bob"), &admins, &[3]), Access::None);
+    }
+
+    #[test]
+    fn read_only_access_can_only_look() {
+        assert!(Access::ReadOnly.allows("/mirror_list"));
+        assert!(Access::ReadOnly.allows("/mirror_help"));
+        assert!(!Access::ReadOnly.allows("/mirror_add"));
+        assert!(!Access::ReadOnly.allows("/mirror_remove"));
+        assert!(Access::Full(1).allows("/mirror_remove"));
+        assert!(!Access::None.allows("/mirror_list"));
+    }
+}
@@ -3,111 +3,71 @@ use std::{
     io::{self, ErrorKind},
 };
 
-use log::debug;
-use mime_to_ext::MIME_DATA_MAP;
+use teloxide::types::PhotoSize;
 
 use crate::{
-    types::{Attachment, MyResult, TelegramMessageData},
-    utils::make_error,
+    file_types::{name_by_unique_id, name_or_unique_id},
+    types::{Attachment, MyResult, PhotoSizePolicy, PostProcess, TelegramMessageData},
 };
 
-pub fn get_audio_attachments<'a>(
-    message_data: &TelegramMessageData<'a>,
-) -> Result<Vec<Attachment<'a>>, Box<dyn Error + Sync + Send>> {
+pub fn get_audio_attachments(
+    message_data: &TelegramMessageData,
+) -> Result<Vec<Attachment>, Box<dyn Error + Sync + Send>> {
     let mut attachments = Vec::new();
 
     if let Some(audio) = message_data.audio {
-        let file_ext = match audio.mime_type.clone() {
-            Some(mime) => {
-                let mime_type = format!("{}/{}", mime.type_(), mime.subtype());
-                let ext = match MIME_DATA_MAP.get(&mime_type) {
-                    Some(mime_data) => {
-                        debug!(
-                            "Found mime type `{}`, writing ext `{}`",
-                            mime_type, mime_data.ext
-                        );
-                        mime_data.ext.as_str()
-                    }
-                    None => {
-                        return Err(Box::new(io::Error::new(
-                            ErrorKind::Other,
-                            format!("Could not look up mime type `{}`", mime_type),
-                        )));
-                    }
-                };
-                ext
-            }
-            None => {
-                return Err(Box::new(io::Error::new(
-                    ErrorKind::Other,
-                    "Failed to get mime type for audio",
-                )));
-            }
-        };
-
-        let filename = format!("{}{}", audio.file_unique_id, file_ext);
+        let filename = name_by_unique_id(
+            &audio.file_unique_id,
+            audio.mime_type.as_ref(),
+            audio.file_name.as_deref(),
+        );
 
         attachments.push(Attachment::new(
             filename,
             audio.file_id.clone(),
+            audio.file_unique_id.clone(),
             audio.file_size,
         ));
     }
     Ok(attachments)
 }
 
-pub fn get_file_attachments<'a>(
-    message_data: &TelegramMessageData<'a>,
-) -> Result<Vec<Attachment<'a>>, Box<dyn Error + Sync + Send>> {
+pub fn get_file_attachments(
+    message_data: &TelegramMessageData,
+) -> Result<Vec<Attachment>, Box<dyn Error + Sync + Send>> {
     let mut attachments = Vec::new();
 
     if let Some(file) = message_data.file {
-        let file_ext = match file.mime_type.clone() {
-            Some(mime) => {
-                let mime_type = format!("{}/{}", mime.type_(), mime.subtype());
-                let ext = match MIME_DATA_MAP.get(&mime_type) {
-                    Some(mime_data) => {
-                        debug!(
-                            "Found mime type `{}`, writing ext `{}`",
-                            mime_type, mime_data.ext
-                        );
-                        mime_data.ext.as_str()
-                    }
-                    None => {
-                        return Err(Box::new(io::Error::new(
-                            ErrorKind::Other,
-                            format!("Could not look up ext for file mime type `{}`", mime_type),
-                        )));
-                    }
-                };
-                ext
-            }
-            None => {
-                return Err(Box::new(io::Error::new(
-                    ErrorKind::Other,
-                    "Failed to get mime type for file",
-                )));
-            }
-        };
+        let filename = name_or_unique_id(
+            &file.file_unique_id,
+            file.mime_type.as_ref(),
+            file.file_name.as_deref(),
+        );
 
-        let filename = match &file.file_name {
-            Some(filename) => filename.to_owned(),
-            None => format!("{}{}", file.file_unique_id, file_ext),
+        // Images sent as files skip telegram's compression, so they may need ours. GIFs would
+        // lose their animation, so those are left alone.
+        let post_process = match &file.mime_type {
+            Some(mime) if mime.type_() == mime::IMAGE && mime.subtype() != mime::GIF => {
+                PostProcess::Image
+            }
+            _ => PostProcess::None,
         };
 
-        attachments.push(Attachment::new(
+        attachments.push(Attachment::with_post_process(
             filename,
             file.file_id.clone(),
+            file.file_unique_id.clone(),
             file.file_size,
+            post_process,
         ));
     }
     Ok(attachments)
 }
 
 /// Stickers have no mime type, so we have to guess the file extension I guess
-pub fn get_sticker_attachments<'a>(
-    message_data: &TelegramMessageData<'a>,
-) -> Result<Vec<Attachment<'a>>, Box<dyn Error + Sync + Send>> {
+pub fn get_sticker_attachments(
+    message_data: &TelegramMessageData,
+) -> Result<Vec<Attachment>, Box<dyn Error + Sync + Send>> {
     let mut attachments = Vec::new();
 
     if let Some(sticker) = message_data.sticker {
@@ -126,127 +86,216 @@ pub fn get_sticker_attachments<'a>(
         attachments.push(Attachment::new(
             filename,
             sticker.file_id.clone(),
+            sticker.file_unique_id.clone(),
             sticker.file_size,
         ));
     }
     Ok(attachments)
 }
 
-pub fn get_video_attachments<'a>(
-    message_data: &TelegramMessageData<'a>,
-) -> MyResult<Vec<Attachment<'a>>> {
+pub fn get_video_attachments(message_data: &TelegramMessageData) -> MyResult<Vec<Attachment>> {
     let mut attachments = Vec::new();
 
     if let Some(video) = message_data.video {
-        // Try to get the filename
-        let filename = match &video.file_name {
-            // Quick return if we have it
-            Some(filename) => {
-                debug!("Found video filename: {}", filename);
-                filename.to_owned()
-            }
-            None => {
-                // Otherwise, do a lott of work to get it
-                debug!("No filename found for video, trying to get it");
-                let file_ext = match video.mime_type.clone() {
-                    Some(mime) => {
-                        let mime_type = format!("{}/{}", mime.type_(), mime.subtype());
-                        let ext = match MIME_DATA_MAP.get(&mime_type) {
-                            Some(mime_data) => mime_data.ext.as_str(),
-                            None => {
-                                return Err(make_error(&format!(
-                                    "Could not look up ext for video mime type `{}`",
-                                    mime_type
-                                )));
-                            }
-                        };
-                        ext
-                    }
-                    None => {
-                        return Err(Box::new(io::Error::new(
-                            ErrorKind::Other,
-                            "Failed to get mime type for video",
-                        )));
-                    }
-                };
-
-                format!("{}{}", video.file_unique_id, file_ext)
-            }
-        };
-        attachments.push(Attachment::new(
+        let filename = name_or_unique_id(
+            &video.file_unique_id,
+            video.mime_type.as_ref(),
+            video.file_name.as_deref(),
+        );
+        attachments.push(Attachment::with_post_process(
             filename,
             video.file_id.clone(),
+            video.file_unique_id.clone(),
             video.file_size,
+            PostProcess::Video {
+                duration: video.duration,
+                width: video.width,
+                height: video.height,
+            },
         ));
     }
     Ok(attachments)
 }
 
-pub fn get_gif_attachments<'a>(
-    message_data: &TelegramMessageData<'a>,
-) -> MyResult<Vec<Attachment<'a>>> {
+pub fn get_gif_attachments(message_data: &TelegramMessageData) -> MyResult<Vec<Attachment>> {
     let mut attachments = Vec::new();
 
     if let Some(gif) = message_data.gif {
-        // Try to get the filename
-        let filename = match &gif.file_name {
-            // Quick return if we have it
-            Some(filename) => {
-                debug!("Found video filename: {}", filename);
-                filename.to_owned()
-            }
-            None => {
-                // Otherwise, do a lott of work to get it
-                debug!("No filename found for video, trying to get it");
-                let file_ext = match gif.mime_type.clone() {
-                    Some(mime) => {
-                        let mime_type = format!("{}/{}", mime.type_(), mime.subtype());
-                        let ext = match MIME_DATA_MAP.get(&mime_type) {
-                            Some(mime_data) => mime_data.ext.as_str(),
-                            None => {
-                                return Err(make_error(&format!(
-                                    "Could not look up ext for gif mime type `{}`",
-                                    mime_type
-                                )));
-                            }
-                        };
-                        ext
-                    }
-                    None => {
-                        return Err(Box::new(io::Error::new(
-                            ErrorKind::Other,
-                            "Failed to get mime type for gif",
-                        )));
-                    }
-                };
-
-                format!("{}{}", gif.file_unique_id, file_ext)
-            }
-        };
+        let filename = name_or_unique_id(
+            &gif.file_unique_id,
+            gif.mime_type.as_ref(),
+            gif.file_name.as_deref(),
+        );
         attachments.push(Attachment::new(
             filename,
             gif.file_id.clone(),
+            gif.file_unique_id.clone(),
             gif.file_size,
         ));
     }
     Ok(attachments)
 }
 
-pub fn get_photo_attachments<'a>(
-    message_data: &TelegramMessageData<'a>,
-) -> Option<Vec<Attachment<'a>>> {
-    if let Some(photo) = message_data.photos.and_then(|photos| photos.last()) {
+/// Pick one of telegram's sizes of a photo and download it.
+///
+/// `budget` is how many bytes are left in the message after the other attachments.
+pub fn get_photo_attachments(
+    message_data: &TelegramMessageData,
+    policy: PhotoSizePolicy,
+    budget: u64,
+) -> Option<Vec<Attachment>> {
+    if let Some(photo) = message_data
+        .photos
+        .and_then(|photos| select_photo_size(photos, policy, budget))
+    {
         let mut attachments = Vec::new();
 
         let filename = format!("{}.jpg", photo.file_unique_id);
 
-        attachments.push(Attachment::new(
+        // Telegram already compressed it, but the largest size can still be over the upload limit
+        attachments.push(Attachment::with_post_process(
             filename,
             photo.file_id.clone(),
+            photo.file_unique_id.clone(),
             photo.file_size,
+            PostProcess::Image,
         ));
 
         return Some(attachments);
     }
     None
 }
+
+/// Telegram sends photo sizes smallest first. When nothing satisfies the policy, the smallest
+/// size is used so the photo still makes it across.
+pub fn select_photo_size(
+    photos: &[PhotoSize],
+    policy: PhotoSizePolicy,
+    budget: u64,
+) -> Option<&PhotoSize> {
+    let fits = |photo: &&PhotoSize| match policy {
+        PhotoSizePolicy::Largest => true,
+        // Sizes telegram didn't tell us about are assumed to fit
+        PhotoSizePolicy::FitBudget => photo
+            .file_size
+            .map(|size| size as u64 <= budget)
+            .unwrap_or(true),
+        PhotoSizePolicy::MaxDimension(max) => photo.width.max(photo.height) <= max,
+    };
+
+    photos
+        .iter()
+        .filter(fits)
+        .max_by_key(|photo| photo.width as u64 * photo.height as u64)
+        .or_else(|| photos.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Telegram's usual sizes for a 1280x960 photo, smallest first
+    fn sizes() -> Vec<PhotoSize> {
+        [
+            (90, 68, 1_500),
+            (320, 240, 20_000),
+            (800, 600, 90_000),
+            (1280, 960, 200_000),
+        ]
+        .into_iter()
+        .map(|(width, height, file_size)| PhotoSize {
+            file_id: format!("id-{}", width),
+            file_unique_id: format!("unique-{}", width),
+            width,
+            height,
+            file_size: Some(file_size),
+        })
+        .collect()
+    }
+
+    fn width(photos: &[PhotoSize], policy: PhotoSizePolicy, budget: u64) -> Option<u32> {
+        select_photo_size(photos, policy, budget).map(|photo| photo.width)
+    }
+
+    #[test]
+    fn largest_ignores_the_budget() {
+        assert_eq!(width(&sizes(), PhotoSizePolicy::Largest, 0), Some(1280));
+    }
+
+    #[test]
+    fn fit_budget_takes_the_biggest_that_fits() {
+        let photos = sizes();
+        assert_eq!(
+            width(&photos, PhotoSizePolicy::FitBudget, 1_000_000),
+            Some(1280)
+        );
+        assert_eq!(
+            width(&photos, PhotoSizePolicy::FitBudget, 100_000),
+            Some(800)
+        );
+        assert_eq!(
+            width(&photos, PhotoSizePolicy::FitBudget, 90_000),
+            Some(800)
+        );
+        // Nothing fits, so the smallest still goes
+        assert_eq!(width(&photos, PhotoSizePolicy::FitBudget, 10), Some(90));
+    }
+
+    #[test]
+    fn unknown_sizes_are_assumed_to_fit() {
+        let mut photos = sizes();
+        photos[3].file_size = None;
+        assert_eq!(
+            width(&photos, PhotoSizePolicy::FitBudget, 100_000),
+            Some(1280)
+        );
+    }
+
+    #[test]
+    fn max_dimension_checks_both_sides() {
+        let photos = sizes();
+        assert_eq!(
+            width(&photos, PhotoSizePolicy::MaxDimension(800), 0),
+            Some(800)
+        );
+        assert_eq!(
+            width(&photos, PhotoSizePolicy::MaxDimension(799), 0),
+            Some(320)
+        );
+        let portrait: Vec<PhotoSize> = sizes()
+            .into_iter()
+            .map(|photo| PhotoSize {
+                width: photo.height,
+                height: photo.width,
+                ..photo
+            })
+            .collect();
+        assert_eq!(
+            width(&portrait, PhotoSizePolicy::MaxDimension(800), 0),
+            Some(600)
+        );
+    }
+
+    #[test]
+    fn no_sizes_means_no_photo() {
+        assert_eq!(width(&[], PhotoSizePolicy::Largest, 0), None);
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(
+            "largest".parse::<PhotoSizePolicy>().unwrap(),
+            PhotoSizePolicy::Largest
+        );
+        assert_eq!(
+            "fit-budget".parse::<PhotoSizePolicy>().unwrap(),
+            PhotoSizePolicy::FitBudget
+        );
+        assert_eq!(
+            "max-dimension:1024".parse::<PhotoSizePolicy>().unwrap(),
+            PhotoSizePolicy::MaxDimension(1024)
+        );
+        assert!("max-dimension:big".parse::<PhotoSizePolicy>().is_err());
+        assert!("smallest".parse::<PhotoSizePolicy>().is_err());
+    }
+}
@@ -0,0 +1,562 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use log::{info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{
+    ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageEntity, MessageEntityKind,
+};
+
+use crate::{
+    buttons::get_message_buttons,
+    digest,
+    file_types::resolve_sniffed_extension,
+    formatting::entities_to_html,
+    images::shrink_image,
+    media_cache::cache_put,
+    message_log::{self, LoggedMessage},
+    routes,
+    sinks::{send_to_destinations, ReadyMessage},
+    spool::{clear_spool_dir, link_or_copy, SpooledFile},
+    state_lock::StateLock,
+    transcode::shrink_video,
+    types::{Destination, DownloadedAttachment, FileData, MessageKind, MessageSource, MyResult},
+    utils::make_error,
+    SPOOL_THRESHOLD_BYTES, STATE_LOCK_PATH,
+};
+
+const USAGE: &str =
+    "Usage: tg_discord_mirror backfill <export dir or result.json> --chat <chat id> \
+    [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--delay-ms N] [--dry-run]";
+
+/// How far a backfill into a route has got, so an interrupted one can pick up where it stopped
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Progress {
+    last_message_id: i32,
+    /// Messages that didn't make it to every destination, tried again on the next run
+    #[serde(default)]
+    failed: Vec<i32>,
+    /// Sinks each failed message did make it to. The message log drops old posts first, so it
+    /// can't be trusted to still have backfilled ones.
+    #[serde(default)]
+    sent_to: HashMap<i32, Vec<String>>,
+    /// The date range the backfill was started with, resuming with another one would leave gaps
+    #[serde(default)]
+    from: Option<NaiveDate>,
+    #[serde(default)]
+    to: Option<NaiveDate>,
+}
+
+#[derive(Debug)]
+struct BackfillArgs {
+    export_dir: PathBuf,
+    result_path: PathBuf,
+    chat_id: ChatId,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    delay: Duration,
+    dry_run: bool,
+}
+
+/// The bits of a Telegram Desktop `result.json` we use
+#[derive(Debug, Deserialize)]
+struct Export {
+    name: Option<String>,
+    messages: Vec<ExportMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportMessage {
+    id: i32,
+    #[serde(rename = "type")]
+    kind: String,
+    date: String,
+    date_unixtime: Option<String>,
+    /// Only there for posts that were edited
+    edited_unixtime: Option<String>,
+    #[serde(default)]
+    text_entities: Vec<ExportEntity>,
+    photo: Option<String>,
+    file: Option<String>,
+    media_type: Option<String>,
+    duration_seconds: Option<u32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    inline_bot_buttons: Vec<Vec<ExportButton>>,
+    forwarded_from: Option<String>,
+    /// Signature on posts in signed channels
+    author: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportEntity {
+    #[serde(rename = "type")]
+    kind: String,
+    text: String,
+    href: Option<String>,
+    language: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportButton {
+    #[serde(rename = "type")]
+    kind: String,
+    text: String,
+    data: Option<String>,
+}
+
+/// Post the history from a Telegram Desktop export to one route's destinations
+pub async fn run_backfill(args: &[String]) -> MyResult<()> {
+    let args = parse_args(args)?;
+
+    let export: Export = serde_json::from_slice(&tokio::fs::read(&args.result_path).await?)?;
+    info!(
+        "Read {} messages from the export of {}",
+        export.messages.len(),
+        export.name.as_deref().unwrap_or("an unnamed chat")
+    );
+
+    routes::init(crate::build_channel_data().await).await?;
+    let channel = routes::channel(args.chat_id)
+        .ok_or_else(|| make_error("That chat isn't one of the configured routes"))?;
+
+    // A dry run leaves the state files alone, anything else can't share them with a running bot
+    let _state_lock = if args.dry_run {
+        None
+    } else {
+        Some(StateLock::acquire(&STATE_LOCK_PATH)?)
+    };
+    clear_spool_dir();
+    if !args.dry_run {
+        message_log::load()?;
+        // Posts for digests join the ones already waiting, rather than replacing them on disk
+        digest::load()?;
+    }
+
+    let progress_path = args
+        .export_dir
+        .join(format!("backfill_progress_{}.json", args.chat_id.0));
+    let mut progress: Progress = match tokio::fs::read(&progress_path).await {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(_) => Progress {
+            from: args.from,
+            to: args.to,
+            ..Progress::default()
+        },
+    };
+    if (progress.from, progress.to) != (args.from, args.to) {
+        return Err(make_error(&format!(
+            "{} is for a backfill from {} to {}, run it with the same --from and --to or delete \
+            the file to start over",
+            progress_path.display(),
+            describe_date(progress.from),
+            describe_date(progress.to)
+        )));
+    }
+    if progress.last_message_id > 0 {
+        info!(
+            "Resuming after message {} with {} to retry, delete {} to start over",
+            progress.last_message_id,
+            progress.failed.len(),
+            progress_path.display()
+        );
+    }
+
+    let (mut posted, mut skipped, mut failed) = (0, 0, 0);
+    for export_message in &export.messages {
+        let retrying = progress.failed.contains(&export_message.id);
+        if export_message.kind != "message"
+            || (export_message.id <= progress.last_message_id && !retrying)
+        {
+            continue;
+        }
+
+        let date = message_date(export_message)?;
+        let in_range = args
+            .from
+            .map_or(true, |from| date.naive_utc().date() >= from)
+            && args.to.map_or(true, |to| date.naive_utc().date() <= to);
+        if !in_range {
+            skipped += 1;
+            continue;
+        }
+
+        let message = ready_message(&args, &export, export_message, date).await;
+        if message.is_empty() {
+            skipped += 1;
+            continue;
+        }
+
+        if args.dry_run {
+            report(
+                &message,
+                &channel.destinations_for(message.filter_text(), &message.source),
+            );
+            posted += 1;
+            continue;
+        }
+
+        // A retry only goes to the destinations that didn't get it last time
+        let mut sent_to = progress
+            .sent_to
+            .remove(&export_message.id)
+            .unwrap_or_default();
+        let destinations: Vec<&Destination> = channel
+            .destinations_for(message.filter_text(), &message.source)
+            .into_iter()
+            .filter(|destination| !sent_to.contains(&destination.sink.name()))
+            .collect();
+
+        let (sent, result) = send_to_destinations(&message, &destinations).await;
+        progress.failed.retain(|id| *id != export_message.id);
+        sent_to.extend(sent.iter().map(|(sink_name, _)| sink_name.clone()));
+        match result {
+            Ok(()) => posted += 1,
+            Err(err) => {
+                warn!("Failed to backfill message {}: {}", export_message.id, err);
+                progress.failed.push(export_message.id);
+                progress.sent_to.insert(export_message.id, sent_to);
+                failed += 1;
+            }
+        }
+        // Keep what earlier tries sent too, if the log still has it
+        let mut logged_sent = message_log::lookup(args.chat_id, export_message.id)
+            .map(|logged| logged.sent)
+            .unwrap_or_default();
+        logged_sent.extend(sent);
+        message_log::record(
+            args.chat_id,
+            export_message.id,
+            LoggedMessage::new(&message, Vec::new(), logged_sent).await,
+        );
+
+        progress.last_message_id = progress.last_message_id.max(export_message.id);
+        tokio::fs::write(&progress_path, serde_json::to_vec(&progress)?).await?;
+        tokio::time::sleep(args.delay).await;
+    }
+
+    if !args.dry_run {
+        message_log::flush().await;
+        digest::flush().await;
+    }
+    clear_spool_dir();
+    if args.dry_run {
+        println!(
+            "Dry run: {} messages would be posted, {} skipped",
+            posted, skipped
+        );
+    } else {
+        println!(
+            "Backfill done: {} posted, {} skipped, {} failed",
+            posted, skipped, failed
+        );
+    }
+    Ok(())
+}
+
+fn describe_date(date: Option<NaiveDate>) -> String {
+    date.map_or_else(
+        || "the edge of the export".to_string(),
+        |date| date.to_string(),
+    )
+}
+
+fn parse_args(args: &[String]) -> MyResult<BackfillArgs> {
+    let mut args = args.iter();
+    let path = PathBuf::from(args.next().ok_or_else(|| make_error(USAGE))?);
+    let (export_dir, result_path) = if path.is_dir() {
+        (path.clone(), path.join("result.json"))
+    } else {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        (dir, path)
+    };
+
+    let mut chat_id = None;
+    let mut from = None;
+    let mut to = None;
+    let mut delay = Duration::from_millis(2000);
+    let mut dry_run = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| make_error(USAGE));
+        match arg.as_str() {
+            "--chat" => chat_id = Some(ChatId(value()?.parse()?)),
+            "--from" => from = Some(NaiveDate::parse_from_str(value()?, "%Y-%m-%d")?),
+            "--to" => to = Some(NaiveDate::parse_from_str(value()?, "%Y-%m-%d")?),
+            "--delay-ms" => delay = Duration::from_millis(value()?.parse()?),
+            "--dry-run" => dry_run = true,
+            _ => return Err(make_error(USAGE)),
+        }
+    }
+
+    Ok(BackfillArgs {
+        export_dir,
+        result_path,
+        chat_id: chat_id.ok_or_else(|| make_error(USAGE))?,
+        from,
+        to,
+        delay,
+        dry_run,
+    })
+}
+
+/// Newer exports have a unix timestamp, older ones only local time, which we take as UTC
+fn message_date(message: &ExportMessage) -> MyResult<DateTime<Utc>> {
+    if let Some(unix) = &message.date_unixtime {
+        return Ok(Utc.timestamp(unix.parse()?, 0));
+    }
+    let naive = NaiveDateTime::parse_from_str(&message.date, "%Y-%m-%dT%H:%M:%S")?;
+    Ok(DateTime::from_utc(naive, Utc))
+}
+
+/// Build the message the same way `message_handler` would have, loading media from the export
+async fn ready_message(
+    args: &BackfillArgs,
+    export: &Export,
+    message: &ExportMessage,
+    date: DateTime<Utc>,
+) -> ReadyMessage {
+    let (text, entities) = text_and_entities(&message.text_entities);
+    let text = if text.is_empty() { None } else { Some(text) };
+
+    let mut attachments = Vec::new();
+    for (index, relative) in message.photo.iter().chain(message.file.iter()).enumerate() {
+        // Media that wasn't exported is replaced by a note in parentheses
+        if relative.starts_with('(') {
+            warn!("Message {} has media missing from the export", message.id);
+            continue;
+        }
+        let file_unique_id = format!("export-{}-{}-{}", args.chat_id.0, message.id, index);
+        let path = args.export_dir.join(relative);
+        match load_attachment(&path, file_unique_id, message, args.dry_run).await {
+            Ok(attachment) => attachments.push(attachment),
+            Err(err) => warn!("Failed to load {}: {}", relative, err),
+        }
+    }
+
+    ReadyMessage {
+        message_html: text
+            .as_deref()
+            .map(|text| entities_to_html(text, &entities)),
+        message_text: text,
+        entities,
+        attachments,
+        buttons: get_message_buttons(export_buttons(&message.inline_bot_buttons).as_ref()),
+        source: MessageSource {
+            chat_id: args.chat_id,
+            chat_title: export.name.clone(),
+            chat_username: None,
+            message_id: message.id,
+            date,
+            kind: export_kind(message),
+            forwarded: message.forwarded_from.is_some(),
+            author_signature: message.author.clone(),
+            // Nobody needs a notification for every old post
+            silent: true,
+            // The export doesn't say, and it was let out of telegram already
+            protected: false,
+            edited_at: message
+                .edited_unixtime
+                .as_ref()
+                .and_then(|unix| unix.parse().ok())
+                .map(|unix| Utc.timestamp(unix, 0)),
+        },
+    }
+}
+
+/// Work out what kind of post it was from what the export says about its media
+fn export_kind(message: &ExportMessage) -> MessageKind {
+    if message.photo.is_some() {
+        return MessageKind::Photo;
+    }
+    match (message.file.is_some(), message.media_type.as_deref()) {
+        (_, Some("video_file" | "video_message")) => MessageKind::Video,
+        (_, Some("animation")) => MessageKind::Animation,
+        (_, Some("audio_file")) => MessageKind::Audio,
+        (_, Some("voice_message")) => MessageKind::Voice,
+        (_, Some("sticker")) => MessageKind::Sticker,
+        (true, _) => MessageKind::Document,
+        (false, _) if !message.text_entities.is_empty() => MessageKind::Text,
+        _ => MessageKind::Other,
+    }
+}
+
+/// Read a media file from the export and put it through the same post-processing as downloads.
+/// A dry run only reads it, without caching or shrinking anything.
+async fn load_attachment(
+    path: &Path,
+    file_unique_id: String,
+    message: &ExportMessage,
+    dry_run: bool,
+) -> MyResult<DownloadedAttachment> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| make_error("Media path has no file name"))?
+        .to_string();
+
+    let size = tokio::fs::metadata(path).await?.len();
+    let data = if size > *SPOOL_THRESHOLD_BYTES as u64 {
+        let spooled = SpooledFile::new(&file_name).await?;
+        link_or_copy(path, spooled.path()).await?;
+        FileData::Spooled(spooled)
+    } else {
+        FileData::Memory(tokio::fs::read(path).await?, None)
+    };
+    if !dry_run {
+        cache_put(&file_unique_id, None, &data).await;
+    }
+
+    let attachment = resolve_sniffed_extension(DownloadedAttachment {
+        file_name,
+        file_unique_id: file_unique_id.clone(),
+        data,
+        original: None,
+    })
+    .await;
+
+    if dry_run {
+        return Ok(attachment);
+    }
+    let attachment = match (message.media_type.as_deref(), &message.photo) {
+        (Some("video_file"), _) => {
+            shrink_video(
+                attachment,
+                &file_unique_id,
+                message.duration_seconds.unwrap_or_default(),
+                message.width.unwrap_or_default(),
+                message.height.unwrap_or_default(),
+            )
+            .await
+        }
+        (None, Some(_)) => shrink_image(attachment, &file_unique_id).await,
+        _ => attachment,
+    };
+    Ok(attachment)
+}
+
+/// Rebuild the plain text and telegram entities from the export's list of text pieces
+fn text_and_entities(pieces: &[ExportEntity]) -> (String, Vec<MessageEntity>) {
+    let mut text = String::new();
+    let mut entities = Vec::new();
+    let mut offset = 0;
+
+    for piece in pieces {
+        let length = piece.text.encode_utf16().count();
+        let kind = match piece.kind.as_str() {
+            "bold" => Some(MessageEntityKind::Bold),
+            "italic" => Some(MessageEntityKind::Italic),
+            "underline" => Some(MessageEntityKind::Underline),
+            "strikethrough" => Some(MessageEntityKind::Strikethrough),
+            "code" => Some(MessageEntityKind::Code),
+            "pre" => Some(MessageEntityKind::Pre {
+                language: piece
+                    .language
+                    .clone()
+                    .filter(|language| !language.is_empty()),
+            }),
+            "link" => Some(MessageEntityKind::Url),
+            "text_link" => piece
+                .href
+                .as_deref()
+                .and_then(|href| href.parse().ok())
+                .map(|url| MessageEntityKind::TextLink { url }),
+            "mention" => Some(MessageEntityKind::Mention),
+            "hashtag" => Some(MessageEntityKind::Hashtag),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            entities.push(MessageEntity::new(kind, offset, length));
+        }
+        text.push_str(&piece.text);
+        offset += length;
+    }
+
+    (text, entities)
+}
+
+/// Turn the exported inline keyboard back into telegram's markup, so the button code can take it
+fn export_buttons(rows: &[Vec<ExportButton>]) -> Option<InlineKeyboardMarkup> {
+    if rows.is_empty() {
+        return None;
+    }
+
+    let rows = rows.iter().map(|row| {
+        row.iter()
+            .map(|button| {
+                match (
+                    button.kind.as_str(),
+                    button.data.as_deref().map(str::parse::<Url>),
+                ) {
+                    ("url", Some(Ok(url))) => InlineKeyboardButton::url(button.text.clone(), url),
+                    _ => InlineKeyboardButton::callback(button.text.clone(), button.text.clone()),
+                }
+            })
+            .collect::<Vec<_>>()
+    });
+    Some(InlineKeyboardMarkup::new(rows))
+}
+
+/// Print what would be sent where, without sending it
+fn report(message: &ReadyMessage, destinations: &[&Destination]) {
+    println!(
+        "#{} {} | {} chars of text, {} attachment(s)",
+        message.source.message_id,
+        message.source.date.format("%Y-%m-%d %H:%M"),
+        message
+            .message_text
+            .as_deref()
+            .map_or(0, |text| text.chars().count()),
+        message.attachments.len()
+    );
+    for destination in destinations {
+        let sink = &destination.sink;
+        let outgoing = message.outgoing_for(&sink.capabilities());
+        let dropped = message.attachments.len() - outgoing.attachments.len();
+        if dropped > 0 {
+            println!(
+                "    {}: {} attachment(s) over its limits before shrinking",
+                sink.name(),
+                dropped
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_without_a_range_or_failures_still_loads() {
+        let progress: Progress = serde_json::from_str(r#"{"last_message_id": 12}"#).unwrap();
+        assert_eq!(progress.last_message_id, 12);
+        assert!(progress.failed.is_empty());
+        assert!(progress.sent_to.is_empty());
+        assert_eq!((progress.from, progress.to), (None, None));
+    }
+
+    #[test]
+    fn progress_keeps_its_range_and_failures() {
+        let progress = Progress {
+            last_message_id: 30,
+            failed: vec![4, 17],
+            sent_to: HashMap::from([(4, vec!["discord".to_string()])]),
+            from: NaiveDate::from_ymd_opt(2021, 1, 1),
+            to: None,
+        };
+        let loaded: Progress =
+            serde_json::from_slice(&serde_json::to_vec(&progress).unwrap()).unwrap();
+        assert_eq!(loaded.failed, [4, 17]);
+        assert_eq!(loaded.sent_to[&4], ["discord"]);
+        assert!(!loaded.sent_to.contains_key(&17));
+        assert_eq!(loaded.from, progress.from);
+        assert_eq!(loaded.to, None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::str::FromStr;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serenity::{builder::CreateComponents, model::interactions::message_component::ButtonStyle};
+use teloxide::types::{InlineKeyboardButtonKind, InlineKeyboardMarkup};
+
+use crate::{formatting::escape_discord_markdown, types::BoxedError, utils::make_error};
+
+/// Discord allows at most 5 action rows per message
+const MAX_ACTION_ROWS: usize = 5;
+/// And at most 5 buttons in each of those rows
+const MAX_BUTTONS_PER_ROW: usize = 5;
+/// Button labels longer than this are rejected by Discord
+const MAX_LABEL_LENGTH: usize = 80;
+
+/// What to do with inline keyboard buttons that don't just open a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackButtonPolicy {
+    /// Drop them entirely
+    Skip,
+    /// Render their labels as plain text under the message
+    Text,
+}
+
+impl Default for CallbackButtonPolicy {
+    fn default() -> Self {
+        CallbackButtonPolicy::Skip
+    }
+}
+
+impl FromStr for CallbackButtonPolicy {
+    type Err = BoxedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(CallbackButtonPolicy::Skip),
+            "text" => Ok(CallbackButtonPolicy::Text),
+            _ => Err(make_error(&format!(
+                "Unknown callback button policy `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkButton {
+    pub label: String,
+    pub url: String,
+}
+
+/// The buttons of a Telegram inline keyboard, split into the ones Discord can show and the rest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessageButtons {
+    /// URL buttons laid out to fit into Discord's action rows
+    pub link_rows: Vec<Vec<LinkButton>>,
+    /// URL buttons that didn't fit into the 5x5 grid
+    pub overflow_links: Vec<LinkButton>,
+    /// Labels of every button that isn't a plain link (callbacks, inline queries, games...)
+    pub callback_labels: Vec<String>,
+}
+
+impl MessageButtons {
+    pub fn is_empty(&self) -> bool {
+        self.link_rows.is_empty()
+            && self.overflow_links.is_empty()
+            && self.callback_labels.is_empty()
+    }
+
+    /// Build the action rows for a Discord message, or None if there are no link buttons
+    pub fn to_components(&self) -> Option<CreateComponents> {
+        if self.link_rows.is_empty() {
+            return None;
+        }
+
+        let mut components = CreateComponents::default();
+        for row in &self.link_rows {
+            components.create_action_row(|action_row| {
+                for button in row {
+                    action_row.create_button(|b| {
+                        b.style(ButtonStyle::Link)
+                            .label(&button.label)
+                            .url(&button.url)
+                    });
+                }
+                action_row
+            });
+        }
+        Some(components)
+    }
+
+    /// Text to append to the message content for the buttons that can't be real Discord buttons.
+    /// Labels are escaped, so they show up as written instead of being read as markdown.
+    pub fn text_suffix(&self, policy: CallbackButtonPolicy) -> Option<String> {
+        let mut lines: Vec<String> = self
+            .overflow_links
+            .iter()
+            .map(|button| {
+                format!(
+                    "{}: <{}>",
+                    escape_discord_markdown(&button.label),
+                    button.url
+                )
+            })
+            .collect();
+
+        if policy == CallbackButtonPolicy::Text {
+            lines.extend(
+                self.callback_labels
+                    .iter()
+                    .map(|label| format!("[{}]", escape_discord_markdown(label))),
+            );
+        }
+
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+/// Pull the buttons off a Telegram inline keyboard, wrapping long rows to respect Discord's limits
+pub fn get_message_buttons(markup: Option<&InlineKeyboardMarkup>) -> MessageButtons {
+    let mut buttons = MessageButtons::default();
+
+    let markup = match markup {
+        Some(markup) => markup,
+        None => return buttons,
+    };
+
+    for tg_row in &markup.inline_keyboard {
+        let mut links = Vec::new();
+
+        for tg_button in tg_row {
+            match &tg_button.kind {
+                InlineKeyboardButtonKind::Url(url) => links.push(LinkButton {
+                    label: truncate_label(&tg_button.text),
+                    url: url.to_string(),
+                }),
+                InlineKeyboardButtonKind::LoginUrl(login_url) => links.push(LinkButton {
+                    label: truncate_label(&tg_button.text),
+                    url: login_url.url.to_string(),
+                }),
+                _ => buttons.callback_labels.push(tg_button.text.clone()),
+            }
+        }
+
+        // Telegram rows can be up to 8 wide, so wrap them into several Discord rows
+        for chunk in links.chunks(MAX_BUTTONS_PER_ROW) {
+            if buttons.link_rows.len() < MAX_ACTION_ROWS {
+                buttons.link_rows.push(chunk.to_vec());
+            } else {
+                buttons.overflow_links.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    if !buttons.overflow_links.is_empty() {
+        warn!(
+            "Inline keyboard has more link buttons than Discord allows, {} will be sent as text",
+            buttons.overflow_links.len()
+        );
+    }
+
+    buttons
+}
+
+fn truncate_label(label: &str) -> String {
+    if label.chars().count() <= MAX_LABEL_LENGTH {
+        label.to_string()
+    } else {
+        let mut truncated: String = label.chars().take(MAX_LABEL_LENGTH - 1).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+    use teloxide::types::InlineKeyboardButton;
+
+    use super::*;
+
+    fn link(n: usize) -> InlineKeyboardButton {
+        InlineKeyboardButton::url(
+            format!("Link {}", n),
+            Url::parse(&format!("https://example.com/{}", n)).unwrap(),
+        )
+    }
+
+    fn keyboard(rows: Vec<Vec<InlineKeyboardButton>>) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new(rows)
+    }
+
+    #[test]
+    fn no_keyboard_is_empty() {
+        assert!(get_message_buttons(None).is_empty());
+    }
+
+    #[test]
+    fn wide_rows_wrap() {
+        let markup = keyboard(vec![(0..8).map(link).collect()]);
+        let buttons = get_message_buttons(Some(&markup));
+
+        let widths: Vec<usize> = buttons.link_rows.iter().map(Vec::len).collect();
+        assert_eq!(widths, vec![5, 3]);
+        assert_eq!(buttons.link_rows[1][0].label, "Link 5");
+        assert!(buttons.overflow_links.is_empty());
+    }
+
+    #[test]
+    fn links_past_the_grid_overflow() {
+        let markup = keyboard(
+            (0..6)
+                .map(|row| (row * 5..row * 5 + 5).map(link).collect())
+                .collect(),
+        );
+        let buttons = get_message_buttons(Some(&markup));
+
+        assert_eq!(buttons.link_rows.len(), MAX_ACTION_ROWS);
+        assert_eq!(buttons.overflow_links.len(), 5);
+        assert_eq!(buttons.overflow_links[0].url, "https://example.com/25");
+    }
+
+    #[test]
+    fn callbacks_are_kept_apart() {
+        let markup = keyboard(vec![vec![
+            link(0),
+            InlineKeyboardButton::callback("Vote".to_string(), "vote".to_string()),
+        ]]);
+        let buttons = get_message_buttons(Some(&markup));
+
+        assert_eq!(buttons.link_rows.len(), 1);
+        assert_eq!(buttons.link_rows[0].len(), 1);
+        assert_eq!(buttons.callback_labels, vec!["Vote".to_string()]);
+    }
+
+    #[test]
+    fn long_labels_are_truncated() {
+        let label = "x".repeat(MAX_LABEL_LENGTH + 10);
+        let truncated = truncate_label(&label);
+        assert_eq!(truncated.chars().count(), MAX_LABEL_LENGTH);
+        assert!(truncated.ends_with('…'));
+    }
+
+    #[test]
+    fn text_suffix_follows_the_policy() {
+        let buttons = MessageButtons {
+            callback_labels: vec!["Vote".to_string()],
+            ..MessageButtons::default()
+        };
+        assert_eq!(buttons.text_suffix(CallbackButtonPolicy::Skip), None);
+        assert_eq!(
+            buttons.text_suffix(CallbackButtonPolicy::Text).as_deref(),
+            Some("[Vote]")
+        );
+    }
+
+    #[test]
+    fn text_suffix_escapes_labels() {
+        let buttons = MessageButtons {
+            overflow_links: vec![LinkButton {
+                label: "**Sale**".to_string(),
+                url: "https://example.com".to_string(),
+            }],
+            callback_labels: vec!["[x](https://evil.example) a_b".to_string()],
+            ..MessageButtons::default()
+        };
+        assert_eq!(
+            buttons.text_suffix(CallbackButtonPolicy::Text).as_deref(),
+            Some("\\*\\*Sale\\*\\*: <https://example.com>\n[\\[x\\](https://evil.example) a\\_b]")
+        );
+    }
+
+    #[test]
+    fn parses_callback_button_policies() {
+        assert_eq!(
+            "skip".parse::<CallbackButtonPolicy>().unwrap(),
+            CallbackButtonPolicy::Skip
+        );
+        assert_eq!(
+            "text".parse::<CallbackButtonPolicy>().unwrap(),
+            CallbackButtonPolicy::Text
+        );
+        assert!("Text".parse::<CallbackButtonPolicy>().is_err());
+    }
+
+    #[test]
+    fn text_suffix_lists_overflow_links() {
+        let buttons = MessageButtons {
+            overflow_links: vec![LinkButton {
+                label: "More".to_string(),
+                url: "https://example.com".to_string(),
+            }],
+            callback_labels: vec!["Vote".to_string()],
+            ..MessageButtons::default()
+        };
+        assert_eq!(
+            buttons.text_suffix(CallbackButtonPolicy::Skip).as_deref(),
+            Some("More: <https://example.com>")
+        );
+        assert_eq!(
+            buttons.text_suffix(CallbackButtonPolicy::Text).as_deref(),
+            Some("More: <https://example.com>\n[Vote]")
+        );
+    }
+}
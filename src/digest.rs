@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use teloxide::types::ChatId;
+use tokio::sync::Notify;
+
+use crate::{
+    file_types::sniff_mime,
+    media_server::media_url,
+    routes,
+    schedule::is_quiet,
+    sinks::{truncate_text, OutgoingMessage, Sink},
+    templates::post_link,
+    types::{BoxedError, Destination, MyResult},
+    utils::{make_error, parse_duration, save_state, spawn_state_writer, write_json_atomically},
+    DIGEST_PATH,
+};
+
+/// How often to look for digests that have waited long enough
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How much of each post's text goes in a digest
+const EXCERPT_LENGTH: usize = 300;
+
+/// When a destination sends out the posts it has been collecting. A digest goes out once its
+/// first post has waited `interval` or once it holds `max_posts`, whichever comes first.
+#[derive(Debug, Clone, Copy)]
+pub struct DigestPolicy {
+    interval: Option<Duration>,
+    max_posts: Option<usize>,
+}
+
+impl FromStr for DigestPolicy {
+    type Err = BoxedError;
+
+    /// Parses `every:<duration>` and `max:<count>` separated by `;`, at least one of them. For
+    /// example `every:1h;max:20`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = DigestPolicy {
+            interval: None,
+            max_posts: None,
+        };
+
+        for rule in s.split(';').map(str::trim).filter(|rule| !rule.is_empty()) {
+            match rule.split_once(':') {
+                Some(("every", interval)) => policy.interval = Some(parse_duration(interval)?),
+                Some(("max", count)) => policy.max_posts = Some(count.trim().parse()?),
+                _ => return Err(make_error(&format!("Unknown digest rule `{}`", rule))),
+            }
+        }
+        if policy.interval.is_none() && policy.max_posts.is_none() {
+            return Err(make_error("A digest needs an `every:` or a `max:`"));
+        }
+        Ok(policy)
+    }
+}
+
+/// One post waiting to go out in a digest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestEntry {
+    pub chat_id: i64,
+    pub chat_title: Option<String>,
+    pub message_id: i32,
+    pub date: DateTime<Utc>,
+    /// Link back to the post on telegram, empty for private groups
+    pub link: Option<String>,
+    /// The start of the post's text
+    pub excerpt: Option<String>,
+    /// The post's first image on the media server, if it's set up
+    pub thumbnail: Option<String>,
+}
+
+impl DigestEntry {
+    async fn new(message: &OutgoingMessage<'_>) -> Self {
+        let source = message.source;
+        let mut thumbnail = None;
+        for attachment in &message.attachments {
+            if sniff_mime(&attachment.data).await.starts_with("image/") {
+                thumbnail = media_url(&attachment.file_unique_id);
+                break;
+            }
+        }
+        let ChatId(chat_id) = source.chat_id;
+
+        Self {
+            chat_id,
+            chat_title: source.chat_title.clone(),
+            message_id: source.message_id,
+            date: source.date,
+            link: post_link(source),
+            excerpt: message
+                .text
+                .as_deref()
+                .map(|text| truncate_text(text, EXCERPT_LENGTH)),
+            thumbnail,
+        }
+    }
+}
+
+/// The posts a destination has collected so far
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingDigest {
+    /// When the first post came in
+    started: DateTime<Utc>,
+    entries: Vec<DigestEntry>,
+}
+
+/// Pending digests by the name of the sink they go out through
+type Digests = HashMap<String, PendingDigest>;
+
+lazy_static! {
+    static ref DIGESTS: Mutex<Digests> = Mutex::new(HashMap::new());
+    /// Held while a digest is going out, so the same posts can't be sent twice
+    static ref SENDING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    /// Poked whenever a digest changes, for the writer task
+    static ref SAVE_NEEDED: Notify = Notify::new();
+}
+
+/// Read the pending digests back in from `DIGEST_PATH`, if there are any, and start the task that
+/// saves them there as they change
+pub fn load() -> MyResult<()> {
+    spawn_state_writer("pending digests", &SAVE_NEEDED, save);
+    if !DIGEST_PATH.exists() {
+        return Ok(());
+    }
+
+    let digests: Digests = serde_json::from_slice(&std::fs::read(&*DIGEST_PATH)?)?;
+    info!(
+        "Loaded {} posts waiting in digests",
+        digests
+            .values()
+            .map(|pending| pending.entries.len())
+            .sum::<usize>()
+    );
+    *DIGESTS.lock().unwrap() = digests;
+    Ok(())
+}
+
+/// Hold a post back for the destination's next digest, sending it right away if that fills it up
+/// outside of quiet hours
+pub async fn add(destination: &Destination, message: &OutgoingMessage<'_>) {
+    let sink = destination.sink.as_ref();
+    let name = sink.name();
+    let entry = DigestEntry::new(message).await;
+    let full = {
+        let mut digests = DIGESTS.lock().unwrap();
+        let pending = digests
+            .entry(name.clone())
+            .or_insert_with(|| PendingDigest {
+                started: Utc::now(),
+                entries: Vec::new(),
+            });
+        pending.entries.push(entry);
+        let full = is_full(destination.digest.as_ref(), pending);
+        SAVE_NEEDED.notify_one();
+        full
+    };
+    info!("Held message back for the next digest to {}", name);
+
+    if full && !is_quiet(destination, Utc::now()) {
+        send(sink).await;
+    }
+}
+
+/// Check for digests that have waited long enough every so often, and send them
+pub fn spawn_digest_timer() {
+    tokio::spawn(async {
+        let mut timer = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            timer.tick().await;
+            for sink in due() {
+                send(sink.as_ref()).await;
+            }
+        }
+    });
+}
+
+fn is_full(policy: Option<&DigestPolicy>, pending: &PendingDigest) -> bool {
+    policy
+        .and_then(|policy| policy.max_posts)
+        .map_or(false, |max_posts| pending.entries.len() >= max_posts)
+}
+
+/// Sinks with a digest that should go out now. Digests for sinks that aren't mirrored to any more,
+/// because their route was removed, are dropped here rather than waiting forever.
+fn due() -> Vec<Arc<dyn Sink>> {
+    let now = Utc::now();
+    let destinations: Vec<Destination> = routes::channels()
+        .iter()
+        .flat_map(|channel| channel.destinations.clone())
+        .collect();
+    let mut digests = DIGESTS.lock().unwrap();
+    let mut due: Vec<Arc<dyn Sink>> = Vec::new();
+    let mut orphaned = Vec::new();
+
+    for (name, pending) in digests.iter() {
+        let mut for_sink = destinations
+            .iter()
+            .filter(|destination| destination.sink.name() == *name)
+            .peekable();
+        if for_sink.peek().is_none() {
+            // A paused route can still pick its digest back up
+            if !routes::is_parked(name) {
+                orphaned.push(name.clone());
+            }
+            continue;
+        }
+        if let Some(destination) = for_sink.find(|destination| is_due(pending, destination, now)) {
+            due.push(destination.sink.clone());
+        }
+    }
+
+    for name in orphaned {
+        if let Some(pending) = digests.remove(&name) {
+            warn!(
+                "Dropping {} posts waiting in a digest to {}, it isn't mirrored to any more",
+                pending.entries.len(),
+                name
+            );
+        }
+        SAVE_NEEDED.notify_one();
+    }
+    due
+}
+
+/// Whether a destination's digest should go out now, never during its quiet hours. Digests that
+/// were only collected because of quiet hours go out as soon as those are over, and so do ones for
+/// destinations that have stopped collecting digests since, so those posts aren't stuck forever.
+fn is_due(pending: &PendingDigest, destination: &Destination, now: DateTime<Utc>) -> bool {
+    if is_quiet(destination, now) {
+        return false;
+    }
+    let waited = (now - pending.started).to_std().unwrap_or_default();
+    match &destination.digest {
+        Some(policy) => {
+            policy.interval.map_or(false, |interval| waited >= interval)
+                || is_full(Some(policy), pending)
+        }
+        None => true,
+    }
+}
+
+/// Send everything waiting for a sink as one digest, keeping it for next time if that fails
+async fn send(sink: &dyn Sink) {
+    let _sending = SENDING.lock().await;
+    let name = sink.name();
+    let entries = match DIGESTS.lock().unwrap().get(&name) {
+        Some(pending) => pending.entries.clone(),
+        None => return,
+    };
+    if entries.is_empty() {
+        return;
+    }
+
+    if let Err(err) = sink.send_digest(&entries).await {
+        warn!(
+            "Failed to send a digest to {}, will try again: {}",
+            name, err
+        );
+        return;
+    }
+    info!("Sent a digest of {} posts to {}", entries.len(), name);
+
+    let mut digests = DIGESTS.lock().unwrap();
+    // Posts that came in while it was sending wait for the next one
+    if let Some(pending) = digests.get_mut(&name) {
+        pending.entries.drain(..entries.len());
+        pending.started = Utc::now();
+        if pending.entries.is_empty() {
+            digests.remove(&name);
+        }
+    }
+    SAVE_NEEDED.notify_one();
+}
+
+/// A plain text digest, for sinks that have nothing nicer
+pub fn digest_text(entries: &[DigestEntry]) -> String {
+    let mut text = format!("{} new posts", entries.len());
+    for entry in entries {
+        text.push_str("\n\n");
+        text.push_str(entry.chat_title.as_deref().unwrap_or("Telegram"));
+        if let Some(excerpt) = &entry.excerpt {
+            text.push_str(": ");
+            text.push_str(excerpt);
+        }
+        if let Some(link) = &entry.link {
+            text.push('\n');
+            text.push_str(link);
+        }
+    }
+    text
+}
+
+/// Save the pending digests right away, for when the process is about to stop
+pub async fn flush() {
+    save_state("pending digests", save).await;
+}
+
+fn save() -> MyResult<()> {
+    write_json_atomically(&DIGEST_PATH, &*DIGESTS.lock().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::sinks::archive::ArchiveSink;
+
+    fn pending(posts: usize, started: DateTime<Utc>) -> PendingDigest {
+        PendingDigest {
+            started,
+            entries: (0..posts)
+                .map(|message_id| DigestEntry {
+                    chat_id: -100,
+                    chat_title: None,
+                    message_id: message_id as i32,
+                    date: started,
+                    link: None,
+                    excerpt: None,
+                    thumbnail: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn destination(digest: Option<&str>, quiet: Option<&str>) -> Destination {
+        Destination {
+            digest: digest.map(|policy| policy.parse().unwrap()),
+            quiet: quiet.map(|rules| rules.parse().unwrap()),
+            ..Destination::new(Arc::new(ArchiveSink::new(std::env::temp_dir())))
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        let both: DigestPolicy = " every:1h ; max:20 ".parse().unwrap();
+        assert_eq!(both.interval, Some(Duration::from_secs(60 * 60)));
+        assert_eq!(both.max_posts, Some(20));
+
+        let every: DigestPolicy = "every:90s".parse().unwrap();
+        assert_eq!(every.interval, Some(Duration::from_secs(90)));
+        assert_eq!(every.max_posts, None);
+
+        for bad in ["", ";", "max:lots", "every:1y", "weekly:1", "every 1h"] {
+            assert!(bad.parse::<DigestPolicy>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn full_once_it_has_max_posts() {
+        let policy: DigestPolicy = "max:3".parse().unwrap();
+        let now = Utc::now();
+        assert!(!is_full(Some(&policy), &pending(2, now)));
+        assert!(is_full(Some(&policy), &pending(3, now)));
+        assert!(is_full(Some(&policy), &pending(4, now)));
+
+        let timed: DigestPolicy = "every:1h".parse().unwrap();
+        assert!(!is_full(Some(&timed), &pending(100, now)));
+        assert!(!is_full(None, &pending(100, now)));
+    }
+
+    #[test]
+    fn due_after_the_interval_or_when_full() {
+        let started = Utc.ymd(2024, 1, 1).and_hms(12, 0, 0);
+        let hourly = destination(Some("every:1h;max:5"), None);
+        assert!(!is_due(
+            &pending(1, started),
+            &hourly,
+            started + chrono::Duration::minutes(59)
+        ));
+        assert!(is_due(
+            &pending(1, started),
+            &hourly,
+            started + chrono::Duration::hours(1)
+        ));
+        assert!(is_due(&pending(5, started), &hourly, started));
+
+        let capped = destination(Some("max:5"), None);
+        assert!(!is_due(
+            &pending(1, started),
+            &capped,
+            started + chrono::Duration::days(7)
+        ));
+    }
+
+    #[test]
+    fn never_due_during_quiet_hours() {
+        let started = Utc.ymd(2024, 1, 1).and_hms(12, 0, 0);
+        let quiet_evenings = destination(Some("every:1h"), Some("quiet:18:00-20:00"));
+        let evening = Utc.ymd(2024, 1, 1).and_hms(19, 0, 0);
+        assert!(!is_due(&pending(1, started), &quiet_evenings, evening));
+        assert!(is_due(
+            &pending(1, started),
+            &quiet_evenings,
+            evening + chrono::Duration::hours(1)
+        ));
+
+        // Posts only collected because of quiet hours go out once they end
+        let quiet_digest = destination(None, Some("quiet:18:00-20:00;deliver:digest"));
+        assert!(!is_due(&pending(1, evening), &quiet_digest, evening));
+        assert!(is_due(
+            &pending(1, evening),
+            &quiet_digest,
+            evening + chrono::Duration::hours(1)
+        ));
+    }
+}
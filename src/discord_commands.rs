@@ -0,0 +1,385 @@
+use log::warn;
+use serenity::{
+    builder::CreateApplicationCommandOption,
+    client::Context,
+    model::{
+        application::{
+            command::{Command, CommandOptionType},
+            interaction::{
+                application_command::{ApplicationCommandInteraction, CommandDataOption},
+                Interaction, InteractionResponseType,
+            },
+        },
+        permissions::Permissions,
+    },
+};
+use teloxide::types::ChatId;
+
+use crate::{
+    routes::{self, StoredRoute},
+    types::{MyResult, ProtectedContentPolicy},
+    utils::{make_error, make_webhook},
+    DISCORD_SUBSCRIBE_BOTH_WAYS, SUBSCRIBE_SOURCES,
+};
+
+/// Discord won't show more choices than this on one option
+const MAX_CHOICES: usize = 25;
+
+/// Register `/telegram` with Discord, if `DISCORD_SUBSCRIBE_SOURCES` lists anything to subscribe to
+pub async fn register_commands(ctx: &Context) -> MyResult<()> {
+    if SUBSCRIBE_SOURCES.is_empty() {
+        return Ok(());
+    }
+
+    Command::create_global_application_command(&ctx.http, |command| {
+        command
+            .name("telegram")
+            .description("Mirror Telegram channels into this one")
+            .default_member_permissions(Permissions::MANAGE_WEBHOOKS)
+            .dm_permission(false)
+            .create_option(|subcommand| {
+                subcommand
+                    .name("subscribe")
+                    .description("Start mirroring a Telegram channel here")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|option| source_option(option, true))
+                    .create_sub_option(|option| {
+                        option
+                            .name("protected")
+                            .description("What to do with posts Telegram won't let be forwarded")
+                            .kind(CommandOptionType::String)
+                            .required(false)
+                            .add_string_choice("Mirror them", "mirror")
+                            .add_string_choice("Only mirror their text", "text-only")
+                            .add_string_choice("Skip them", "skip")
+                    });
+                if *DISCORD_SUBSCRIBE_BOTH_WAYS {
+                    subcommand.create_sub_option(|option| {
+                        option
+                            .name("both_ways")
+                            .description("Mirror messages sent here back into the Telegram channel")
+                            .kind(CommandOptionType::Boolean)
+                            .required(false)
+                    });
+                }
+                subcommand
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("unsubscribe")
+                    .description("Stop mirroring a Telegram channel here, or all of them")
+                    .kind(CommandOptionType::SubCommand)
+                    .create_sub_option(|option| source_option(option, false))
+            })
+            .create_option(|subcommand| {
+                subcommand
+                    .name("status")
+                    .description("Show which Telegram channels are mirrored here")
+                    .kind(CommandOptionType::SubCommand)
+            })
+    })
+    .await?;
+    Ok(())
+}
+
+fn source_option(
+    option: &mut CreateApplicationCommandOption,
+    required: bool,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("channel")
+        .description("The Telegram channel")
+        .kind(CommandOptionType::String)
+        .required(required);
+    for (name, _) in SUBSCRIBE_SOURCES.iter().take(MAX_CHOICES) {
+        option.add_string_choice(name, name);
+    }
+    option
+}
+
+/// Answer a `/telegram` command. The reply is only shown to whoever ran it.
+pub async fn handle_interaction(ctx: &Context, interaction: Interaction) {
+    let command = match interaction {
+        Interaction::ApplicationCommand(command) if command.data.name == "telegram" => command,
+        _ => return,
+    };
+
+    // Creating webhooks can take longer than Discord waits for a response, so answer later
+    if let Err(err) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|data| data.ephemeral(true))
+        })
+        .await
+    {
+        warn!("Failed to acknowledge /telegram: {}", err);
+        return;
+    }
+
+    let reply = run_command(ctx, &command)
+        .await
+        .unwrap_or_else(|err| format!("That didn't work: {}", err));
+    if let Err(err) = command
+        .edit_original_interaction_response(&ctx.http, |response| response.content(reply))
+        .await
+    {
+        warn!("Failed to answer /telegram: {}", err);
+    }
+}
+
+async fn run_command(ctx: &Context, command: &ApplicationCommandInteraction) -> MyResult<String> {
+    // Discord already hides the command from people without the permission, but server admins
+    // can override that, so check it here too
+    let permissions = command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions);
+    if !can_manage_webhooks(permissions) {
+        return Ok("You need the Manage Webhooks permission for that".to_string());
+    }
+
+    let subcommand = command
+        .data
+        .options
+        .first()
+        .ok_or_else(|| make_error("Missing a subcommand"))?;
+    let channel_id = command.channel_id.0;
+    let source = chosen_source(subcommand);
+
+    match subcommand.name.as_str() {
+        "subscribe" => {
+            let source = source.ok_or_else(|| make_error("Which Telegram channel?"))?;
+            let chat_id = source_chat(source)?;
+            if routes::list(Some(chat_id))
+                .iter()
+                .any(|route| route.discord_channel_id == Some(channel_id))
+            {
+                return Ok(format!("This channel already mirrors {}", source));
+            }
+
+            let protected_content = match string_option(subcommand, "protected") {
+                Some(policy) => policy.parse()?,
+                None => ProtectedContentPolicy::default(),
+            };
+
+            let both_ways = *DISCORD_SUBSCRIBE_BOTH_WAYS
+                && bool_option(subcommand, "both_ways").unwrap_or(false);
+
+            let webhook = command
+                .channel_id
+                .create_webhook(&ctx.http, format!("Telegram {}", source))
+                .await?;
+            match routes::add(chat_id, &webhook.url()?, true, protected_content, both_ways).await {
+                Ok(route) => Ok(format!(
+                    "Now mirroring {}{} here (route #{})",
+                    source,
+                    if route.both_ways { " both ways" } else { "" },
+                    route.id
+                )),
+                Err(err) => {
+                    if let Err(err) = webhook.delete(&ctx.http).await {
+                        warn!("Failed to clean up webhook {}: {}", webhook.id, err);
+                    }
+                    Err(err)
+                }
+            }
+        }
+        "unsubscribe" => {
+            let chat_id = source.map(source_chat).transpose()?;
+            let subscribed = channel_routes(chat_id, channel_id);
+            if subscribed.is_empty() {
+                return Ok("This channel isn't mirroring that".to_string());
+            }
+
+            // Keep going past failures, so one broken route doesn't hold up the rest
+            let mut stopped = Vec::new();
+            let mut problems = Vec::new();
+            for route in &subscribed {
+                let name = source_name(route.chat_id);
+                if let Err(err) = routes::remove(route.id, None) {
+                    problems.push(format!("couldn't stop mirroring {}: {}", name, err));
+                    continue;
+                }
+                stopped.push(name.clone());
+                // Webhooks someone made by hand are theirs to clean up
+                if route.managed {
+                    let deleted: MyResult<()> = match make_webhook(&route.webhook_url).await {
+                        Ok(webhook) => webhook.delete(&ctx.http).await.map_err(Into::into),
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = deleted {
+                        warn!(
+                            "Failed to delete the webhook of route #{}: {}",
+                            route.id, err
+                        );
+                        problems.push(format!(
+                            "the webhook for {} is still there and can be deleted by hand",
+                            name
+                        ));
+                    }
+                }
+            }
+
+            let mut reply = if stopped.is_empty() {
+                "Nothing was stopped".to_string()
+            } else {
+                format!("Stopped mirroring {}", stopped.join(", "))
+            };
+            if !problems.is_empty() {
+                reply.push_str(&format!(", but {}", problems.join(", and ")));
+            }
+            Ok(reply)
+        }
+        "status" => {
+            let subscribed = channel_routes(None, channel_id);
+            if subscribed.is_empty() {
+                return Ok("This channel isn't mirroring any Telegram channels".to_string());
+            }
+            let lines: Vec<String> = subscribed
+                .iter()
+                .map(|route| {
+                    format!(
+                        "{} (route #{}){}{}",
+                        source_name(route.chat_id),
+                        route.id,
+                        if route.both_ways { ", both ways" } else { "" },
+                        if route.paused { ", paused" } else { "" }
+                    )
+                })
+                .collect();
+            Ok(format!("Mirroring here:\n{}", lines.join("\n")))
+        }
+        _ => Err(make_error("Unknown subcommand")),
+    }
+}
+
+/// Whether the member running a command may add and remove webhooks. Discord only sends
+/// permissions along for commands run in a server.
+fn can_manage_webhooks(permissions: Option<Permissions>) -> bool {
+    permissions.map_or(false, |permissions| permissions.manage_webhooks())
+}
+
+/// The `channel` option of a subcommand, if it was given
+fn chosen_source(subcommand: &CommandDataOption) -> Option<&str> {
+    string_option(subcommand, "channel")
+}
+
+fn string_option<'a>(subcommand: &'a CommandDataOption, name: &str) -> Option<&'a str> {
+    subcommand
+        .options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_str())
+}
+
+fn bool_option(subcommand: &CommandDataOption, name: &str) -> Option<bool> {
+    subcommand
+        .options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.value.as_ref())
+        .and_then(|value| value.as_bool())
+}
+
+/// Only the sources in `DISCORD_SUBSCRIBE_SOURCES` can be subscribed to
+fn source_chat(source: &str) -> MyResult<ChatId> {
+    find_source(&SUBSCRIBE_SOURCES, source)
+}
+
+fn find_source(sources: &[(String, ChatId)], source: &str) -> MyResult<ChatId> {
+    sources
+        .iter()
+        .find(|(name, _)| name == source)
+        .map(|(_, chat_id)| *chat_id)
+        .ok_or_else(|| make_error(&format!("{} can't be subscribed to", source)))
+}
+
+fn source_name(chat_id: i64) -> String {
+    SUBSCRIBE_SOURCES
+        .iter()
+        .find(|(_, ChatId(id))| *id == chat_id)
+        .map(|(name, _)| name.clone())
+        .unwrap_or_else(|| chat_id.to_string())
+}
+
+/// Added routes that post into a Discord channel, only the ones from `chat_id` if it's given
+fn channel_routes(chat_id: Option<ChatId>, discord_channel_id: u64) -> Vec<StoredRoute> {
+    routes_in_channel(routes::list(chat_id), chat_id, discord_channel_id)
+}
+
+fn routes_in_channel(
+    routes: Vec<StoredRoute>,
+    chat_id: Option<ChatId>,
+    discord_channel_id: u64,
+) -> Vec<StoredRoute> {
+    routes
+        .into_iter()
+        .filter(|route| chat_id.map_or(true, |ChatId(id)| route.chat_id == id))
+        .filter(|route| route.discord_channel_id == Some(discord_channel_id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(id: u32, chat_id: i64, discord_channel_id: Option<u64>) -> StoredRoute {
+        StoredRoute {
+            id,
+            chat_id,
+            webhook_url: format!("https://discord.com/api/webhooks/{}/token", id),
+            name: format!("Telegram {}", id),
+            discord_channel_id,
+            managed: true,
+            paused: false,
+            protected_content: Default::default(),
+            both_ways: false,
+        }
+    }
+
+    #[test]
+    fn needs_manage_webhooks() {
+        assert!(!can_manage_webhooks(None));
+        assert!(!can_manage_webhooks(Some(Permissions::SEND_MESSAGES)));
+        assert!(can_manage_webhooks(Some(
+            Permissions::SEND_MESSAGES | Permissions::MANAGE_WEBHOOKS
+        )));
+    }
+
+    #[test]
+    fn only_listed_sources_can_be_subscribed_to() {
+        let sources = vec![
+            ("news".to_string(), ChatId(-1001)),
+            ("@updates".to_string(), ChatId(-1002)),
+        ];
+        assert_eq!(find_source(&sources, "news").unwrap(), ChatId(-1001));
+        assert_eq!(find_source(&sources, "@updates").unwrap(), ChatId(-1002));
+        assert!(find_source(&sources, "-1001").is_err());
+        assert!(find_source(&sources, "News").is_err());
+        assert!(find_source(&[], "news").is_err());
+    }
+
+    #[test]
+    fn unsubscribing_only_touches_this_channel() {
+        let routes = vec![
+            route(1, -1001, Some(10)),
+            route(2, -1002, Some(10)),
+            route(3, -1001, Some(20)),
+            route(4, -1001, None),
+        ];
+        let ids = |chat_id: Option<ChatId>, channel: u64| -> Vec<u32> {
+            routes_in_channel(routes.clone(), chat_id, channel)
+                .iter()
+                .map(|route| route.id)
+                .collect()
+        };
+
+        assert_eq!(ids(Some(ChatId(-1001)), 10), vec![1]);
+        assert_eq!(ids(None, 10), vec![1, 2]);
+        assert_eq!(ids(Some(ChatId(-1001)), 20), vec![3]);
+        assert!(ids(Some(ChatId(-1002)), 20).is_empty());
+        assert!(ids(None, 30).is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+use chrono::Utc;
+use teloxide::types::ChatId;
+
+use crate::{
+    formatting::escape_html,
+    media_server::media_url,
+    message_log::{self, LoggedMessage},
+    sinks::truncate_text,
+};
+
+/// Entry titles are cut down to this many characters of the post's first line
+const MAX_TITLE_LENGTH: usize = 80;
+
+/// Atom feed of the latest posts in a chat, built from the message log. Protected posts are left
+/// out, feeds are public.
+pub fn atom_feed(chat_id: ChatId, max_entries: usize) -> String {
+    let entries = message_log::latest_public(chat_id, max_entries);
+    let ChatId(id) = chat_id;
+
+    let title = feed_title(id, &entries);
+    // Edits bump posts' `updated`, so the feed is as fresh as its freshest post
+    let updated = entries
+        .iter()
+        .map(|(_, logged)| logged.updated)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("<id>urn:telegram-mirror:{}</id>\n", id));
+    xml.push_str(&format!("<title>{}</title>\n", escape_html(&title)));
+    xml.push_str(&format!(
+        "<author><name>{}</name></author>\n",
+        escape_html(&title)
+    ));
+    xml.push_str(&format!("<updated>{}</updated>\n", updated.to_rfc3339()));
+    if let Some(link) = entries
+        .first()
+        .and_then(|(_, logged)| logged.chat_username.as_ref())
+    {
+        xml.push_str(&format!(
+            "<link href=\"https://t.me/{}\"/>\n",
+            escape_html(link)
+        ));
+    }
+
+    for (message_id, logged) in &entries {
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!(
+            "<id>urn:telegram-mirror:{}:{}</id>\n",
+            id, message_id
+        ));
+        xml.push_str(&format!(
+            "<title>{}</title>\n",
+            escape_html(&entry_title(*message_id, logged))
+        ));
+        xml.push_str(&format!(
+            "<published>{}</published>\n",
+            logged.date.to_rfc3339()
+        ));
+        xml.push_str(&format!(
+            "<updated>{}</updated>\n",
+            logged.updated.to_rfc3339()
+        ));
+        if let Some(link) = post_link(*message_id, logged) {
+            xml.push_str(&format!(
+                "<link rel=\"alternate\" href=\"{}\"/>\n",
+                escape_html(&link)
+            ));
+        }
+        for media in &logged.media {
+            if let Some(url) = media_url(&media.file_unique_id) {
+                xml.push_str(&format!(
+                    "<link rel=\"enclosure\" href=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                    escape_html(&url),
+                    escape_html(&media.mime_type),
+                    media.size
+                ));
+            }
+        }
+        xml.push_str(&format!(
+            "<content type=\"html\">{}</content>\n",
+            escape_html(&entry_html(logged))
+        ));
+        xml.push_str("</entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// RSS 2.0 version of `atom_feed`, for readers that still only speak RSS
+pub fn rss_feed(chat_id: ChatId, max_entries: usize) -> String {
+    let entries = message_log::latest_public(chat_id, max_entries);
+    let ChatId(id) = chat_id;
+
+    let title = feed_title(id, &entries);
+    // Private chats have nothing to link to, and `<link>` has to be a real URL
+    let channel_link = entries
+        .first()
+        .and_then(|(_, logged)| logged.chat_username.as_ref())
+        .map(|username| format!("https://t.me/{}", username));
+    let updated = entries
+        .iter()
+        .map(|(_, logged)| logged.updated)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_html(&title)));
+    if let Some(channel_link) = channel_link {
+        xml.push_str(&format!("<link>{}</link>\n", escape_html(&channel_link)));
+    }
+    xml.push_str(&format!(
+        "<description>Posts from {}</description>\n",
+        escape_html(&title)
+    ));
+    xml.push_str(&format!(
+        "<lastBuildDate>{}</lastBuildDate>\n",
+        updated.to_rfc2822()
+    ));
+
+    for (message_id, logged) in &entries {
+        xml.push_str("<item>\n");
+        xml.push_str(&format!(
+            "<guid isPermaLink=\"false\">urn:telegram-mirror:{}:{}</guid>\n",
+            id, message_id
+        ));
+        xml.push_str(&format!(
+            "<title>{}</title>\n",
+            escape_html(&entry_title(*message_id, logged))
+        ));
+        xml.push_str(&format!(
+            "<pubDate>{}</pubDate>\n",
+            logged.date.to_rfc2822()
+        ));
+        if let Some(link) = post_link(*message_id, logged) {
+            xml.push_str(&format!("<link>{}</link>\n", escape_html(&link)));
+        }
+        // RSS only allows one enclosure per item, the rest are still linked from the content
+        if let Some((media, url)) = logged
+            .media
+            .iter()
+            .find_map(|media| media_url(&media.file_unique_id).map(|url| (media, url)))
+        {
+            xml.push_str(&format!(
+                "<enclosure url=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                escape_html(&url),
+                escape_html(&media.mime_type),
+                media.size
+            ));
+        }
+        xml.push_str(&format!(
+            "<description>{}</description>\n",
+            escape_html(&entry_html(logged))
+        ));
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+fn feed_title(chat_id: i64, entries: &[(i32, LoggedMessage)]) -> String {
+    entries
+        .first()
+        .and_then(|(_, logged)| logged.chat_title.clone())
+        .unwrap_or_else(|| format!("Telegram chat {}", chat_id))
+}
+
+/// First line of the post, or a placeholder for posts that are only media
+fn entry_title(message_id: i32, logged: &LoggedMessage) -> String {
+    logged
+        .text
+        .as_deref()
+        .and_then(|text| text.lines().find(|line| !line.trim().is_empty()))
+        .map(|line| truncate_text(line.trim(), MAX_TITLE_LENGTH))
+        .unwrap_or_else(|| format!("Post {}", message_id))
+}
+
+/// Public link to the post, only possible for chats with a username
+fn post_link(message_id: i32, logged: &LoggedMessage) -> Option<String> {
+    logged
+        .chat_username
+        .as_ref()
+        .map(|username| format!("https://t.me/{}/{}", username, message_id))
+}
+
+/// The post as HTML, with its images inline and other media linked underneath
+fn entry_html(logged: &LoggedMessage) -> String {
+    let mut html = logged
+        .html
+        .as_deref()
+        .map(|html| html.replace('\n', "<br>"))
+        .unwrap_or_default();
+
+    for media in &logged.media {
+        let url = match media_url(&media.file_unique_id) {
+            Some(url) => url,
+            None => continue,
+        };
+        if media.mime_type.starts_with("image/") {
+            html.push_str(&format!(
+                "<p><img src=\"{}\" alt=\"{}\"></p>",
+                escape_html(&url),
+                escape_html(&media.file_name)
+            ));
+        } else {
+            html.push_str(&format!(
+                "<p><a href=\"{}\">{}</a></p>",
+                escape_html(&url),
+                escape_html(&media.file_name)
+            ));
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_log::LoggedMedia;
+
+    fn log_post(chat_id: ChatId, chat_username: Option<&str>) {
+        log_message(chat_id, 7, chat_username, false);
+    }
+
+    fn log_message(chat_id: ChatId, message_id: i32, chat_username: Option<&str>, protected: bool) {
+        let date = Utc::now();
+        message_log::record(
+            chat_id,
+            message_id,
+            LoggedMessage {
+                media_ids: Vec::new(),
+                sent: Vec::new(),
+                text: Some("Hello there".to_string()),
+                html: None,
+                chat_title: Some("Test channel".to_string()),
+                chat_username: chat_username.map(str::to_string),
+                date,
+                updated: date,
+                media: vec![LoggedMedia {
+                    file_unique_id: format!("media-{}", message_id),
+                    file_name: "photo.jpg".to_string(),
+                    mime_type: "image/jpeg".to_string(),
+                    size: 1024,
+                }],
+                protected,
+            },
+        );
+    }
+
+    #[test]
+    fn private_chats_have_no_links() {
+        let chat_id = ChatId(-1001);
+        log_post(chat_id, None);
+
+        let rss = rss_feed(chat_id, 10);
+        assert!(!rss.contains("<link>"), "{}", rss);
+    }
+
+    #[test]
+    fn protected_posts_stay_out() {
+        let chat_id = ChatId(-1003);
+        log_message(chat_id, 1, Some("testchannel"), false);
+        log_message(chat_id, 2, Some("testchannel"), true);
+
+        for feed in [atom_feed(chat_id, 10), rss_feed(chat_id, 10)] {
+            assert!(feed.contains("urn:telegram-mirror:-1003:1<"), "{}", feed);
+            assert!(!feed.contains("urn:telegram-mirror:-1003:2<"), "{}", feed);
+            assert!(!feed.contains("media-2"), "{}", feed);
+        }
+    }
+
+    #[test]
+    fn public_chats_link_back() {
+        let chat_id = ChatId(-1002);
+        log_post(chat_id, Some("testchannel"));
+
+        let rss = rss_feed(chat_id, 10);
+        assert!(rss.contains("<link>https://t.me/testchannel</link>"));
+        assert!(rss.contains("<link>https://t.me/testchannel/7</link>"));
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+
+use log::{debug, warn};
+use mime::Mime;
+use mime_to_ext::MIME_DATA_MAP;
+use tokio::io::AsyncReadExt;
+
+use crate::types::{DownloadedAttachment, FileData};
+
+/// Extension used when nothing else could tell us what a file is
+const FALLBACK_EXT: &str = ".bin";
+/// How much of a file to look at when sniffing its type
+const SNIFF_LEN: u64 = 8192;
+
+/// Name a file after its unique ID, with an extension from the declared MIME type or failing that
+/// the original file name. If neither helps the name is left bare, and `resolve_sniffed_extension`
+/// fills it in once the file is downloaded.
+pub fn name_by_unique_id(
+    file_unique_id: &str,
+    mime: Option<&Mime>,
+    original_name: Option<&str>,
+) -> String {
+    match resolve_extension(mime, original_name) {
+        Some(ext) => format!("{}{}", file_unique_id, ext),
+        None => file_unique_id.to_string(),
+    }
+}
+
+/// Keep the original file name when there is one, otherwise fall back to `name_by_unique_id`
+pub fn name_or_unique_id(
+    file_unique_id: &str,
+    mime: Option<&Mime>,
+    original_name: Option<&str>,
+) -> String {
+    match original_name {
+        Some(original_name) => original_name.to_owned(),
+        None => name_by_unique_id(file_unique_id, mime, None),
+    }
+}
+
+/// Try the declared MIME type, then the extension of the original name. Includes the leading dot.
+pub fn resolve_extension(mime: Option<&Mime>, original_name: Option<&str>) -> Option<String> {
+    if let Some(mime) = mime {
+        let mime_type = format!("{}/{}", mime.type_(), mime.subtype());
+        match MIME_DATA_MAP.get(&mime_type) {
+            Some(mime_data) => {
+                debug!(
+                    "Found mime type `{}`, writing ext `{}`",
+                    mime_type, mime_data.ext
+                );
+                return Some(mime_data.ext.clone());
+            }
+            None => debug!("Could not look up ext for mime type `{}`", mime_type),
+        }
+    }
+
+    original_name
+        .and_then(|name| Path::new(name).extension())
+        .and_then(|ext| ext.to_str())
+        .map(|ext| format!(".{}", ext))
+}
+
+/// Give a file we named after its unique ID an extension, sniffed from its contents or `.bin` as a
+/// last resort. Names that came from telegram are kept as they are, even without an extension.
+pub async fn resolve_sniffed_extension(
+    mut attachment: DownloadedAttachment,
+) -> DownloadedAttachment {
+    if attachment.file_name != attachment.file_unique_id {
+        return attachment;
+    }
+
+    let ext = match sniff_extension(&attachment.data).await {
+        Some(ext) => {
+            debug!("Sniffed `{}` as `.{}`", attachment.file_name, ext);
+            format!(".{}", ext)
+        }
+        None => FALLBACK_EXT.to_string(),
+    };
+    attachment.file_name.push_str(&ext);
+    // Serenity uploads spooled files under the name they have on disk
+    if let FileData::Spooled(file) = &mut attachment.data {
+        if let Err(err) = file.rename(&attachment.file_name).await {
+            warn!("Failed to rename {}: {}", file.path().display(), err);
+        }
+    }
+    attachment
+}
+
+/// Guess an extension, without the dot, from the magic bytes at the start of the file
+async fn sniff_extension(data: &FileData) -> Option<&'static str> {
+    sniff_type(data).await.map(|kind| kind.extension())
+}
+
+/// Guess the MIME type of a file from its contents, for APIs that want a content type
+pub async fn sniff_mime(data: &FileData) -> &'static str {
+    sniff_type(data)
+        .await
+        .map(|kind| kind.mime_type())
+        .unwrap_or("application/octet-stream")
+}
+
+async fn sniff_type(data: &FileData) -> Option<infer::Type> {
+    match data {
+        FileData::Memory(data, _) => infer::get(&data[..data.len().min(SNIFF_LEN as usize)]),
+        FileData::Spooled(file) => {
+            let mut head = Vec::new();
+            tokio::fs::File::open(file.path())
+                .await
+                .ok()?
+                .take(SNIFF_LEN)
+                .read_to_end(&mut head)
+                .await
+                .ok()?;
+            infer::get(&head)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spool::SpooledFile;
+
+    const PNG_HEADER: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    fn attachment(file_name: &str, data: &[u8]) -> DownloadedAttachment {
+        DownloadedAttachment {
+            file_name: file_name.to_string(),
+            file_unique_id: "AgADBAAD".to_string(),
+            data: FileData::Memory(data.to_vec(), None),
+            original: None,
+        }
+    }
+
+    #[test]
+    fn extension_from_the_original_name() {
+        assert_eq!(
+            resolve_extension(None, Some("notes.tar.gz")).as_deref(),
+            Some(".gz")
+        );
+        assert_eq!(resolve_extension(None, Some("Makefile")), None);
+        assert_eq!(resolve_extension(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn original_names_are_kept_as_they_are() {
+        let named = resolve_sniffed_extension(attachment("Makefile", &PNG_HEADER)).await;
+        assert_eq!(named.file_name, "Makefile");
+    }
+
+    #[tokio::test]
+    async fn unique_id_names_get_a_sniffed_extension() {
+        let sniffed = resolve_sniffed_extension(attachment("AgADBAAD", &PNG_HEADER)).await;
+        assert_eq!(sniffed.file_name, "AgADBAAD.png");
+
+        let unknown = resolve_sniffed_extension(attachment("AgADBAAD", b"plain")).await;
+        assert_eq!(unknown.file_name, "AgADBAAD.bin");
+    }
+
+    #[tokio::test]
+    async fn spooled_files_are_renamed_to_match() {
+        let spooled = SpooledFile::new("AgADBAAD").await.unwrap();
+        std::fs::write(spooled.path(), PNG_HEADER).unwrap();
+        let sniffed = resolve_sniffed_extension(DownloadedAttachment {
+            data: FileData::Spooled(spooled),
+            ..attachment("AgADBAAD", b"")
+        })
+        .await;
+
+        match &sniffed.data {
+            FileData::Spooled(file) => {
+                assert_eq!(file.path().file_name().unwrap(), "AgADBAAD.png");
+                assert_eq!(std::fs::read(file.path()).unwrap(), PNG_HEADER);
+            }
+            FileData::Memory(..) => panic!("the file should still be spooled"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sniffs_mime_types() {
+        let png = FileData::Memory(PNG_HEADER.to_vec(), None);
+        assert_eq!(sniff_mime(&png).await, "image/png");
+        let unknown = FileData::Memory(b"plain".to_vec(), None);
+        assert_eq!(sniff_mime(&unknown).await, "application/octet-stream");
+    }
+}
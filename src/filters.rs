@@ -0,0 +1,205 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use teloxide::types::MessageEntity;
+
+use crate::{
+    formatting::hashtags,
+    types::{BoxedError, MessageKind, MessageSource},
+    utils::make_error,
+};
+
+/// Rules for which posts from a chat go to one destination. Every rule that's set has to pass.
+#[derive(Debug, Clone, Default)]
+pub struct RouteFilter {
+    /// The text has to match at least one of these
+    include: Vec<Regex>,
+    /// And none of these
+    exclude: Vec<Regex>,
+    /// The post has to carry at least one of these, lowercase and without the `#`
+    hashtags: Vec<String>,
+    kinds: Vec<MessageKind>,
+    has_media: Option<bool>,
+    forwarded: Option<bool>,
+    /// The author signature has to match this, posts without one never do
+    signature: Option<Regex>,
+}
+
+impl RouteFilter {
+    /// Whether a post goes through, judged on what telegram sent before anything is downloaded.
+    ///
+    /// `text` is the post's text and its entities. It's `None` for album items without a caption
+    /// when the album's caption isn't known, which the text rules can't judge, so they let it by.
+    pub fn allows(&self, text: Option<(&str, &[MessageEntity])>, source: &MessageSource) -> bool {
+        if let Some((text, entities)) = text {
+            if !self.include.is_empty() && !self.include.iter().any(|regex| regex.is_match(text)) {
+                return false;
+            }
+            if self.exclude.iter().any(|regex| regex.is_match(text)) {
+                return false;
+            }
+            if !self.hashtags.is_empty() {
+                let tags: Vec<String> = hashtags(text, entities)
+                    .iter()
+                    .map(|tag| tag.trim_start_matches('#').to_lowercase())
+                    .collect();
+                if !self.hashtags.iter().any(|wanted| tags.contains(wanted)) {
+                    return false;
+                }
+            }
+        }
+        if !self.kinds.is_empty() && !self.kinds.contains(&source.kind) {
+            return false;
+        }
+        if let Some(has_media) = self.has_media {
+            if source.kind.has_media() != has_media {
+                return false;
+            }
+        }
+        if let Some(forwarded) = self.forwarded {
+            if source.forwarded != forwarded {
+                return false;
+            }
+        }
+        if let Some(signature) = &self.signature {
+            match &source.author_signature {
+                Some(author) if signature.is_match(author) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl FromStr for RouteFilter {
+    type Err = BoxedError;
+
+    /// Parses rules separated by `;`, any of
+    /// `include:<regex>`, `exclude:<regex>`, `hashtag:<tag>`, `kind:<kind>[,<kind>...]`,
+    /// `media:yes|no`, `forwarded:yes|no` and `signature:<regex>`.
+    ///
+    /// `include`, `exclude` and `hashtag` can be given more than once. Regexes can't contain a
+    /// literal `;`, write it as `\x3B` instead.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = RouteFilter::default();
+
+        for rule in s.split(';').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (name, value) = rule
+                .split_once(':')
+                .ok_or_else(|| make_error(&format!("Filter rule `{}` is missing a `:`", rule)))?;
+            match name.trim() {
+                "include" => filter.include.push(Regex::new(value)?),
+                "exclude" => filter.exclude.push(Regex::new(value)?),
+                "hashtag" => filter
+                    .hashtags
+                    .push(value.trim().trim_start_matches('#').to_lowercase()),
+                "kind" => {
+                    for kind in value.split(',') {
+                        filter.kinds.push(kind.trim().parse()?);
+                    }
+                }
+                "media" => filter.has_media = Some(parse_yes_no(value)?),
+                "forwarded" => filter.forwarded = Some(parse_yes_no(value)?),
+                "signature" => filter.signature = Some(Regex::new(value)?),
+                _ => return Err(make_error(&format!("Unknown filter rule `{}`", name))),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, BoxedError> {
+    match value.trim() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(make_error(&format!("Expected yes or no, got `{}`", value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use teloxide::types::{ChatId, MessageEntityKind};
+
+    use super::*;
+
+    fn source(kind: MessageKind) -> MessageSource {
+        MessageSource {
+            chat_id: ChatId(-100),
+            chat_title: None,
+            chat_username: None,
+            message_id: 1,
+            date: Utc::now(),
+            kind,
+            forwarded: false,
+            author_signature: Some("Ziah".to_string()),
+            silent: false,
+            protected: false,
+            edited_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_every_rule() {
+        let filter: RouteFilter = "include:(?i)release; exclude:beta ; hashtag:#News;hashtag:dev; \
+            kind:photo,video; media:yes; forwarded:no; signature:^Z"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.include.len(), 1);
+        assert_eq!(filter.exclude.len(), 1);
+        assert_eq!(filter.hashtags, ["news", "dev"]);
+        assert_eq!(filter.kinds, [MessageKind::Photo, MessageKind::Video]);
+        assert_eq!(filter.has_media, Some(true));
+        assert_eq!(filter.forwarded, Some(false));
+        assert!(filter.signature.is_some());
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for bad in [
+            "include",
+            "colour:red",
+            "include:(",
+            "kind:photo,hologram",
+            "media:maybe",
+        ] {
+            assert!(bad.parse::<RouteFilter>().is_err(), "{}", bad);
+        }
+        assert!("".parse::<RouteFilter>().is_ok());
+    }
+
+    #[test]
+    fn judges_text_and_hashtags() {
+        let filter: RouteFilter = "include:release;exclude:beta;hashtag:news".parse().unwrap();
+        let tag = [MessageEntity::new(MessageEntityKind::Hashtag, 0, 5)];
+        let source = source(MessageKind::Text);
+
+        assert!(filter.allows(Some(("#News new release", &tag)), &source));
+        assert!(!filter.allows(Some(("#News new beta release", &tag)), &source));
+        assert!(!filter.allows(Some(("#News nothing new", &tag)), &source));
+        assert!(!filter.allows(Some(("#News new release", &[])), &source));
+    }
+
+    #[test]
+    fn uncaptioned_album_items_pass_text_rules() {
+        let filter: RouteFilter = "include:release;hashtag:news;kind:photo".parse().unwrap();
+        assert!(filter.allows(None, &source(MessageKind::Photo)));
+        // The rest of the rules still apply
+        assert!(!filter.allows(None, &source(MessageKind::Video)));
+    }
+
+    #[test]
+    fn judges_the_source() {
+        let filter: RouteFilter = "media:no;forwarded:no;signature:^Zi".parse().unwrap();
+        assert!(filter.allows(Some(("", &[])), &source(MessageKind::Text)));
+        assert!(!filter.allows(Some(("", &[])), &source(MessageKind::Photo)));
+
+        let mut forwarded = source(MessageKind::Text);
+        forwarded.forwarded = true;
+        assert!(!filter.allows(Some(("", &[])), &forwarded));
+
+        let mut unsigned = source(MessageKind::Text);
+        unsigned.author_signature = None;
+        assert!(!filter.allows(Some(("", &[])), &unsigned));
+    }
+}
@@ -0,0 +1,503 @@
+use teloxide::types::{MessageEntity, MessageEntityKind};
+
+/// How one kind of markup writes entities and escapes the text around them
+struct Markup {
+    tags: fn(&MessageEntity, &[u16]) -> Option<Tags>,
+    escape: fn(&str) -> String,
+}
+
+/// What goes either side of an entity's text
+struct Tags {
+    open: String,
+    close: String,
+    /// The text inside is shown as written, so it mustn't be escaped
+    verbatim: bool,
+}
+
+const HTML: Markup = Markup {
+    tags: html_tags,
+    escape: escape_html,
+};
+
+const DISCORD_MARKDOWN: Markup = Markup {
+    tags: discord_markdown_tags,
+    escape: escape_discord_markdown,
+};
+
+/// Render telegram text with its entities as HTML
+pub fn entities_to_html(text: &str, entities: &[MessageEntity]) -> String {
+    render(text, entities, &HTML)
+}
+
+/// Render telegram text with its entities as Discord markdown
+pub fn entities_to_discord_markdown(text: &str, entities: &[MessageEntity]) -> String {
+    render(text, entities, &DISCORD_MARKDOWN)
+}
+
+/// Entity offsets and lengths count UTF-16 code units, so the text is walked in those too.
+/// Telegram only hands out properly nested entities, which lets the closing tags be emitted
+/// in reverse order of the opening ones.
+fn render(text: &str, entities: &[MessageEntity], markup: &Markup) -> String {
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+
+    // Outer entities first when two start at the same place
+    let mut sorted: Vec<&MessageEntity> = entities.iter().collect();
+    sorted.sort_by(|a, b| a.offset.cmp(&b.offset).then(b.length.cmp(&a.length)));
+
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<(usize, Tags)> = Vec::new();
+    let mut pos = 0;
+
+    for entity in sorted {
+        let tags = match (markup.tags)(entity, &utf16) {
+            Some(tags) => tags,
+            None => continue,
+        };
+        let start = entity.offset.min(utf16.len()).max(pos);
+
+        close_until(&mut out, &mut open, &utf16, &mut pos, start, markup);
+        push_text(&mut out, &open, &utf16[pos..start], markup);
+        pos = start;
+
+        out.push_str(&tags.open);
+        open.push(((entity.offset + entity.length).min(utf16.len()), tags));
+    }
+
+    close_until(&mut out, &mut open, &utf16, &mut pos, utf16.len(), markup);
+    push_text(&mut out, &open, &utf16[pos..], markup);
+    out
+}
+
+/// Close every open entity that ends at or before `up_to`, writing the text in between
+fn close_until(
+    out: &mut String,
+    open: &mut Vec<(usize, Tags)>,
+    utf16: &[u16],
+    pos: &mut usize,
+    up_to: usize,
+    markup: &Markup,
+) {
+    while let Some(&(end, _)) = open.last() {
+        if end > up_to {
+            break;
+        }
+        // Badly nested entities just get closed late
+        let end = end.max(*pos);
+        push_text(out, open, &utf16[*pos..end], markup);
+        *pos = end;
+        if let Some((_, tags)) = open.pop() {
+            out.push_str(&tags.close);
+        }
+    }
+}
+
+/// Write plain text, escaped unless it's inside a verbatim entity
+fn push_text(out: &mut String, open: &[(usize, Tags)], utf16: &[u16], markup: &Markup) {
+    let text = String::from_utf16_lossy(utf16);
+    if open.iter().any(|(_, tags)| tags.verbatim) {
+        out.push_str(&text);
+    } else {
+        out.push_str(&(markup.escape)(&text));
+    }
+}
+
+/// The text of a `Url` entity, which is its own link
+fn entity_text(entity: &MessageEntity, utf16: &[u16]) -> String {
+    let end = (entity.offset + entity.length).min(utf16.len());
+    String::from_utf16_lossy(&utf16[entity.offset.min(end)..end])
+}
+
+/// The tags for an entity in HTML, or None if it renders as plain text
+fn html_tags(entity: &MessageEntity, utf16: &[u16]) -> Option<Tags> {
+    let (open, close) = match &entity.kind {
+        MessageEntityKind::Bold => ("<b>".to_string(), "</b>"),
+        MessageEntityKind::Italic => ("<i>".to_string(), "</i>"),
+        MessageEntityKind::Underline => ("<u>".to_string(), "</u>"),
+        MessageEntityKind::Strikethrough => ("<s>".to_string(), "</s>"),
+        MessageEntityKind::Code => ("<code>".to_string(), "</code>"),
+        MessageEntityKind::Pre { language: None } => ("<pre><code>".to_string(), "</code></pre>"),
+        MessageEntityKind::Pre {
+            language: Some(language),
+        } => (
+            format!("<pre><code class=\"language-{}\">", escape_html(language)),
+            "</code></pre>",
+        ),
+        MessageEntityKind::TextLink { url } => (
+            format!("<a href=\"{}\">", escape_html(url.as_str())),
+            "</a>",
+        ),
+        MessageEntityKind::Url => (
+            format!("<a href=\"{}\">", escape_html(&entity_text(entity, utf16))),
+            "</a>",
+        ),
+        _ => return None,
+    };
+    Some(Tags {
+        open,
+        close: close.to_string(),
+        verbatim: false,
+    })
+}
+
+/// The markers for an entity in Discord markdown, or None if it's left as plain text.
+/// Bare URLs are linked by Discord on its own.
+fn discord_markdown_tags(entity: &MessageEntity, _utf16: &[u16]) -> Option<Tags> {
+    let (open, close, verbatim) = match &entity.kind {
+        MessageEntityKind::Bold => ("**".to_string(), "**".to_string(), false),
+        MessageEntityKind::Italic => ("*".to_string(), "*".to_string(), false),
+        MessageEntityKind::Underline => ("__".to_string(), "__".to_string(), false),
+        MessageEntityKind::Strikethrough => ("~~".to_string(), "~~".to_string(), false),
+        MessageEntityKind::Spoiler => ("||".to_string(), "||".to_string(), false),
+        MessageEntityKind::Code => ("`".to_string(), "`".to_string(), true),
+        MessageEntityKind::Pre { language } => (
+            format!("```{}\n", language.as_deref().unwrap_or_default()),
+            "\n```".to_string(),
+            true,
+        ),
+        MessageEntityKind::TextLink { url } => (
+            "[".to_string(),
+            format!("]({})", escape_link_target(url.as_str())),
+            false,
+        ),
+        _ => return None,
+    };
+    Some(Tags {
+        open,
+        close,
+        verbatim,
+    })
+}
+
+/// Hashtags telegram found in the text, as written with their `#`
+pub fn hashtags(text: &str, entities: &[MessageEntity]) -> Vec<String> {
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+    entities
+        .iter()
+        .filter(|entity| matches!(entity.kind, MessageEntityKind::Hashtag))
+        .map(|entity| entity_text(entity, &utf16))
+        .collect()
+}
+
+/// Brackets would end the link target early, and they mean the same percent-encoded
+fn escape_link_target(url: &str) -> String {
+    url.replace('(', "%28").replace(')', "%29")
+}
+
+/// Escape the characters Discord would otherwise read as formatting, including masked links,
+/// quotes and headings
+pub fn escape_discord_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']' | '>' | '#'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escape text for use in HTML content or a quoted attribute
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Inline style markers in Discord markdown, longest first so `**` wins over `*`
+const DISCORD_STYLES: &[&str] = &["**", "__", "~~", "*", "_"];
+
+/// Convert Discord markdown into plain text plus telegram entities.
+///
+/// Handles bold, italic, underline, strikethrough, inline code, code blocks and masked links.
+/// Markers that never get closed are dropped, and anything else is kept as it was written.
+pub fn discord_markdown_to_entities(markdown: &str) -> (String, Vec<MessageEntity>) {
+    let mut out = EntityText::default();
+    // Styles that are currently open, with their marker and where they started
+    let mut open: Vec<(&'static str, usize)> = Vec::new();
+    let mut rest = markdown;
+
+    while let Some(c) = rest.chars().next() {
+        // Escaped punctuation is always literal
+        if let Some(escaped) = rest
+            .strip_prefix('\\')
+            .and_then(|after| after.chars().next())
+        {
+            if escaped.is_ascii_punctuation() {
+                out.push(escaped);
+                rest = &rest[1 + escaped.len_utf8()..];
+                continue;
+            }
+        }
+
+        // Nothing inside code is formatted
+        if let Some(after) = rest.strip_prefix("```") {
+            if let Some(end) = after.find("```") {
+                let block = &after[..end];
+                let (language, code) = match block.split_once('\n') {
+                    Some((first, code))
+                        if !first.is_empty() && !first.contains(char::is_whitespace) =>
+                    {
+                        (Some(first.to_string()), code)
+                    }
+                    _ => (None, block.strip_prefix('\n').unwrap_or(block)),
+                };
+                out.push_entity(MessageEntityKind::Pre { language }, code);
+                rest = &after[end + 3..];
+                continue;
+            }
+        }
+        if let Some(after) = rest.strip_prefix('`') {
+            if let Some(end) = after.find('`') {
+                out.push_entity(MessageEntityKind::Code, &after[..end]);
+                rest = &after[end + 1..];
+                continue;
+            }
+        }
+
+        if c == '[' {
+            if let Some((label, url, consumed)) = masked_link(rest) {
+                out.push_entity(MessageEntityKind::TextLink { url }, label);
+                rest = &rest[consumed..];
+                continue;
+            }
+        }
+
+        if let Some(&marker) = DISCORD_STYLES
+            .iter()
+            .find(|marker| rest.starts_with(**marker))
+        {
+            let after = &rest[marker.len()..];
+            if let Some(index) = open
+                .iter()
+                .rposition(|(open_marker, _)| *open_marker == marker)
+            {
+                let (_, start) = open.remove(index);
+                if out.utf16_len > start {
+                    out.entities.push(MessageEntity::new(
+                        style_kind(marker),
+                        start,
+                        out.utf16_len - start,
+                    ));
+                }
+                rest = after;
+                continue;
+            }
+            // A lone `_` inside a word, like in snake_case, isn't italics
+            let inside_word = marker == "_" && out.text.ends_with(char::is_alphanumeric);
+            if after.contains(marker) && !inside_word {
+                open.push((marker, out.utf16_len));
+                rest = after;
+                continue;
+            }
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    (out.text, out.entities)
+}
+
+#[derive(Default)]
+struct EntityText {
+    text: String,
+    entities: Vec<MessageEntity>,
+    /// Length of `text` in UTF-16 code units, what entity offsets are measured in
+    utf16_len: usize,
+}
+
+impl EntityText {
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.utf16_len += c.len_utf16();
+    }
+
+    fn push_entity(&mut self, kind: MessageEntityKind, text: &str) {
+        let start = self.utf16_len;
+        text.chars().for_each(|c| self.push(c));
+        if self.utf16_len > start {
+            self.entities
+                .push(MessageEntity::new(kind, start, self.utf16_len - start));
+        }
+    }
+}
+
+fn style_kind(marker: &str) -> MessageEntityKind {
+    match marker {
+        "**" => MessageEntityKind::Bold,
+        "__" => MessageEntityKind::Underline,
+        "~~" => MessageEntityKind::Strikethrough,
+        _ => MessageEntityKind::Italic,
+    }
+}
+
+/// Parse `[label](url)` at the start of the text, returning the label, URL and bytes consumed.
+///
+/// The label ends at the first `]`, which has to be followed straight away by `(`. URLs in angle
+/// brackets can contain `)`, bare ones end at the first one.
+fn masked_link(text: &str) -> Option<(&str, reqwest::Url, usize)> {
+    let label_end = text.find(']')?;
+    let label = &text[1..label_end];
+    if label.is_empty() || label.contains('\n') {
+        return None;
+    }
+    let target = text[label_end + 1..].strip_prefix('(')?;
+    let target_start = label_end + 2;
+    let (url, consumed) = match target.strip_prefix('<') {
+        Some(bracketed) => {
+            let url_end = bracketed.find(">)")?;
+            (&bracketed[..url_end], target_start + 1 + url_end + 2)
+        }
+        None => {
+            let url_end = target.find(')')?;
+            (&target[..url_end], target_start + url_end + 1)
+        }
+    };
+    if url.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((label, url.parse().ok()?, consumed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(kind: MessageEntityKind, offset: usize, length: usize) -> MessageEntity {
+        MessageEntity::new(kind, offset, length)
+    }
+
+    #[test]
+    fn nested_styles() {
+        let (text, entities) = discord_markdown_to_entities("**bold _both_** plain");
+        assert_eq!(text, "bold both plain");
+        assert_eq!(
+            entities,
+            [
+                entity(MessageEntityKind::Italic, 5, 4),
+                entity(MessageEntityKind::Bold, 0, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_and_stray_markers_stay_literal() {
+        let (text, entities) = discord_markdown_to_entities(r"\*not bold\* snake_case \_");
+        assert_eq!(text, "*not bold* snake_case _");
+        assert!(entities.is_empty());
+
+        let (text, entities) = discord_markdown_to_entities("**never closed");
+        assert_eq!(text, "**never closed");
+        assert!(entities.is_empty());
+    }
+
+    #[test]
+    fn code_is_not_formatted() {
+        let (text, entities) =
+            discord_markdown_to_entities("`**x**` and ```rust\nfn main() {}\n```");
+        assert_eq!(text, "**x** and fn main() {}\n");
+        assert_eq!(
+            entities,
+            [
+                entity(MessageEntityKind::Code, 0, 5),
+                entity(
+                    MessageEntityKind::Pre {
+                        language: Some("rust".to_string())
+                    },
+                    10,
+                    13
+                ),
+            ]
+        );
+
+        let (text, entities) = discord_markdown_to_entities("```\n*code*```");
+        assert_eq!(text, "*code*");
+        assert_eq!(
+            entities,
+            [entity(MessageEntityKind::Pre { language: None }, 0, 6)]
+        );
+    }
+
+    #[test]
+    fn offsets_are_in_utf16() {
+        let (text, entities) = discord_markdown_to_entities("😀 **hi** ü");
+        assert_eq!(text, "😀 hi ü");
+        assert_eq!(entities, [entity(MessageEntityKind::Bold, 3, 2)]);
+    }
+
+    #[test]
+    fn masked_links() {
+        let (text, entities) = discord_markdown_to_entities("[docs](https://example.com) here");
+        assert_eq!(text, "docs here");
+        assert_eq!(
+            entities,
+            [entity(
+                MessageEntityKind::TextLink {
+                    url: "https://example.com".parse().unwrap()
+                },
+                0,
+                4
+            )]
+        );
+
+        let (text, entities) = discord_markdown_to_entities(
+            "[Rust](<https://en.wikipedia.org/wiki/Rust_(programming_language)>)",
+        );
+        assert_eq!(text, "Rust");
+        assert_eq!(
+            entities,
+            [entity(
+                MessageEntityKind::TextLink {
+                    url: "https://en.wikipedia.org/wiki/Rust_(programming_language)"
+                        .parse()
+                        .unwrap()
+                },
+                0,
+                4
+            )]
+        );
+    }
+
+    #[test]
+    fn escapes_everything_discord_would_format() {
+        assert_eq!(
+            escape_discord_markdown("> #1 [a](b) *c* _d_ ~e~ `f` |g| \\"),
+            "\\> \\#1 \\[a\\](b) \\*c\\* \\_d\\_ \\~e\\~ \\`f\\` \\|g\\| \\\\"
+        );
+    }
+
+    #[test]
+    fn link_targets_keep_their_brackets_apart() {
+        let text = "Rust";
+        let url = "https://en.wikipedia.org/wiki/Rust_(programming_language)";
+        let entities = [entity(
+            MessageEntityKind::TextLink {
+                url: url.parse().unwrap(),
+            },
+            0,
+            4,
+        )];
+        assert_eq!(
+            entities_to_discord_markdown(text, &entities),
+            "[Rust](https://en.wikipedia.org/wiki/Rust_%28programming_language%29)"
+        );
+    }
+
+    #[test]
+    fn not_quite_masked_links() {
+        for markdown in [
+            "[a] (https://example.com)",
+            "[a]b](https://example.com)",
+            "[](https://example.com)",
+            "[a](not a url)",
+        ] {
+            let (text, entities) = discord_markdown_to_entities(markdown);
+            assert_eq!(text, markdown);
+            assert!(entities.is_empty(), "{}", markdown);
+        }
+    }
+}
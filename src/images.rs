@@ -0,0 +1,336 @@
+use std::{
+    env::var,
+    path::{Path, PathBuf},
+};
+
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, io::Reader, DynamicImage, Rgb, RgbImage,
+};
+use log::{debug, info, warn};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    media_cache::{cache_get, cache_put},
+    types::{DownloadedAttachment, FileData, MyResult},
+    utils::{file_stem, make_error},
+    DISCORD_MAX_FILE_SIZE,
+};
+
+/// Cache variant for images that were recompressed or had their metadata stripped
+const DISCORD_IMAGE_VARIANT: &str = "discord-image";
+/// Qualities to try at each size before giving up and shrinking the image
+const JPEG_QUALITIES: [u8; 4] = [90, 80, 70, 60];
+/// Scale factors for the image dimensions, tried in order
+const SCALES: [f32; 5] = [1.0, 0.75, 0.5, 0.35, 0.25];
+/// Quality used when the image already fits and we only want the metadata gone
+const STRIP_QUALITY: u8 = 92;
+/// The EXIF segment has to come before the image data and can't be over 64 KiB, so it's always
+/// somewhere in this much of the start of a file
+const EXIF_SCAN_LEN: u64 = 128 * 1024;
+
+lazy_static! {
+    /// On unless set to `0` or `false`, since EXIF tends to carry GPS coordinates
+    static ref STRIP_IMAGE_METADATA: bool = var("STRIP_IMAGE_METADATA")
+        .map(|value| value != "0" && !value.eq_ignore_ascii_case("false"))
+        .unwrap_or(true);
+}
+
+/// Where the image to decode is. Spooled files are decoded straight off the disk, instead of
+/// holding both the file and the decoded image in memory.
+enum Source {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
+
+/// Make an image fit under the upload limit and drop its EXIF metadata.
+///
+/// Oversized images are re-encoded as JPEG at decreasing quality, then decreasing size, until they
+/// fit. WebP would come out smaller, but the `image` crate can only encode it losslessly, which
+/// doesn't help, and lossy WebP would mean linking libwebp. Anything going wrong hands the original
+/// back, leaving it to the oversize policy.
+pub async fn shrink_image(
+    attachment: DownloadedAttachment,
+    file_unique_id: &str,
+) -> DownloadedAttachment {
+    let size = match attachment.data.size() {
+        Ok(size) => size,
+        Err(_) => return attachment,
+    };
+    let oversized = size > *DISCORD_MAX_FILE_SIZE;
+
+    if !oversized && !*STRIP_IMAGE_METADATA {
+        return attachment;
+    }
+
+    let (source, head) = match &attachment.data {
+        FileData::Memory(data, _) => {
+            let head = data[..data.len().min(EXIF_SCAN_LEN as usize)].to_vec();
+            (Source::Bytes(data.clone()), head)
+        }
+        FileData::Spooled(file) => match read_head(file.path()).await {
+            Ok(head) => (Source::File(file.path().to_path_buf()), head),
+            Err(_) => return attachment,
+        },
+    };
+
+    // Only JPEGs carry EXIF in practice, everything else that fits can go as is
+    let has_exif = exif_segment(&head).is_some();
+    if !oversized && !has_exif {
+        return attachment;
+    }
+
+    let file_name = format!("{}.jpg", file_stem(&attachment.file_name));
+    if let Some(data) = cache_get(file_unique_id, Some(DISCORD_IMAGE_VARIANT), &file_name).await {
+        return DownloadedAttachment {
+            file_name,
+            file_unique_id: file_unique_id.to_string(),
+            data,
+            original: Some(Box::new(attachment)),
+        };
+    }
+
+    let budget = *DISCORD_MAX_FILE_SIZE;
+    // The spooled file belongs to `attachment`, which outlives this
+    let result = tokio::task::spawn_blocking(move || {
+        let image = decode(&source, &head)?;
+        if oversized {
+            recompress(&image, size, budget)
+        } else {
+            encode_jpeg(&image, STRIP_QUALITY)
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(data)) => {
+            let data = FileData::Memory(data, None);
+            cache_put(file_unique_id, Some(DISCORD_IMAGE_VARIANT), &data).await;
+            DownloadedAttachment {
+                file_name,
+                file_unique_id: file_unique_id.to_string(),
+                data,
+                original: Some(Box::new(attachment)),
+            }
+        }
+        Ok(Err(err)) => {
+            warn!("Failed to process image {}: {}", attachment.file_name, err);
+            attachment
+        }
+        Err(err) => {
+            warn!(
+                "Image processing for {} panicked: {}",
+                attachment.file_name, err
+            );
+            attachment
+        }
+    }
+}
+
+/// The start of a file, enough to find its EXIF metadata in
+async fn read_head(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    tokio::fs::File::open(path)
+        .await?
+        .take(EXIF_SCAN_LEN)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(head)
+}
+
+/// Find the smallest amount of damage that gets the image under `budget` bytes
+fn recompress(image: &DynamicImage, size: u64, budget: u64) -> MyResult<Vec<u8>> {
+    for scale in SCALES {
+        let scaled = if scale < 1.0 {
+            let width = (image.width() as f32 * scale) as u32;
+            let height = (image.height() as f32 * scale) as u32;
+            image.resize(width.max(1), height.max(1), FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+
+        for quality in JPEG_QUALITIES {
+            let encoded = encode_jpeg(&scaled, quality)?;
+            debug!(
+                "Image at {}x{} and quality {} is {} bytes",
+                scaled.width(),
+                scaled.height(),
+                quality,
+                encoded.len()
+            );
+            if encoded.len() as u64 <= budget {
+                info!(
+                    "Recompressed image from {} to {} bytes",
+                    size,
+                    encoded.len()
+                );
+                return Ok(encoded);
+            }
+        }
+    }
+
+    Err(make_error("Couldn't get the image under the upload limit"))
+}
+
+/// Decode an image, applying any EXIF rotation first since the tag won't survive re-encoding.
+/// `head` is the start of the file, where the EXIF metadata is.
+fn decode(source: &Source, head: &[u8]) -> MyResult<DynamicImage> {
+    let image = match source {
+        Source::Bytes(bytes) => image::load_from_memory(bytes)?,
+        Source::File(path) => Reader::open(path)?.with_guessed_format()?.decode()?,
+    };
+
+    let image = match exif_segment(head).and_then(exif_orientation) {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    };
+    Ok(image)
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> MyResult<Vec<u8>> {
+    let rgb = flatten(image);
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, quality).encode_image(&rgb)?;
+    Ok(encoded)
+}
+
+/// JPEG has no alpha channel, so put transparent areas on white instead of letting them go black
+fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend =
+            |channel: u8| ((channel as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// Find the body of a JPEG's EXIF APP1 segment, starting at the TIFF header
+fn exif_segment(bytes: &[u8]) -> Option<&[u8]> {
+    // Not a JPEG
+    if bytes.get(0..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] != 0xFF {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        // Start of scan, no more metadata after this
+        if marker == 0xDA {
+            return None;
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let body = bytes.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE1 && body.starts_with(b"Exif\0\0") {
+            return Some(&body[6..]);
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+/// Read the orientation tag out of IFD0
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |at: usize| -> Option<u16> {
+        let raw = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(raw)
+        } else {
+            u16::from_le_bytes(raw)
+        })
+    };
+    let read_u32 = |at: usize| -> Option<u32> {
+        let raw = [
+            *tiff.get(at)?,
+            *tiff.get(at + 1)?,
+            *tiff.get(at + 2)?,
+            *tiff.get(at + 3)?,
+        ];
+        Some(if big_endian {
+            u32::from_be_bytes(raw)
+        } else {
+            u32::from_le_bytes(raw)
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    for index in 0..entries {
+        let entry = ifd + 2 + index * 12;
+        if read_u16(entry)? == 0x0112 {
+            return read_u16(entry + 8);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::png::PngEncoder, ColorType, ImageEncoder};
+
+    use super::*;
+
+    /// A noisy image, so it doesn't compress down to nothing
+    fn noise(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let value = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) as u8;
+            Rgb([value, value.wrapping_mul(3), value.wrapping_add(y as u8)])
+        }))
+    }
+
+    #[test]
+    fn decodes_spooled_files_from_disk() {
+        let rgb = noise(64, 48).to_rgb8();
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&rgb, 64, 48, ColorType::Rgb8)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("decode-test-{}.png", std::process::id()));
+        std::fs::write(&path, &png).unwrap();
+
+        let image = decode(&Source::File(path.clone()), &png).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 48));
+    }
+
+    #[test]
+    fn recompresses_under_the_budget() {
+        let image = noise(512, 512);
+        let full = encode_jpeg(&image, JPEG_QUALITIES[0]).unwrap().len() as u64;
+        let budget = full / 4;
+
+        let shrunk = recompress(&image, full, budget).unwrap();
+        assert!(shrunk.len() as u64 <= budget);
+        assert!(recompress(&image, full, 10).is_err());
+    }
+
+    #[test]
+    fn only_jpegs_have_exif() {
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&[0, 0, 0], 1, 1, ColorType::Rgb8)
+            .unwrap();
+        assert!(exif_segment(&png).is_none());
+
+        // SOI, then an APP1 segment holding a big endian TIFF header with orientation 6
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x22];
+        jpeg.extend_from_slice(b"Exif\0\0MM\0\x2A\0\0\0\x08\0\x01");
+        jpeg.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0x00, 0x06, 0, 0]);
+        jpeg.extend_from_slice(&[0, 0, 0, 0]);
+        let tiff = exif_segment(&jpeg).unwrap();
+        assert_eq!(exif_orientation(tiff), Some(6));
+    }
+}
@@ -1,40 +1,234 @@
 use dotenv::dotenv;
 use once_cell::sync::OnceCell;
 use serenity::http::Http;
-use std::{collections::HashMap, env::var};
+use std::{
+    collections::HashMap,
+    env::var,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 use teloxide::{dispatching::UpdateFilterExt, prelude::*, types::ChatId};
-use tokio::runtime::Runtime;
+use tokio::{runtime::Runtime, sync::Semaphore};
 
 use crate::{
-    telegram_events::message_handler,
-    types::{TgChannelData, WebhookData},
-    utils::make_webhook,
+    admin_commands::{command_handler, is_mirror_command},
+    backfill::run_backfill,
+    buttons::CallbackButtonPolicy,
+    media_cache::{run_cache_command, MediaCache},
+    media_server::spawn_media_server,
+    pings::PingRules,
+    reverse_mirror::{parse_reverse_routes, spawn_discord_client},
+    sinks::{
+        archive::ArchiveSink,
+        json_webhook::{parse_headers, JsonWebhookSink, MediaMode},
+        matrix::MatrixSink,
+        slack::SlackSink,
+        Sink,
+    },
+    spool::{self, clear_spool_dir},
+    state_lock::StateLock,
+    telegram_events::{edited_message_handler, message_handler},
+    templates::Template,
+    types::{Destination, OversizePolicy, PhotoSizePolicy, TgChannelData, WebhookData},
+    utils::{make_webhook, parse_duration},
+    webhook_server::{webhook_listener, UpdateMode},
 };
 
+mod admin_commands;
 mod attachments;
+mod backfill;
+mod buttons;
+mod digest;
+mod discord_commands;
+mod feeds;
+mod file_types;
+mod filters;
+mod formatting;
+mod images;
+mod media_cache;
+mod media_server;
+mod message_log;
+mod pings;
+mod reverse_mirror;
+mod routes;
+mod schedule;
+mod sinks;
+mod spool;
+mod state_lock;
 mod telegram_events;
+mod templates;
+mod transcode;
 mod types;
 mod utils;
+mod webhook_server;
 
 #[macro_use]
 extern crate lazy_static;
 
 lazy_static! {
     static ref HTTP: Http = Http::new("e");
+    /// Shared client for every non-Discord HTTP API we talk to
+    static ref WEB_CLIENT: reqwest::Client = reqwest::Client::new();
     static ref AVATAR_URL: String = var("BOT_ICON").unwrap_or_else(|_| {
         "https://discord.com/assets/1f0bfc0865d324c2587920a7d80c609b.png".to_string()
     });
     static ref USERNAME: String =
         var("BOT_USERNAME").unwrap_or_else(|_| "Telegram Discord Mirror Bot".to_string());
+    /// Set when talking to a self-hosted Bot API server started with `--local`, which hands out
+    /// absolute paths on a shared volume instead of download URLs
+    static ref TELEGRAM_LOCAL_MODE: bool = env_flag("TELEGRAM_LOCAL_MODE");
+    /// Where downloads too big to keep in memory get written to. Each process spools into its own
+    /// directory in there, so clearing it out never touches anything else.
+    static ref SPOOL_ROOT: PathBuf = var("SPOOL_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir().join("tg_discord_mirror"));
+    static ref SPOOL_DIR: PathBuf = spool::process_spool_dir(&SPOOL_ROOT);
+    static ref SPOOL_THRESHOLD_BYTES: u32 = env_or("SPOOL_THRESHOLD_BYTES", 8 * 1024 * 1024);
+    static ref MAX_DOWNLOAD_KIB_IN_FLIGHT: u32 =
+        env_or("MAX_DOWNLOAD_BYTES_IN_FLIGHT", 256 * 1024 * 1024) / 1024;
+    /// One permit per download that may run at once
+    static ref DOWNLOAD_SLOTS: Semaphore =
+        Semaphore::new(env_or("MAX_CONCURRENT_DOWNLOADS", 4) as usize);
+    /// One permit per KiB of downloads that may be in flight or held in memory at once
+    static ref DOWNLOAD_BYTES: Arc<Semaphore> =
+        Arc::new(Semaphore::new(*MAX_DOWNLOAD_KIB_IN_FLIGHT as usize));
+    static ref MEDIA_CACHE: Option<MediaCache> = MediaCache::from_env();
+    /// Where the media server can be reached from outside, for sinks that link to media
+    static ref MEDIA_BASE_URL: Option<String> = var("MEDIA_BASE_URL").ok();
+    /// Where the message log is kept between runs, it only lives in memory if unset
+    static ref MESSAGE_LOG_PATH: Option<PathBuf> = var("MESSAGE_LOG_PATH").map(PathBuf::from).ok();
+    static ref MESSAGE_LOG_MAX_PER_CHAT: u32 = env_or("MESSAGE_LOG_MAX_PER_CHAT", 1000);
+    /// How many of the latest posts go in each feed
+    static ref FEED_MAX_ENTRIES: u32 = env_or("FEED_MAX_ENTRIES", 50);
+    /// Where posts waiting for a digest are kept between runs
+    static ref DIGEST_PATH: PathBuf = var("DIGEST_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("digests.json"));
+    /// Where posts held back by delays or quiet hours are kept between runs
+    static ref HELD_PATH: PathBuf = var("HELD_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("held.json"));
+    /// Taken by whichever process is writing the state files above, see `StateLock`
+    static ref STATE_LOCK_PATH: PathBuf = var("STATE_LOCK_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("tg_discord_mirror.lock"));
+    /// Where routes added with `/mirror_add` are kept between runs
+    static ref ROUTES_PATH: PathBuf = var("ROUTES_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("routes.json"));
+    /// Telegram user IDs allowed to manage routes with the `/mirror_` commands
+    static ref MIRROR_ADMINS: Vec<u64> = var("MIRROR_ADMINS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect();
+    /// Telegram chats Discord admins can subscribe to with `/telegram subscribe`, as
+    /// `<name>=<chat id>` pairs separated by commas
+    static ref SUBSCRIBE_SOURCES: Vec<(String, ChatId)> =
+        parse_subscribe_sources(&var("DISCORD_SUBSCRIBE_SOURCES").unwrap_or_default());
+    /// Lets `/telegram subscribe` mirror the Discord channel back into the Telegram chat as well,
+    /// which needs the privileged message content intent
+    static ref DISCORD_SUBSCRIBE_BOTH_WAYS: bool = env_flag("DISCORD_SUBSCRIBE_BOTH_WAYS");
+    /// Discord channels mirrored into Telegram chats, as `<discord channel id>=<telegram chat id>`
+    /// pairs separated by commas
+    static ref DISCORD_TO_TELEGRAM: HashMap<u64, ChatId> =
+        parse_reverse_routes(&var("DISCORD_TO_TELEGRAM").unwrap_or_default())
+            .unwrap_or_else(|err| config_error(&format!("DISCORD_TO_TELEGRAM is invalid: {}", err)));
+    /// Posts with this hashtag go out without notifications, written without the `#`. Telegram
+    /// doesn't tell bots when a post was sent silently, so this stands in for it.
+    static ref SILENT_HASHTAG: Option<String> = var("SILENT_HASHTAG")
+        .ok()
+        .map(|tag| tag.trim().trim_start_matches('#').to_string())
+        .filter(|tag| !tag.is_empty());
+    /// Biggest upload a webhook can make, 8 MB unless the server is boosted
+    static ref DISCORD_MAX_FILE_SIZE: u64 = env_or("DISCORD_MAX_FILE_SIZE", 8 * 1024 * 1024) as u64;
+    /// Default for how channels pick photo sizes, see `PhotoSizePolicy::from_str`
+    static ref PHOTO_SIZE_POLICY: PhotoSizePolicy = var("PHOTO_SIZE_POLICY")
+        .map(|policy| parse_setting("PHOTO_SIZE_POLICY", &policy))
+        .unwrap_or(PhotoSizePolicy::FitBudget);
+    static ref OVERSIZE_POLICY: OversizePolicy = match var("OVERSIZE_POLICY").as_deref() {
+        Ok("send") => OversizePolicy::Send,
+        _ => OversizePolicy::Skip,
+    };
+}
+
+/// Stop before starting anything, for settings that can't work the way they're written
+fn config_error(message: &str) -> ! {
+    log::error!("{}", message);
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// Parse the value of the setting `key`, stopping with an error if it isn't valid
+fn parse_setting<T>(key: &str, value: &str) -> T
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .unwrap_or_else(|err| config_error(&format!("{} `{}` is invalid: {}", key, value, err)))
+}
+
+/// Parse `<name>=<chat id>` pairs separated by commas, stopping with an error on a bad one
+fn parse_subscribe_sources(sources: &str) -> Vec<(String, ChatId)> {
+    sources
+        .split(',')
+        .map(str::trim)
+        .filter(|source| !source.is_empty())
+        .map(|source| {
+            let (name, chat_id) = source
+                .split_once('=')
+                .filter(|(name, _)| !name.trim().is_empty())
+                .unwrap_or_else(|| {
+                    config_error(&format!(
+                        "DISCORD_SUBSCRIBE_SOURCES entry `{}` should be <name>=<chat id>",
+                        source
+                    ))
+                });
+            (
+                name.trim().to_string(),
+                ChatId(parse_setting("DISCORD_SUBSCRIBE_SOURCES", chat_id.trim())),
+            )
+        })
+        .collect()
+}
+
+/// Read a boolean flag from the environment, anything but `1` or `true` counts as off
+fn env_flag(key: &str) -> bool {
+    var(key)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Read a number from the environment, falling back to the default if it's missing or garbage
+fn env_or(key: &str, default: u32) -> u32 {
+    var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
 }
 
 static RUNTIME: OnceCell<Runtime> = OnceCell::new();
 static BOT: OnceCell<AutoSend<Bot>> = OnceCell::new();
-static CHANNEL_DATA_WEBHOOK: OnceCell<HashMap<ChatId, TgChannelData>> = OnceCell::new();
+/// Where each chat gets mirrored to, which the `/mirror_` commands can change at runtime
+static CHANNEL_DATA_WEBHOOK: OnceCell<RwLock<HashMap<ChatId, Arc<TgChannelData>>>> =
+    OnceCell::new();
 
 fn main() {
     dotenv().ok();
     pretty_env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("cache") {
+        if let Err(err) = run_cache_command(args.get(1).map(String::as_str)) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     log::info!("Starting the runtime...");
 
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -44,10 +238,95 @@ fn main() {
 
     RUNTIME.set(rt).unwrap();
 
+    if args.first().map(String::as_str) == Some("backfill") {
+        if let Err(err) = RUNTIME.get().unwrap().block_on(run_backfill(&args[1..])) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     RUNTIME.get().unwrap().block_on(async_main());
 }
 
 async fn async_main() {
+    routes::init(build_channel_data().await).await.unwrap();
+
+    let mut bot = Bot::from_env();
+    // Point at a self-hosted Bot API server to get past the 20 MB download limit
+    if let Ok(api_url) = var("TELEGRAM_API_URL") {
+        log::info!("Using Bot API server at {}", api_url);
+        let api_url = api_url.parse().unwrap_or_else(|err| {
+            config_error(&format!(
+                "TELEGRAM_API_URL `{}` isn't a valid URL: {}",
+                api_url, err
+            ))
+        });
+        bot = bot.set_api_url(api_url);
+    }
+    let bot = bot.auto_send();
+
+    BOT.set(bot).unwrap();
+
+    let update_mode = UpdateMode::from_env().unwrap_or_else(|err| config_error(&err.to_string()));
+
+    // Clean up after earlier runs that didn't get to do it themselves
+    clear_spool_dir();
+
+    // Held until the end, after the last of the state is saved
+    let _state_lock =
+        StateLock::acquire(&STATE_LOCK_PATH).unwrap_or_else(|err| config_error(&err.to_string()));
+    message_log::load().unwrap();
+    digest::load().unwrap();
+    schedule::load().unwrap();
+    digest::spawn_digest_timer();
+    spawn_media_server().unwrap();
+    // Complain about bad sources now, not when someone first runs `/telegram`
+    lazy_static::initialize(&SUBSCRIBE_SOURCES);
+    lazy_static::initialize(&DISCORD_TO_TELEGRAM);
+    spawn_discord_client().await.unwrap();
+
+    let handler = dptree::entry()
+        // Commands posted in a channel are handled, never mirrored
+        .branch(
+            Update::filter_channel_post()
+                .branch(
+                    dptree::filter(|m: Message| is_mirror_command(&m)).endpoint(command_handler),
+                )
+                .branch(dptree::endpoint(message_handler)),
+        )
+        .branch(
+            Update::filter_edited_channel_post()
+                .filter(|m: Message| !is_mirror_command(&m))
+                .endpoint(edited_message_handler),
+        )
+        .branch(Update::filter_message().endpoint(command_handler));
+
+    let mut dispatcher = Dispatcher::builder(BOT.get().unwrap(), handler)
+        .build()
+        .setup_ctrlc_handler();
+
+    match update_mode {
+        UpdateMode::Polling => dispatcher.dispatch().await,
+        UpdateMode::Webhook(config) => {
+            let listener = webhook_listener(BOT.get().unwrap(), config).await.unwrap();
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("An error from the webhook listener"),
+                )
+                .await
+        }
+    }
+
+    message_log::flush().await;
+    digest::flush().await;
+    schedule::flush().await;
+    clear_spool_dir();
+}
+
+/// Set up every route, from the source chat to where its posts get mirrored
+async fn build_channel_data() -> HashMap<ChatId, TgChannelData> {
     // Ziah Testing
     let webhook_url_1 = var("WEBHOOK_URL_1").unwrap();
     // Nasa Brain
@@ -58,26 +337,96 @@ async fn async_main() {
     let webhook_url_4 = var("WEBHOOK_URL_4").unwrap();
     // Ziah's fwd target #2
     let webhook_url_5 = var("WEBHOOK_URL_5").unwrap();
+    // Slack mirror of my channel, files only go up if there's a bot token
+    let slack_webhook_url_1 = var("SLACK_WEBHOOK_URL_1").ok();
+    let slack_channel_id_1 = var("SLACK_CHANNEL_ID_1").ok();
+    let slack_bot_token = var("SLACK_BOT_TOKEN").ok();
+    // Matrix room for my channel, any homeserver (or a local stand-in) will do
+    let matrix_homeserver = var("MATRIX_HOMESERVER_URL").ok();
+    let matrix_access_token = var("MATRIX_ACCESS_TOKEN").ok();
+    let matrix_room_id_1 = var("MATRIX_ROOM_ID_1").ok();
+    // Our internal services, which take the raw JSON stream
+    let json_webhook_url_1 = var("JSON_WEBHOOK_URL_1").ok();
 
     let mut channel_data: HashMap<ChatId, TgChannelData> = HashMap::new();
 
     // My Channel
+    let mut my_destinations = vec![
+        destination(Arc::new(WebhookData {
+            raw_webhook: make_webhook(&webhook_url_1).await.unwrap(),
+            webhook_username: "The Queen's Herald".into(),
+            icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into(),
+            callback_buttons: callback_buttons("WEBHOOK_CALLBACK_BUTTONS_1", CallbackButtonPolicy::Text),
+            template: template("WEBHOOK_TEMPLATE_1"),
+            pings: pings("WEBHOOK_PINGS_1"),
+        }), "WEBHOOK", 1),
+        destination(Arc::new(WebhookData {
+            raw_webhook: make_webhook(&webhook_url_2).await.unwrap(),
+            webhook_username: "eeee??".into(),
+            icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into(),
+            callback_buttons: callback_buttons("WEBHOOK_CALLBACK_BUTTONS_2", CallbackButtonPolicy::Skip),
+            template: template("WEBHOOK_TEMPLATE_2"),
+            pings: pings("WEBHOOK_PINGS_2"),
+        }), "WEBHOOK", 2),
+    ];
+    if let Some(webhook_url) = slack_webhook_url_1 {
+        if slack_bot_token.is_some() && slack_channel_id_1.is_none() {
+            config_error("SLACK_BOT_TOKEN needs SLACK_CHANNEL_ID_1 to share files in the channel");
+        }
+        my_destinations.push(destination(
+            Arc::new(SlackSink {
+                webhook_url,
+                bot_token: slack_bot_token,
+                channel_id: slack_channel_id_1,
+                callback_buttons: callback_buttons(
+                    "SLACK_CALLBACK_BUTTONS_1",
+                    CallbackButtonPolicy::Text,
+                ),
+            }),
+            "SLACK",
+            1,
+        ));
+    }
+    if let (Some(homeserver), Some(access_token), Some(room_id)) =
+        (matrix_homeserver, matrix_access_token, matrix_room_id_1)
+    {
+        my_destinations.push(destination(
+            Arc::new(MatrixSink {
+                homeserver: homeserver.parse().unwrap(),
+                access_token,
+                room_id,
+                max_file_size: env_or("MATRIX_MAX_FILE_SIZE", 50 * 1024 * 1024) as u64,
+            }),
+            "MATRIX",
+            1,
+        ));
+    }
+    if let Some(url) = json_webhook_url_1 {
+        my_destinations.push(destination(
+            Arc::new(JsonWebhookSink {
+                url,
+                secret: var("JSON_WEBHOOK_SECRET_1").ok(),
+                headers: parse_headers(&var("JSON_WEBHOOK_HEADERS_1").unwrap_or_default())
+                    .unwrap_or_else(|err| {
+                        config_error(&format!("JSON_WEBHOOK_HEADERS_1 is invalid: {}", err))
+                    }),
+                media: var("JSON_WEBHOOK_MEDIA_1")
+                    .map(|mode| parse_setting("JSON_WEBHOOK_MEDIA_1", &mode))
+                    .unwrap_or(MediaMode::None),
+                max_file_size: env_or("JSON_WEBHOOK_MAX_FILE_SIZE", 25 * 1024 * 1024) as u64,
+                max_retries: env_or("JSON_WEBHOOK_RETRIES", 3),
+            }),
+            "JSON_WEBHOOK",
+            1,
+        ));
+    }
+
     channel_data.insert(
         ChatId(-1001765404638),
         TgChannelData {
             chat_id: ChatId(-1001765404638),
-            webhooks: vec![
-                WebhookData {
-                    raw_webhook: make_webhook(&webhook_url_1).await.unwrap(),
-                    webhook_username: "The Queen's Herald".into(),
-                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into()
-                },
-                WebhookData {
-                    raw_webhook: make_webhook(&webhook_url_2).await.unwrap(),
-                    webhook_username: "eeee??".into(),
-                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into()
-                }
-            ],
+            photo_size: photo_size(1),
+            destinations: my_destinations,
         },
     );
 
@@ -86,37 +435,112 @@ async fn async_main() {
         ChatId(-1001514642130),
         TgChannelData {
             chat_id: ChatId(-1001514642130),
-            webhooks: vec![
-                WebhookData {
+            photo_size: photo_size(2),
+            destinations: vec![
+                destination(Arc::new(WebhookData {
                     raw_webhook: make_webhook(&webhook_url_3).await.unwrap(),
                     webhook_username: "The Queen's Herald".into(),
-                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into()
-                },
-                WebhookData {
+                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into(),
+                    callback_buttons: callback_buttons("WEBHOOK_CALLBACK_BUTTONS_3", CallbackButtonPolicy::Skip),
+                    template: template("WEBHOOK_TEMPLATE_3"),
+                    pings: pings("WEBHOOK_PINGS_3"),
+                }), "WEBHOOK", 3),
+                destination(Arc::new(WebhookData {
                     raw_webhook: make_webhook(&webhook_url_4).await.unwrap(),
                     webhook_username: "The Queen's Herald".into(),
-                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into()
-                },
-                WebhookData {
+                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into(),
+                    callback_buttons: callback_buttons("WEBHOOK_CALLBACK_BUTTONS_4", CallbackButtonPolicy::Skip),
+                    template: template("WEBHOOK_TEMPLATE_4"),
+                    pings: pings("WEBHOOK_PINGS_4"),
+                }), "WEBHOOK", 4),
+                destination(Arc::new(WebhookData {
                     raw_webhook: make_webhook(&webhook_url_5).await.unwrap(),
                     webhook_username: "The Queen's Herald".into(),
-                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into()
-                },
+                    icon_url: "https://cdn.discordapp.com/attachments/849900302965669919/994085960130244639/unknown.png".into(),
+                    callback_buttons: callback_buttons("WEBHOOK_CALLBACK_BUTTONS_5", CallbackButtonPolicy::Skip),
+                    template: template("WEBHOOK_TEMPLATE_5"),
+                    pings: pings("WEBHOOK_PINGS_5"),
+                }), "WEBHOOK", 5),
             ],
         },
     );
 
-    CHANNEL_DATA_WEBHOOK.set(channel_data).unwrap();
+    // Chats listed in ARCHIVE_CHATS also get written to the on-disk archive
+    if let Ok(archive_dir) = var("ARCHIVE_DIR") {
+        let archive: Arc<dyn Sink> = Arc::new(ArchiveSink::new(PathBuf::from(archive_dir)));
+        let chats = var("ARCHIVE_CHATS").unwrap_or_default();
+        for chat_id in chats.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            let chat_id = ChatId(parse_setting("ARCHIVE_CHATS", chat_id));
+            match channel_data.get_mut(&chat_id) {
+                Some(channel) => channel.destinations.push(Destination::new(archive.clone())),
+                None => log::warn!("Can't archive chat {}, it isn't mirrored", chat_id.0),
+            }
+        }
+    }
 
-    let bot = Bot::from_env().auto_send();
+    channel_data
+}
 
-    BOT.set(bot).unwrap();
+/// How the `n`th channel picks photo sizes, from `PHOTO_SIZE_POLICY_<n>` or the default in
+/// `PHOTO_SIZE_POLICY`. My channel is 1 and Ziah's is 2.
+fn photo_size(n: u32) -> PhotoSizePolicy {
+    let key = format!("PHOTO_SIZE_POLICY_{}", n);
+    match var(&key) {
+        Ok(policy) => parse_setting(&key, &policy),
+        Err(_) => *PHOTO_SIZE_POLICY,
+    }
+}
 
-    let handler = dptree::entry().branch(Update::filter_channel_post().endpoint(message_handler));
+/// The template in `key`, if it's set. See `Template` for how they're written.
+fn template(key: &str) -> Option<Template> {
+    var(key).ok().map(|template| parse_setting(key, &template))
+}
 
-    Dispatcher::builder(BOT.get().unwrap(), handler)
-        .build()
-        .setup_ctrlc_handler()
-        .dispatch()
-        .await;
+/// What to do with callback buttons according to `key`, `skip` or `text`, or `default` if it's unset
+fn callback_buttons(key: &str, default: CallbackButtonPolicy) -> CallbackButtonPolicy {
+    var(key)
+        .map(|policy| parse_setting(key, &policy))
+        .unwrap_or(default)
+}
+
+/// The ping rules in `key`, if it's set. See `PingRules::from_str` for how they're written.
+fn pings(key: &str) -> PingRules {
+    var(key)
+        .map(|rules| parse_setting(key, &rules))
+        .unwrap_or_default()
+}
+
+/// A destination for `sink`, only getting the posts that pass the filter in `<kind>_FILTER_<n>` if
+/// it's set. See `RouteFilter::from_str` for how filters are written.
+///
+/// `<kind>_PROTECTED_<n>` is `mirror`, `text-only` or `skip`, for what to do with posts telegram
+/// won't let people forward. They're mirrored like the rest unless it says otherwise.
+///
+/// Posts are collected into digests instead if `<kind>_DIGEST_<n>` is set, see
+/// `DigestPolicy::from_str`. `<kind>_QUIET_<n>` holds them back at certain times, see
+/// `QuietHours::from_str`, and `<kind>_DELAY_<n>` makes each one wait a while, like `5m`.
+fn destination(sink: Arc<dyn Sink>, kind: &str, n: u32) -> Destination {
+    Destination {
+        sink,
+        filter: var(format!("{}_FILTER_{}", kind, n))
+            .map(|filter| parse_setting(&format!("{}_FILTER_{}", kind, n), &filter))
+            .unwrap_or_default(),
+        protected_content: var(format!("{}_PROTECTED_{}", kind, n))
+            .map(|policy| parse_setting(&format!("{}_PROTECTED_{}", kind, n), &policy))
+            .unwrap_or_default(),
+        digest: var(format!("{}_DIGEST_{}", kind, n))
+            .ok()
+            .map(|policy| parse_setting(&format!("{}_DIGEST_{}", kind, n), &policy)),
+        quiet: var(format!("{}_QUIET_{}", kind, n))
+            .ok()
+            .map(|quiet| parse_setting(&format!("{}_QUIET_{}", kind, n), &quiet)),
+        delay: var(format!("{}_DELAY_{}", kind, n)).ok().map(|delay| {
+            parse_duration(&delay).unwrap_or_else(|err| {
+                config_error(&format!(
+                    "{}_DELAY_{} `{}` is invalid: {}",
+                    kind, n, delay, err
+                ))
+            })
+        }),
+    }
 }
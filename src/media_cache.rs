@@ -0,0 +1,429 @@
+use std::{
+    env::var,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    env_or,
+    spool::{link_or_copy, SpooledFile},
+    types::{FileData, MyResult},
+    utils::make_error,
+    MEDIA_CACHE, SPOOL_THRESHOLD_BYTES,
+};
+
+/// Expired entries are looked for at most this often, unless the cache is over its size limit
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Partial files younger than this are assumed to still be being written
+const PARTIAL_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Keeps the names of files being written unique within the process
+static NEXT_PARTIAL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// On-disk LRU cache of telegram media, keyed by `file_unique_id`.
+///
+/// Entries are plain files named after the key, and the modification time doubles as the last
+/// access time, so there's no index to keep in sync with the directory.
+#[derive(Debug)]
+pub struct MediaCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    usage: Mutex<CacheUsage>,
+}
+
+/// What's known about the cache directory without scanning it again
+#[derive(Debug, Default)]
+struct CacheUsage {
+    /// Running total of the entry sizes, None until the first scan
+    total_bytes: Option<u64>,
+    last_pruned: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub total_bytes: u64,
+    pub oldest: Option<SystemTime>,
+    pub newest: Option<SystemTime>,
+}
+
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl MediaCache {
+    /// The cache is only enabled when `MEDIA_CACHE_DIR` is set. `MEDIA_CACHE_MAX_MB` and
+    /// `MEDIA_CACHE_MAX_AGE_DAYS` bound it, defaulting to 1 GB and 30 days.
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(var("MEDIA_CACHE_DIR").ok()?);
+        let max_bytes = env_or("MEDIA_CACHE_MAX_MB", 1024) as u64 * 1024 * 1024;
+        let max_age = Duration::from_secs(env_or("MEDIA_CACHE_MAX_AGE_DAYS", 30) as u64 * 86400);
+
+        Some(Self::new(dir, max_bytes, max_age))
+    }
+
+    fn new(dir: PathBuf, max_bytes: u64, max_age: Duration) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            max_age,
+            usage: Mutex::new(CacheUsage::default()),
+        }
+    }
+
+    /// Variants hold post-processed versions of a file, like a transcoded video
+    fn entry_path(&self, file_unique_id: &str, variant: Option<&str>) -> PathBuf {
+        // Unique IDs are base64ish, but be careful anyway since they end up as file names
+        let key: String = file_unique_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        match variant {
+            Some(variant) => self.dir.join(format!("{}@{}", key, variant)),
+            None => self.dir.join(key),
+        }
+    }
+
+    /// Look up a file, handing back a copy the caller owns so eviction can't pull it out from under them
+    pub async fn get(
+        &self,
+        file_unique_id: &str,
+        variant: Option<&str>,
+        file_name: &str,
+    ) -> Option<FileData> {
+        let path = self.entry_path(file_unique_id, variant);
+        let metadata = tokio::fs::metadata(&path).await.ok()?;
+
+        let data = if metadata.len() > *SPOOL_THRESHOLD_BYTES as u64 {
+            let spooled = SpooledFile::new(file_name).await.ok()?;
+            link_or_copy(&path, spooled.path()).await.ok()?;
+            FileData::Spooled(spooled)
+        } else {
+            FileData::Memory(tokio::fs::read(&path).await.ok()?, None)
+        };
+
+        // Bump the entry to the front of the LRU order
+        if let Err(err) = touch(&path) {
+            warn!("Failed to update cache entry {}: {}", path.display(), err);
+        }
+
+        debug!("Media cache hit for {}", path.display());
+        Some(data)
+    }
+
+    /// Store a file, then evict whatever no longer fits. The directory is only scanned again when
+    /// the running total goes over the size limit, or to look for expired entries now and then.
+    pub async fn put(
+        &self,
+        file_unique_id: &str,
+        variant: Option<&str>,
+        data: &FileData,
+    ) -> MyResult<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        // Write next to the entry then rename, so readers never see a half written file. The name
+        // is unique so two puts of the same file can't write into each other.
+        let path = self.entry_path(file_unique_id, variant);
+        let partial = partial_path(&path);
+        let written = match data {
+            FileData::Memory(bytes, _) => tokio::fs::write(&partial, bytes).await,
+            FileData::Spooled(spooled) => link_or_copy(spooled.path(), &partial).await,
+        };
+        if let Err(err) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(err.into());
+        }
+        let replaced = tokio::fs::metadata(&path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        tokio::fs::rename(&partial, &path).await?;
+        let size = tokio::fs::metadata(&path).await?.len();
+
+        let needs_prune = {
+            let mut usage = self.usage.lock().unwrap();
+            let expiry_due = usage
+                .last_pruned
+                .map_or(true, |at| at.elapsed() >= EXPIRY_INTERVAL);
+            match &mut usage.total_bytes {
+                Some(total) => {
+                    *total = (*total + size).saturating_sub(replaced);
+                    *total > self.max_bytes || expiry_due
+                }
+                None => true,
+            }
+        };
+        if needs_prune {
+            let dir = self.dir.clone();
+            let (max_bytes, max_age) = (self.max_bytes, self.max_age);
+            let pruned =
+                tokio::task::spawn_blocking(move || prune_dir(&dir, max_bytes, max_age)).await??;
+            self.record_prune(pruned);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let entries = read_entries(&self.dir)?;
+
+        Ok(CacheStats {
+            entries: entries.len(),
+            total_bytes: entries.iter().map(|entry| entry.size).sum(),
+            oldest: entries.iter().map(|entry| entry.last_used).min(),
+            newest: entries.iter().map(|entry| entry.last_used).max(),
+        })
+    }
+
+    /// Drop expired entries, then the least recently used ones until the cache fits its size limit
+    pub fn prune(&self) -> io::Result<usize> {
+        let pruned = prune_dir(&self.dir, self.max_bytes, self.max_age)?;
+        self.record_prune(pruned);
+        Ok(pruned.removed)
+    }
+
+    fn record_prune(&self, pruned: Pruned) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.total_bytes = Some(pruned.remaining_bytes);
+        usage.last_pruned = Some(Instant::now());
+    }
+}
+
+/// Look a file up in the global cache, if there is one
+pub async fn cache_get(
+    file_unique_id: &str,
+    variant: Option<&str>,
+    file_name: &str,
+) -> Option<FileData> {
+    match &*MEDIA_CACHE {
+        Some(cache) => cache.get(file_unique_id, variant, file_name).await,
+        None => None,
+    }
+}
+
+/// Store a file in the global cache if there is one. Failures are only logged, the cache is best effort.
+pub async fn cache_put(file_unique_id: &str, variant: Option<&str>, data: &FileData) {
+    if let Some(cache) = &*MEDIA_CACHE {
+        if let Err(err) = cache.put(file_unique_id, variant, data).await {
+            warn!("Failed to cache {}: {}", file_unique_id, err);
+        }
+    }
+}
+
+/// Handle `cache stats` and `cache prune` from the command line
+pub fn run_cache_command(command: Option<&str>) -> MyResult<()> {
+    let cache = MEDIA_CACHE
+        .as_ref()
+        .ok_or_else(|| make_error("MEDIA_CACHE_DIR is not set, so there is no cache"))?;
+
+    match command {
+        Some("stats") => {
+            let stats = cache.stats()?;
+            println!("Entries:     {}", stats.entries);
+            println!(
+                "Total size:  {:.1} MB / {:.1} MB",
+                stats.total_bytes as f64 / 1024.0 / 1024.0,
+                cache.max_bytes as f64 / 1024.0 / 1024.0
+            );
+            if let Some(oldest) = stats.oldest.and_then(|time| time.elapsed().ok()) {
+                println!(
+                    "Oldest used: {:.1} days ago",
+                    oldest.as_secs_f64() / 86400.0
+                );
+            }
+            if let Some(newest) = stats.newest.and_then(|time| time.elapsed().ok()) {
+                println!(
+                    "Newest used: {:.1} days ago",
+                    newest.as_secs_f64() / 86400.0
+                );
+            }
+        }
+        Some("prune") => {
+            let removed = cache.prune()?;
+            println!("Removed {} cache entries", removed);
+        }
+        _ => return Err(make_error("Usage: tg_discord_mirror cache <stats|prune>")),
+    }
+    Ok(())
+}
+
+/// Where an entry is written before it's renamed into place, like `<entry>.<pid>-<n>.partial`
+fn partial_path(path: &Path) -> PathBuf {
+    let id = NEXT_PARTIAL_ID.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}-{}.partial", std::process::id(), id));
+    path.with_file_name(name)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pruned {
+    removed: usize,
+    /// Size of the entries left afterwards
+    remaining_bytes: u64,
+}
+
+fn prune_dir(dir: &Path, max_bytes: u64, max_age: Duration) -> io::Result<Pruned> {
+    let mut entries = read_entries(dir)?;
+    // Oldest first
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let mut total_bytes: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut removed = 0;
+
+    for entry in entries {
+        let expired = entry
+            .last_used
+            .elapsed()
+            .map(|age| age > max_age)
+            .unwrap_or(false);
+
+        if !expired && total_bytes <= max_bytes {
+            // Everything after this is newer, so it's all staying
+            break;
+        }
+
+        match fs::remove_file(&entry.path) {
+            Ok(_) => {
+                total_bytes -= entry.size;
+                removed += 1;
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => total_bytes -= entry.size,
+            Err(err) => warn!("Failed to evict {}: {}", entry.path.display(), err),
+        }
+    }
+
+    if removed > 0 {
+        info!("Evicted {} entries from the media cache", removed);
+    }
+    Ok(Pruned {
+        removed,
+        remaining_bytes: total_bytes,
+    })
+}
+
+fn read_entries(dir: &Path) -> io::Result<Vec<CacheEntry>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = Vec::new();
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry?;
+        let metadata = dir_entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        // Files still being written aren't entries yet, but ones left behind by a crash are
+        // cleaned up like any other
+        let path = dir_entry.path();
+        let in_progress = path.extension().map_or(false, |ext| ext == "partial")
+            && metadata
+                .modified()?
+                .elapsed()
+                .map_or(true, |age| age < PARTIAL_GRACE);
+        if in_progress {
+            continue;
+        }
+        entries.push(CacheEntry {
+            path,
+            size: metadata.len(),
+            last_used: metadata.modified()?,
+        });
+    }
+    Ok(entries)
+}
+
+fn touch(path: &Path) -> io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cache(name: &str, max_bytes: u64) -> MediaCache {
+        let dir =
+            std::env::temp_dir().join(format!("media-cache-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        MediaCache::new(dir, max_bytes, Duration::from_secs(86400))
+    }
+
+    fn bytes(size: usize) -> FileData {
+        FileData::Memory(vec![0; size], None)
+    }
+
+    #[test]
+    fn partial_names_are_unique() {
+        let path = Path::new("/cache/AgADBAAD");
+        let (first, second) = (partial_path(path), partial_path(path));
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+        assert_eq!(first.extension().unwrap(), "partial");
+    }
+
+    #[tokio::test]
+    async fn pruning_skips_files_being_written() {
+        let cache = test_cache("partial", 10);
+        cache.put("a", None, &bytes(8)).await.unwrap();
+        let partial = partial_path(&cache.entry_path("b", None));
+        fs::write(&partial, vec![0; 100]).unwrap();
+
+        assert_eq!(cache.prune().unwrap(), 0);
+        assert!(partial.exists());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_a_running_total() {
+        let cache = test_cache("total", 100);
+        cache.put("a", None, &bytes(10)).await.unwrap();
+        cache.put("b", None, &bytes(20)).await.unwrap();
+        // Replacing an entry only counts the difference
+        cache.put("a", None, &bytes(15)).await.unwrap();
+
+        assert_eq!(cache.usage.lock().unwrap().total_bytes, Some(35));
+        assert_eq!(cache.stats().unwrap().total_bytes, 35);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_over_the_limit() {
+        let cache = test_cache("evict", 25);
+        cache.put("old", None, &bytes(10)).await.unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(cache.entry_path("old", None))
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+        cache.put("new", None, &bytes(20)).await.unwrap();
+
+        assert!(!cache.entry_path("old", None).exists());
+        assert!(cache.entry_path("new", None).exists());
+        assert_eq!(cache.usage.lock().unwrap().total_bytes, Some(20));
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::{env::var, net::SocketAddr};
+
+use axum::{
+    body::StreamBody,
+    extract::Path,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use log::{error, info, warn};
+
+use teloxide::types::ChatId;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    feeds::{atom_feed, rss_feed},
+    file_types::sniff_mime,
+    routes,
+    types::{FileData, MyResult},
+    FEED_MAX_ENTRIES, MEDIA_BASE_URL, MEDIA_CACHE,
+};
+
+/// Whether `spawn_media_server` has anything to serve on
+pub fn media_server_enabled() -> bool {
+    var("MEDIA_SERVER_LISTEN").is_ok()
+}
+
+/// Start serving the media cache and feeds over HTTP if `MEDIA_SERVER_LISTEN` is set.
+///
+/// Files are served at `/media/<file_unique_id>`, which is what sinks and feeds use when they link
+/// to media under `MEDIA_BASE_URL` instead of uploading it. Every source chat gets an Atom feed at
+/// `/feeds/<chat_id>/atom` and an RSS one at `/feeds/<chat_id>/rss`.
+pub fn spawn_media_server() -> MyResult<()> {
+    let listen_addr: SocketAddr = match var("MEDIA_SERVER_LISTEN") {
+        Ok(addr) => addr.parse()?,
+        Err(_) => return Ok(()),
+    };
+    if MEDIA_CACHE.is_none() {
+        warn!("MEDIA_SERVER_LISTEN is set but MEDIA_CACHE_DIR isn't, every request will 404");
+    }
+
+    let app = Router::new()
+        .route("/media/:file_unique_id", get(serve_media))
+        .route("/feeds/:chat_id/atom", get(serve_atom))
+        .route("/feeds/:chat_id/rss", get(serve_rss));
+    let server = axum::Server::try_bind(&listen_addr)?.serve(app.into_make_service());
+    info!("Serving cached media on {}", listen_addr);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("Media server failed: {}", err);
+        }
+    });
+    Ok(())
+}
+
+async fn serve_media(Path(file_unique_id): Path<String>) -> Result<Response, StatusCode> {
+    let cache = match &*MEDIA_CACHE {
+        Some(cache) => cache,
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+    let data = cache
+        .get(&file_unique_id, None, &file_unique_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let content_type = sniff_mime(&data).await;
+    match data {
+        FileData::Memory(bytes, _) => {
+            Ok(([(header::CONTENT_TYPE, content_type)], bytes).into_response())
+        }
+        // Spooled files are big, so stream them off the disk instead of reading them in first. The
+        // open handle keeps working after the spooled copy is cleaned up at the end of this.
+        FileData::Spooled(file) => {
+            let opened = tokio::fs::File::open(file.path())
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let length = opened
+                .metadata()
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .len();
+            Ok((
+                [
+                    (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+                    (header::CONTENT_LENGTH, HeaderValue::from(length)),
+                ],
+                StreamBody::new(ReaderStream::new(opened)),
+            )
+                .into_response())
+        }
+    }
+}
+
+async fn serve_atom(Path(chat_id): Path<i64>) -> Result<impl IntoResponse, StatusCode> {
+    let chat_id = tracked_chat(chat_id)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        atom_feed(chat_id, *FEED_MAX_ENTRIES as usize),
+    ))
+}
+
+async fn serve_rss(Path(chat_id): Path<i64>) -> Result<impl IntoResponse, StatusCode> {
+    let chat_id = tracked_chat(chat_id)?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        rss_feed(chat_id, *FEED_MAX_ENTRIES as usize),
+    ))
+}
+
+/// Only chats we mirror have feeds
+fn tracked_chat(chat_id: i64) -> Result<ChatId, StatusCode> {
+    let chat_id = ChatId(chat_id);
+    match routes::channel(chat_id) {
+        Some(_) => Ok(chat_id),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Public URL of a cached file, if `MEDIA_BASE_URL` says where the media server can be reached
+pub fn media_url(file_unique_id: &str) -> Option<String> {
+    MEDIA_BASE_URL
+        .as_ref()
+        .map(|base| format!("{}/media/{}", base.trim_end_matches('/'), file_unique_id))
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use teloxide::types::ChatId;
+use tokio::sync::Notify;
+
+use crate::{
+    file_types::sniff_mime,
+    sinks::{ReadyMessage, SentMessage},
+    types::MyResult,
+    utils::{save_state, spawn_state_writer, write_json_atomically},
+    MESSAGE_LOG_MAX_PER_CHAT, MESSAGE_LOG_PATH,
+};
+
+/// What we mirrored a telegram message as, so edits can be mirrored too and feeds can be built
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedMessage {
+    /// Unique IDs of the media on the telegram message, to tell text edits from media edits
+    pub media_ids: Vec<String>,
+    /// Sink name and the message it sent
+    pub sent: Vec<(String, SentMessage)>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub chat_title: Option<String>,
+    /// Public username of the chat, for linking back to the post
+    pub chat_username: Option<String>,
+    pub date: DateTime<Utc>,
+    /// When the post was last edited, or posted if it never was
+    pub updated: DateTime<Utc>,
+    /// The files that were actually mirrored, which are in the media cache under these IDs
+    pub media: Vec<LoggedMedia>,
+    /// Telegram won't let the post be forwarded or saved, so it stays out of public feeds
+    #[serde(default)]
+    pub protected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedMedia {
+    pub file_unique_id: String,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+}
+
+impl LoggedMessage {
+    pub async fn new(
+        message: &ReadyMessage,
+        media_ids: Vec<String>,
+        sent: Vec<(String, SentMessage)>,
+    ) -> Self {
+        let mut media = Vec::new();
+        for attachment in &message.attachments {
+            media.push(LoggedMedia {
+                file_unique_id: attachment.file_unique_id.clone(),
+                file_name: attachment.file_name.clone(),
+                mime_type: sniff_mime(&attachment.data).await.to_string(),
+                size: attachment.data.size().unwrap_or_default(),
+            });
+        }
+
+        Self {
+            media_ids,
+            sent,
+            text: message.message_text.clone(),
+            html: message.message_html.clone(),
+            chat_title: message.source.chat_title.clone(),
+            chat_username: message.source.chat_username.clone(),
+            date: message.source.date,
+            updated: message.source.date,
+            media,
+            protected: message.source.protected,
+        }
+    }
+}
+
+/// Messages per chat, by message ID so the newest are at the end
+type Log = HashMap<i64, BTreeMap<i32, LoggedMessage>>;
+
+lazy_static! {
+    static ref MESSAGE_LOG: Mutex<Log> = Mutex::new(HashMap::new());
+    /// Poked whenever the log changes, for the writer task
+    static ref SAVE_NEEDED: Notify = Notify::new();
+}
+
+/// Read the log back in from `MESSAGE_LOG_PATH`, if it's set and there is one, and start the task
+/// that saves it there as it changes
+pub fn load() -> MyResult<()> {
+    let path = match &*MESSAGE_LOG_PATH {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let writer_path = path.clone();
+    spawn_state_writer("message log", &SAVE_NEEDED, move || save(&writer_path));
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let log: Log = serde_json::from_slice(&std::fs::read(path)?)?;
+    info!(
+        "Loaded {} logged messages from {}",
+        log.values().map(BTreeMap::len).sum::<usize>(),
+        path.display()
+    );
+    *MESSAGE_LOG.lock().unwrap() = log;
+    Ok(())
+}
+
+/// Remember a message, dropping the oldest ones in the chat past `MESSAGE_LOG_MAX_PER_CHAT`
+pub fn record(chat_id: ChatId, message_id: i32, logged: LoggedMessage) {
+    let mut log = MESSAGE_LOG.lock().unwrap();
+    let ChatId(chat_id) = chat_id;
+
+    let chat = log.entry(chat_id).or_default();
+    chat.insert(message_id, logged);
+    while chat.len() > *MESSAGE_LOG_MAX_PER_CHAT as usize {
+        let oldest = *chat.keys().next().unwrap();
+        chat.remove(&oldest);
+    }
+    SAVE_NEEDED.notify_one();
+}
+
+/// Add what another sink sent to a message that's already logged, for posts that went out late
+pub fn add_sent(chat_id: ChatId, message_id: i32, sink_name: String, sent: SentMessage) {
+    let mut log = MESSAGE_LOG.lock().unwrap();
+    let ChatId(chat_id) = chat_id;
+
+    let logged = match log
+        .get_mut(&chat_id)
+        .and_then(|chat| chat.get_mut(&message_id))
+    {
+        Some(logged) => logged,
+        None => return,
+    };
+    logged.sent.push((sink_name, sent));
+    SAVE_NEEDED.notify_one();
+}
+
+pub fn lookup(chat_id: ChatId, message_id: i32) -> Option<LoggedMessage> {
+    let ChatId(chat_id) = chat_id;
+    MESSAGE_LOG
+        .lock()
+        .unwrap()
+        .get(&chat_id)?
+        .get(&message_id)
+        .cloned()
+}
+
+/// The newest `count` messages from a chat that can go in a public feed, newest first
+pub fn latest_public(chat_id: ChatId, count: usize) -> Vec<(i32, LoggedMessage)> {
+    let ChatId(chat_id) = chat_id;
+    MESSAGE_LOG
+        .lock()
+        .unwrap()
+        .get(&chat_id)
+        .map(|chat| {
+            chat.iter()
+                .rev()
+                .filter(|(_, logged)| !logged.protected)
+                .take(count)
+                .map(|(id, logged)| (*id, logged.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Save the log right away, for when the process is about to stop
+pub async fn flush() {
+    if let Some(path) = &*MESSAGE_LOG_PATH {
+        let path = path.clone();
+        save_state("message log", move || save(&path)).await;
+    }
+}
+
+fn save(path: &Path) -> MyResult<()> {
+    write_json_atomically(path, &*MESSAGE_LOG.lock().unwrap())
+}
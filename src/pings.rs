@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use crate::{formatting::hashtags, sinks::OutgoingMessage, types::BoxedError, utils::make_error};
+
+/// Who a route pings on Discord when a post mentions certain keywords or hashtags
+#[derive(Debug, Clone, Default)]
+pub struct PingRules {
+    rules: Vec<PingRule>,
+}
+
+#[derive(Debug, Clone)]
+struct PingRule {
+    trigger: Trigger,
+    mentions: Vec<Mention>,
+}
+
+#[derive(Debug, Clone)]
+enum Trigger {
+    /// Anywhere in the text, ignoring case
+    Keyword(String),
+    /// One of the post's hashtags, lowercase and without the `#`
+    Hashtag(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mention {
+    Role(u64),
+    User(u64),
+}
+
+impl Mention {
+    /// How the mention is written in message content
+    pub fn markup(self) -> String {
+        match self {
+            Mention::Role(id) => format!("<@&{}>", id),
+            Mention::User(id) => format!("<@{}>", id),
+        }
+    }
+}
+
+impl PingRules {
+    /// Everyone to ping for a message, each only once, in the order the rules list them
+    pub fn mentions_for(&self, message: &OutgoingMessage<'_>) -> Vec<Mention> {
+        let text = message.text.as_deref().unwrap_or_default();
+        let lowercase = text.to_lowercase();
+        let tags: Vec<String> = hashtags(text, message.entities)
+            .iter()
+            .map(|tag| tag.trim_start_matches('#').to_lowercase())
+            .collect();
+
+        let mut mentions = Vec::new();
+        for rule in &self.rules {
+            let triggered = match &rule.trigger {
+                Trigger::Keyword(keyword) => lowercase.contains(keyword),
+                Trigger::Hashtag(tag) => tags.contains(tag),
+            };
+            if triggered {
+                for mention in &rule.mentions {
+                    if !mentions.contains(mention) {
+                        mentions.push(*mention);
+                    }
+                }
+            }
+        }
+        mentions
+    }
+}
+
+impl FromStr for PingRules {
+    type Err = BoxedError;
+
+    /// Parses rules separated by `;`, each a keyword or `#hashtag`, then `=`, then who to ping as
+    /// `role:<id>` or `user:<id>` separated by commas. For example
+    /// `#urgent=role:123;outage=role:123,user:456`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+
+        for rule in s.split(';').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (trigger, mentions) = rule
+                .split_once('=')
+                .ok_or_else(|| make_error(&format!("Ping rule `{}` is missing a `=`", rule)))?;
+            let trigger = trigger.trim().to_lowercase();
+            let trigger = match trigger.strip_prefix('#').map(str::to_string) {
+                Some(tag) => Trigger::Hashtag(tag),
+                None => Trigger::Keyword(trigger),
+            };
+
+            let mentions = mentions
+                .split(',')
+                .map(|mention| -> Result<Mention, BoxedError> {
+                    match mention.trim().split_once(':') {
+                        Some(("role", id)) => Ok(Mention::Role(id.parse()?)),
+                        Some(("user", id)) => Ok(Mention::User(id.parse()?)),
+                        _ => Err(make_error(&format!(
+                            "Expected role:<id> or user:<id>, got `{}`",
+                            mention
+                        ))),
+                    }
+                })
+                .collect::<Result<Vec<_>, BoxedError>>()?;
+            rules.push(PingRule { trigger, mentions });
+        }
+        Ok(PingRules { rules })
+    }
+}
+
+/// Break up `@everyone` and `@here` so they show as text even where mentions are allowed
+pub fn neutralize_mass_mentions(text: &str) -> String {
+    text.replace("@everyone", "@\u{200B}everyone")
+        .replace("@here", "@\u{200B}here")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use teloxide::types::{ChatId, MessageEntity, MessageEntityKind};
+
+    use super::*;
+    use crate::{
+        buttons::MessageButtons,
+        types::{MessageKind, MessageSource},
+    };
+
+    fn mentions(rules: &str, text: &str, entities: &[MessageEntity]) -> Vec<Mention> {
+        let rules: PingRules = rules.parse().unwrap();
+        let buttons = MessageButtons::default();
+        let source = MessageSource {
+            chat_id: ChatId(-100),
+            chat_title: None,
+            chat_username: None,
+            message_id: 1,
+            date: Utc::now(),
+            kind: MessageKind::Text,
+            forwarded: false,
+            author_signature: None,
+            silent: false,
+            protected: false,
+            edited_at: None,
+        };
+        rules.mentions_for(&OutgoingMessage {
+            text: Some(text.to_string()),
+            html: None,
+            attachments: Vec::new(),
+            entities,
+            buttons: &buttons,
+            source: &source,
+        })
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules: PingRules = " #Urgent = role:1 ; outage=role:1, user:2 ;"
+            .parse()
+            .unwrap();
+        assert_eq!(rules.rules.len(), 2);
+        assert!(matches!(&rules.rules[0].trigger, Trigger::Hashtag(tag) if tag == "urgent"));
+        assert!(matches!(&rules.rules[1].trigger, Trigger::Keyword(word) if word == "outage"));
+        assert_eq!(
+            rules.rules[1].mentions,
+            [Mention::Role(1), Mention::User(2)]
+        );
+        assert!("".parse::<PingRules>().unwrap().rules.is_empty());
+
+        for bad in ["outage", "outage=", "outage=team:1", "outage=role:abc"] {
+            assert!(bad.parse::<PingRules>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn keywords_match_anywhere_ignoring_case() {
+        let rules = "outage=role:1";
+        assert_eq!(mentions(rules, "Big OUTAGE today", &[]), [Mention::Role(1)]);
+        assert!(mentions(rules, "All good", &[]).is_empty());
+    }
+
+    #[test]
+    fn hashtags_only_match_tags() {
+        let rules = "#urgent=user:2";
+        let tagged = [MessageEntity::new(MessageEntityKind::Hashtag, 4, 7)];
+        assert_eq!(mentions(rules, "Now #Urgent", &tagged), [Mention::User(2)]);
+        // The word alone isn't the hashtag
+        assert!(mentions(rules, "Not urgent", &[]).is_empty());
+    }
+
+    #[test]
+    fn each_mention_only_once_in_rule_order() {
+        let rules = "outage=role:1,user:2;down=user:2,role:3";
+        assert_eq!(
+            mentions(rules, "outage, everything is down", &[]),
+            [Mention::Role(1), Mention::User(2), Mention::Role(3)]
+        );
+        assert_eq!(Mention::Role(1).markup(), "<@&1>");
+        assert_eq!(Mention::User(2).markup(), "<@2>");
+    }
+
+    #[test]
+    fn mass_mentions_are_broken_up() {
+        let neutral = neutralize_mass_mentions("@everyone look, @here too");
+        assert!(!neutral.contains("@everyone"));
+        assert!(!neutral.contains("@here"));
+        assert_eq!(neutral.replace('\u{200B}', ""), "@everyone look, @here too");
+    }
+}
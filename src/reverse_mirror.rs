@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    env::var,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use serenity::{
+    client::{Client, Context, EventHandler},
+    model::{
+        application::interaction::Interaction,
+        channel::Message as DiscordMessage,
+        gateway::{GatewayIntents, Ready},
+        id::WebhookId,
+    },
+};
+use teloxide::{
+    prelude::*,
+    types::{InputFile, MessageEntity, MessageEntityKind},
+};
+
+use crate::{
+    discord_commands::{handle_interaction, register_commands},
+    formatting::discord_markdown_to_entities,
+    routes::{self, StoredRoute},
+    types::MyResult,
+    utils::{download_to_spool, make_error},
+    BOT, DISCORD_SUBSCRIBE_BOTH_WAYS, DISCORD_TO_TELEGRAM, SUBSCRIBE_SOURCES,
+};
+
+/// Telegram cuts message text off at this many UTF-16 code units
+const MAX_TEXT_LENGTH: usize = 4096;
+/// And media captions at this many
+const MAX_CAPTION_LENGTH: usize = 1024;
+/// Biggest file a bot can upload
+const MAX_UPLOAD_SIZE: u64 = 50 * 1024 * 1024;
+/// Photos over this have to go as documents instead
+const MAX_PHOTO_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Mirrors messages from Discord channels back into telegram chats, and answers slash commands
+struct DiscordHandler {
+    /// Our own bot user, filled in once the gateway is ready
+    bot_user_id: AtomicU64,
+}
+
+/// Connect to the Discord gateway if `DISCORD_BOT_TOKEN` is set and there's something for the
+/// bot to do, either mirroring the channels in `DISCORD_TO_TELEGRAM` or offering `/telegram` for
+/// the sources in `DISCORD_SUBSCRIBE_SOURCES`. Slash commands need `DISCORD_APPLICATION_ID` too.
+pub async fn spawn_discord_client() -> MyResult<()> {
+    let token = match var("DISCORD_BOT_TOKEN") {
+        Ok(token) => token,
+        Err(_) => return Ok(()),
+    };
+    if DISCORD_TO_TELEGRAM.is_empty() && SUBSCRIBE_SOURCES.is_empty() {
+        return Ok(());
+    }
+
+    // Slash commands come through without any intents, message content is privileged so it's
+    // only asked for when there are channels to mirror, or `/telegram` can add some
+    let mirrors = !DISCORD_TO_TELEGRAM.is_empty()
+        || (*DISCORD_SUBSCRIBE_BOTH_WAYS && !SUBSCRIBE_SOURCES.is_empty());
+    let intents = if mirrors {
+        GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT
+    } else {
+        GatewayIntents::empty()
+    };
+    let mut builder = Client::builder(&token, intents).event_handler(DiscordHandler {
+        bot_user_id: AtomicU64::new(0),
+    });
+    if let Ok(application_id) = var("DISCORD_APPLICATION_ID") {
+        builder = builder.application_id(application_id.parse()?);
+    }
+    let mut client = builder.await?;
+
+    tokio::spawn(async move {
+        if let Err(err) = client.start().await {
+            error!("Discord gateway connection failed: {}", err);
+        }
+    });
+    Ok(())
+}
+
+/// Parse `<discord channel id>=<telegram chat id>` pairs separated by commas
+pub fn parse_reverse_routes(routes: &str) -> MyResult<HashMap<u64, ChatId>> {
+    routes
+        .split(',')
+        .filter(|route| !route.trim().is_empty())
+        .map(|route| -> MyResult<(u64, ChatId)> {
+            let (channel, chat) = route.split_once('=').ok_or_else(|| {
+                make_error(&format!(
+                    "Route `{}` should be <discord channel id>=<telegram chat id>",
+                    route
+                ))
+            })?;
+            Ok((channel.trim().parse()?, ChatId(chat.trim().parse()?)))
+        })
+        .collect()
+}
+
+/// The chat a Discord channel is mirrored into, either configured in `DISCORD_TO_TELEGRAM` or
+/// subscribed both ways through `/telegram`
+fn reverse_route(
+    channel_id: u64,
+    configured: &HashMap<u64, ChatId>,
+    stored: &[StoredRoute],
+) -> Option<ChatId> {
+    configured.get(&channel_id).copied().or_else(|| {
+        stored
+            .iter()
+            .find(|route| {
+                route.both_ways && !route.paused && route.discord_channel_id == Some(channel_id)
+            })
+            .map(|route| ChatId(route.chat_id))
+    })
+}
+
+#[async_trait]
+impl EventHandler for DiscordHandler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        info!("Connected to the Discord gateway as {}", ready.user.name);
+        self.bot_user_id.store(ready.user.id.0, Ordering::Relaxed);
+
+        if let Err(err) = register_commands(&ctx).await {
+            error!("Failed to register slash commands: {}", err);
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        handle_interaction(&ctx, interaction).await;
+    }
+
+    async fn message(&self, _ctx: Context, msg: DiscordMessage) {
+        let chat_id =
+            match reverse_route(msg.channel_id.0, &DISCORD_TO_TELEGRAM, &routes::list(None)) {
+                Some(chat_id) => chat_id,
+                None => return,
+            };
+
+        // Never echo back what the bridge posted itself, or the two sides would loop forever
+        if msg.webhook_id.map_or(false, is_own_webhook)
+            || msg.author.id.0 == self.bot_user_id.load(Ordering::Relaxed)
+        {
+            return;
+        }
+
+        if let Err(err) = mirror_message(chat_id, &msg).await {
+            warn!(
+                "Failed to mirror Discord message {} to telegram: {}",
+                msg.id, err
+            );
+        }
+    }
+}
+
+/// Whether a webhook is one our sinks post through
+fn is_own_webhook(webhook_id: WebhookId) -> bool {
+    routes::channels()
+        .iter()
+        .flat_map(|channel| channel.destinations.iter())
+        .any(|destination| destination.sink.discord_webhook_id() == Some(webhook_id))
+}
+
+async fn mirror_message(chat_id: ChatId, msg: &DiscordMessage) -> MyResult<()> {
+    let bot = BOT
+        .get()
+        .ok_or_else(|| make_error("Failed to get ref to Bot"))?;
+
+    let author = msg
+        .member
+        .as_ref()
+        .and_then(|member| member.nick.clone())
+        .unwrap_or_else(|| msg.author.name.clone());
+    let mentions: Vec<(u64, &str)> = msg
+        .mentions
+        .iter()
+        .map(|user| (user.id.0, user.name.as_str()))
+        .collect();
+    let (text, entities) = with_author(&author, &resolve_mentions(&msg.content, &mentions));
+
+    let attachments: Vec<_> = msg
+        .attachments
+        .iter()
+        .filter(|attachment| {
+            let fits = attachment.size <= MAX_UPLOAD_SIZE;
+            if !fits {
+                warn!(
+                    "Skipping {}, it's too big for telegram",
+                    attachment.filename
+                );
+            }
+            fits
+        })
+        .collect();
+
+    // The text rides along as the first caption if it's short enough, otherwise it goes first
+    let caption_fits = text.encode_utf16().count() <= MAX_CAPTION_LENGTH;
+    let mut caption = None;
+    if attachments.is_empty() || !caption_fits {
+        let (text, entities) = truncate_entities(text, entities, MAX_TEXT_LENGTH);
+        bot.send_message(chat_id, text).entities(entities).await?;
+    } else {
+        caption = Some((text, entities));
+    }
+
+    // One at a time, each spooled file is gone again before the next is downloaded
+    for attachment in attachments {
+        let spooled =
+            match download_to_spool(&attachment.url, &attachment.filename, attachment.size).await {
+                Ok(spooled) => spooled,
+                Err(err) => {
+                    warn!("Failed to download {}: {}", attachment.filename, err);
+                    continue;
+                }
+            };
+        let file = InputFile::file(spooled.path().to_path_buf());
+        let is_photo = attachment
+            .content_type
+            .as_deref()
+            .map_or(false, |content_type| content_type.starts_with("image/"))
+            && attachment.size <= MAX_PHOTO_SIZE;
+
+        match (is_photo, caption.take()) {
+            (true, Some((text, entities))) => {
+                bot.send_photo(chat_id, file)
+                    .caption(text)
+                    .caption_entities(entities)
+                    .await?;
+            }
+            (true, None) => {
+                bot.send_photo(chat_id, file).await?;
+            }
+            (false, Some((text, entities))) => {
+                bot.send_document(chat_id, file)
+                    .caption(text)
+                    .caption_entities(entities)
+                    .await?;
+            }
+            (false, None) => {
+                bot.send_document(chat_id, file).await?;
+            }
+        }
+    }
+
+    // Every download failed, so the text never went out as a caption
+    if let Some((text, entities)) = caption {
+        bot.send_message(chat_id, text).entities(entities).await?;
+    }
+    Ok(())
+}
+
+/// Put the author's name in bold in front of the converted message
+fn with_author(author: &str, content: &str) -> (String, Vec<MessageEntity>) {
+    let (content, mut entities) = discord_markdown_to_entities(content);
+    let prefix = format!("{}:\n", author);
+    let shift = prefix.encode_utf16().count();
+
+    for entity in &mut entities {
+        entity.offset += shift;
+    }
+    entities.insert(
+        0,
+        MessageEntity::new(MessageEntityKind::Bold, 0, author.encode_utf16().count()),
+    );
+    (format!("{}{}", prefix, content), entities)
+}
+
+/// Cut text down to `max` UTF-16 code units, dropping or trimming entities that ran past the end
+fn truncate_entities(
+    text: String,
+    entities: Vec<MessageEntity>,
+    max: usize,
+) -> (String, Vec<MessageEntity>) {
+    if text.encode_utf16().count() <= max {
+        return (text, entities);
+    }
+
+    let mut truncated = String::new();
+    let mut length = 0;
+    for c in text.chars() {
+        if length + c.len_utf16() > max {
+            break;
+        }
+        truncated.push(c);
+        length += c.len_utf16();
+    }
+
+    let entities = entities
+        .into_iter()
+        .filter(|entity| entity.offset < length)
+        .map(|mut entity| {
+            entity.length = entity.length.min(length - entity.offset);
+            entity
+        })
+        .collect();
+    (truncated, entities)
+}
+
+/// Swap Discord's `<@id>`, `<#id>`, `<@&id>` and custom emoji tags for something readable.
+/// `users` are the ids and names of the users the message mentions.
+fn resolve_mentions(content: &str, users: &[(u64, &str)]) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let tag_end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let tag = &rest[start + 1..tag_end];
+
+        let replacement = if let Some(id) = tag
+            .strip_prefix("@!")
+            .or_else(|| tag.strip_prefix('@').filter(|id| !id.starts_with('&')))
+        {
+            users
+                .iter()
+                .find(|(user_id, _)| user_id.to_string() == id)
+                .map(|(_, name)| format!("@{}", name))
+        } else if tag.starts_with("@&") {
+            Some("@role".to_string())
+        } else if tag.starts_with('#') {
+            Some("#channel".to_string())
+        } else {
+            // Custom emoji look like `:name:id`, or `a:name:id` when animated
+            let emoji = tag.strip_prefix('a').unwrap_or(tag);
+            emoji
+                .strip_prefix(':')
+                .and_then(|emoji| emoji.split_once(':'))
+                .map(|(name, _)| format!(":{}:", name))
+        };
+
+        match replacement {
+            Some(replacement) => out.push_str(&replacement),
+            None => out.push_str(&rest[start..=tag_end]),
+        }
+        rest = &rest[tag_end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored_route(channel: u64, chat: i64, both_ways: bool, paused: bool) -> StoredRoute {
+        StoredRoute {
+            id: 1,
+            chat_id: chat,
+            webhook_url: "https://discord.com/api/webhooks/1/token".to_string(),
+            name: "bridge".to_string(),
+            discord_channel_id: Some(channel),
+            managed: true,
+            paused,
+            protected_content: Default::default(),
+            both_ways,
+        }
+    }
+
+    #[test]
+    fn parses_reverse_routes() {
+        let routes = parse_reverse_routes(" 123=-100456 ,789=-100999,").unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[&123], ChatId(-100456));
+        assert_eq!(routes[&789], ChatId(-100999));
+        assert!(parse_reverse_routes("").unwrap().is_empty());
+        assert!(parse_reverse_routes("123").is_err());
+        assert!(parse_reverse_routes("abc=-100456").is_err());
+    }
+
+    #[test]
+    fn finds_routes_added_at_runtime() {
+        let configured = parse_reverse_routes("1=-1001").unwrap();
+        let stored = vec![
+            stored_route(2, -1002, true, false),
+            stored_route(3, -1003, false, false),
+            stored_route(4, -1004, true, true),
+        ];
+
+        assert_eq!(reverse_route(1, &configured, &stored), Some(ChatId(-1001)));
+        assert_eq!(reverse_route(2, &configured, &stored), Some(ChatId(-1002)));
+        // One way only, or paused
+        assert_eq!(reverse_route(3, &configured, &stored), None);
+        assert_eq!(reverse_route(4, &configured, &stored), None);
+        assert_eq!(reverse_route(5, &configured, &stored), None);
+    }
+
+    #[test]
+    fn resolves_mentions() {
+        let users = [(42, "alice")];
+        assert_eq!(
+            resolve_mentions("hi <@42> and <@!42>, <@7>", &users),
+            "hi @alice and @alice, <@7>"
+        );
+        assert_eq!(
+            resolve_mentions("<@&5> in <#6> <:wave:123> <a:spin:456>", &users),
+            "@role in #channel :wave: :spin:"
+        );
+        assert_eq!(
+            resolve_mentions("1 < 2 <not a tag", &users),
+            "1 < 2 <not a tag"
+        );
+    }
+
+    #[test]
+    fn puts_the_author_in_bold_first() {
+        let (text, entities) = with_author("bob", "**hey** there");
+        assert_eq!(text, "bob:\nhey there");
+        assert_eq!(
+            entities,
+            vec![
+                MessageEntity::new(MessageEntityKind::Bold, 0, 3),
+                MessageEntity::new(MessageEntityKind::Bold, 5, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_entities_with_the_text() {
+        let entities = vec![
+            MessageEntity::new(MessageEntityKind::Bold, 0, 3),
+            MessageEntity::new(MessageEntityKind::Italic, 2, 4),
+            MessageEntity::new(MessageEntityKind::Code, 5, 2),
+        ];
+        let (text, entities) = truncate_entities("abcdefg".to_string(), entities, 4);
+        assert_eq!(text, "abcd");
+        assert_eq!(
+            entities,
+            vec![
+                MessageEntity::new(MessageEntityKind::Bold, 0, 3),
+                MessageEntity::new(MessageEntityKind::Italic, 2, 2),
+            ]
+        );
+
+        // Counted in UTF-16, and never splitting a surrogate pair
+        let (text, _) = truncate_entities("a😀b".to_string(), Vec::new(), 2);
+        assert_eq!(text, "a");
+
+        let (text, entities) = truncate_entities("short".to_string(), Vec::new(), 10);
+        assert_eq!((text.as_str(), entities.len()), ("short", 0));
+    }
+}
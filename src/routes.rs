@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serenity::model::id::ChannelId;
+use teloxide::types::ChatId;
+
+use crate::{
+    buttons::CallbackButtonPolicy,
+    pings::PingRules,
+    sinks::Sink,
+    types::{Destination, MyResult, ProtectedContentPolicy, TgChannelData, WebhookData},
+    utils::{make_error, make_webhook},
+    AVATAR_URL, CHANNEL_DATA_WEBHOOK, PHOTO_SIZE_POLICY, ROUTES_PATH, USERNAME,
+};
+
+/// A route added with `/mirror_add`, as it's kept on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRoute {
+    pub id: u32,
+    pub chat_id: i64,
+    pub webhook_url: String,
+    /// Name of the webhook on Discord, so routes can be told apart without showing the URL
+    pub name: String,
+    /// The Discord channel the webhook posts in
+    #[serde(default)]
+    pub discord_channel_id: Option<u64>,
+    /// Whether we created the webhook ourselves, and so should delete it with the route
+    #[serde(default)]
+    pub managed: bool,
+    pub paused: bool,
+    /// What to do with posts telegram won't let people forward
+    #[serde(default)]
+    pub protected_content: ProtectedContentPolicy,
+    /// Whether messages in the Discord channel are mirrored back into the chat too
+    #[serde(default)]
+    pub both_ways: bool,
+}
+
+/// A route added at runtime and the sink it posts through, if the webhook could be reached
+struct AddedRoute {
+    stored: StoredRoute,
+    sink: Option<Arc<dyn Sink>>,
+}
+
+lazy_static! {
+    static ref ADDED_ROUTES: Mutex<Vec<AddedRoute>> = Mutex::new(Vec::new());
+}
+
+/// Make the configured routes live, then add back the ones from `ROUTES_PATH`
+pub async fn init(channel_data: HashMap<ChatId, TgChannelData>) -> MyResult<()> {
+    let channel_data = channel_data
+        .into_iter()
+        .map(|(chat_id, channel)| (chat_id, Arc::new(channel)))
+        .collect();
+    CHANNEL_DATA_WEBHOOK
+        .set(RwLock::new(channel_data))
+        .map_err(|_| make_error("The routing table was already set up"))?;
+
+    if !ROUTES_PATH.exists() {
+        return Ok(());
+    }
+    let stored: Vec<StoredRoute> = serde_json::from_slice(&std::fs::read(&*ROUTES_PATH)?)?;
+    info!("Loaded {} added routes", stored.len());
+
+    let mut added = Vec::new();
+    for stored in stored {
+        let sink = match webhook_sink(&stored.webhook_url).await {
+            Ok((sink, _, _)) => Some(sink),
+            Err(err) => {
+                warn!("Route #{} can't reach its webhook: {}", stored.id, err);
+                None
+            }
+        };
+        if let (Some(sink), false) = (&sink, stored.paused) {
+            attach(
+                ChatId(stored.chat_id),
+                sink.clone(),
+                stored.protected_content,
+            );
+        }
+        added.push(AddedRoute { stored, sink });
+    }
+    *ADDED_ROUTES.lock().unwrap() = added;
+    Ok(())
+}
+
+/// Where posts from a chat currently go, if anywhere
+pub fn channel(chat_id: ChatId) -> Option<Arc<TgChannelData>> {
+    CHANNEL_DATA_WEBHOOK
+        .get()?
+        .read()
+        .unwrap()
+        .get(&chat_id)
+        .cloned()
+}
+
+/// Every chat that's currently mirrored
+pub fn channels() -> Vec<Arc<TgChannelData>> {
+    CHANNEL_DATA_WEBHOOK
+        .get()
+        .map(|channels| channels.read().unwrap().values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Whether the sink called `name` belongs to an added route that isn't live right now, but could
+/// be again. That's a paused route, or any route whose webhook couldn't be reached at startup,
+/// since there's no telling which sink that would have been.
+pub fn is_parked(name: &str) -> bool {
+    ADDED_ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|route| match &route.sink {
+            Some(sink) => route.stored.paused && sink.name() == name,
+            None => true,
+        })
+}
+
+/// Check the webhook works, then start mirroring `chat_id` to it
+pub async fn add(
+    chat_id: ChatId,
+    webhook_url: &str,
+    managed: bool,
+    protected_content: ProtectedContentPolicy,
+    both_ways: bool,
+) -> MyResult<StoredRoute> {
+    let (sink, name, discord_channel_id) = webhook_sink(webhook_url).await?;
+    if let Some(channel) = channel(chat_id) {
+        if channel
+            .destinations
+            .iter()
+            .any(|destination| destination.sink.name() == sink.name())
+        {
+            return Err(make_error("That webhook is already mirroring this chat"));
+        }
+    }
+
+    let mut routes = ADDED_ROUTES.lock().unwrap();
+    let ChatId(id) = chat_id;
+    let stored = StoredRoute {
+        id: routes
+            .iter()
+            .map(|route| route.stored.id)
+            .max()
+            .unwrap_or(0)
+            + 1,
+        chat_id: id,
+        webhook_url: webhook_url.to_string(),
+        name,
+        discord_channel_id,
+        managed,
+        paused: false,
+        protected_content,
+        both_ways,
+    };
+    attach(chat_id, sink.clone(), protected_content);
+    routes.push(AddedRoute {
+        stored: stored.clone(),
+        sink: Some(sink),
+    });
+    save(&routes);
+    Ok(stored)
+}
+
+/// Added routes, only the ones from `chat_id` if it's given
+pub fn list(chat_id: Option<ChatId>) -> Vec<StoredRoute> {
+    ADDED_ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|route| in_scope(&route.stored, chat_id))
+        .map(|route| route.stored.clone())
+        .collect()
+}
+
+/// Stop mirroring through an added route and forget it
+pub fn remove(id: u32, chat_id: Option<ChatId>) -> MyResult<StoredRoute> {
+    let mut routes = ADDED_ROUTES.lock().unwrap();
+    let index = find(&routes, id, chat_id)?;
+    let route = routes.remove(index);
+    if let Some(sink) = &route.sink {
+        detach(ChatId(route.stored.chat_id), sink);
+    }
+    save(&routes);
+    Ok(route.stored)
+}
+
+/// Pause or resume an added route, it stays saved either way
+pub fn set_paused(id: u32, chat_id: Option<ChatId>, paused: bool) -> MyResult<StoredRoute> {
+    let mut routes = ADDED_ROUTES.lock().unwrap();
+    let index = find(&routes, id, chat_id)?;
+    let route = &mut routes[index];
+    if route.stored.paused != paused {
+        route.stored.paused = paused;
+        if let Some(sink) = &route.sink {
+            if paused {
+                detach(ChatId(route.stored.chat_id), sink);
+            } else {
+                attach(
+                    ChatId(route.stored.chat_id),
+                    sink.clone(),
+                    route.stored.protected_content,
+                );
+            }
+        }
+    }
+    let stored = route.stored.clone();
+    save(&routes);
+    Ok(stored)
+}
+
+/// The sink an added route posts through, paused or not
+pub fn sink(id: u32, chat_id: Option<ChatId>) -> MyResult<Arc<dyn Sink>> {
+    let routes = ADDED_ROUTES.lock().unwrap();
+    let index = find(&routes, id, chat_id)?;
+    routes[index]
+        .sink
+        .clone()
+        .ok_or_else(|| make_error("That route's webhook couldn't be reached at startup"))
+}
+
+/// Fetch a webhook from Discord, which also checks the URL is real, and wrap it up as a sink.
+/// The webhook's name and channel come along with it.
+async fn webhook_sink(webhook_url: &str) -> MyResult<(Arc<dyn Sink>, String, Option<u64>)> {
+    let raw_webhook = make_webhook(webhook_url).await?;
+    let name = raw_webhook
+        .name
+        .clone()
+        .unwrap_or_else(|| raw_webhook.id.to_string());
+    let channel_id = raw_webhook.channel_id.map(|ChannelId(id)| id);
+    let sink: Arc<dyn Sink> = Arc::new(WebhookData {
+        raw_webhook,
+        webhook_username: USERNAME.clone(),
+        icon_url: AVATAR_URL.clone(),
+        callback_buttons: CallbackButtonPolicy::Text,
+        template: None,
+        pings: PingRules::default(),
+    });
+    Ok((sink, name, channel_id))
+}
+
+fn in_scope(route: &StoredRoute, chat_id: Option<ChatId>) -> bool {
+    chat_id.map_or(true, |ChatId(chat_id)| route.chat_id == chat_id)
+}
+
+fn find(routes: &[AddedRoute], id: u32, chat_id: Option<ChatId>) -> MyResult<usize> {
+    routes
+        .iter()
+        .position(|route| route.stored.id == id && in_scope(&route.stored, chat_id))
+        .ok_or_else(|| make_error(&format!("There's no route #{}", id)))
+}
+
+/// Add a sink to a chat in the live table, swapping in a new copy so in-flight posts aren't affected
+fn attach(chat_id: ChatId, sink: Arc<dyn Sink>, protected_content: ProtectedContentPolicy) {
+    let mut channels = CHANNEL_DATA_WEBHOOK.get().unwrap().write().unwrap();
+    let mut channel = channels
+        .get(&chat_id)
+        .map(|channel| TgChannelData::clone(channel))
+        .unwrap_or_else(|| TgChannelData {
+            destinations: Vec::new(),
+            chat_id,
+            photo_size: *PHOTO_SIZE_POLICY,
+        });
+    channel.destinations.push(Destination {
+        protected_content,
+        ..Destination::new(sink)
+    });
+    channels.insert(chat_id, Arc::new(channel));
+}
+
+/// Take a sink back out of a chat in the live table, dropping the chat if that was its last one
+fn detach(chat_id: ChatId, sink: &Arc<dyn Sink>) {
+    let mut channels = CHANNEL_DATA_WEBHOOK.get().unwrap().write().unwrap();
+    let mut channel = match channels.get(&chat_id) {
+        Some(channel) => TgChannelData::clone(channel),
+        None => return,
+    };
+    let name = sink.name();
+    channel
+        .destinations
+        .retain(|destination| destination.sink.name() != name);
+    if channel.destinations.is_empty() {
+        channels.remove(&chat_id);
+    } else {
+        channels.insert(chat_id, Arc::new(channel));
+    }
+}
+
+fn save(routes: &[AddedRoute]) {
+    let stored: Vec<&StoredRoute> = routes.iter().map(|route| &route.stored).collect();
+    if let Err(err) = write_routes(&ROUTES_PATH, &stored) {
+        warn!("Failed to save the added routes: {}", err);
+    }
+}
+
+/// Write next to the final path then rename over it, so a crash can't lose every route
+fn write_routes(path: &Path, routes: &[&StoredRoute]) -> MyResult<()> {
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, serde_json::to_vec_pretty(routes)?)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
@@ -0,0 +1,725 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, MessageEntity};
+use tokio::sync::Notify;
+
+use crate::{
+    buttons::MessageButtons,
+    media_cache::{cache_get, cache_put},
+    message_log, routes,
+    sinks::{send_to_destinations, ReadyMessage},
+    spool::{link_or_copy, SpooledFile},
+    types::{BoxedError, Destination, DownloadedAttachment, FileData, MessageSource, MyResult},
+    utils::{make_error, save_state, spawn_state_writer, write_json_atomically},
+    HELD_PATH, MEDIA_CACHE,
+};
+
+const EVERY_DAY: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// When a destination doesn't want posts, and what happens to the ones that come in meanwhile
+#[derive(Debug, Clone)]
+pub struct QuietHours {
+    windows: Vec<QuietWindow>,
+    timezone: Tz,
+    delivery: QuietDelivery,
+}
+
+/// Quiet from `start` to `end` on the given days. Windows that cross midnight belong to the day
+/// they start on, and ones that start and end at the same time last the whole day.
+#[derive(Debug, Clone)]
+struct QuietWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    days: Vec<Weekday>,
+}
+
+/// What to do with posts that came in during quiet hours once they're over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuietDelivery {
+    /// Send them one by one, as they would have gone out
+    Each,
+    /// Collect them into a digest
+    Digest,
+}
+
+impl QuietWindow {
+    fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.days.contains(&day) && self.start <= time && time < self.end
+        } else {
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+}
+
+impl QuietHours {
+    pub fn is_quiet(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        self.windows
+            .iter()
+            .any(|window| window.contains(local.weekday(), local.time()))
+    }
+
+    /// The first minute from `from` on that isn't quiet, or `from` itself if it isn't
+    fn next_open(&self, from: DateTime<Utc>) -> DateTime<Utc> {
+        let mut at = from;
+        // Going minute by minute keeps daylight saving time out of it. Quiet all week long never
+        // opens, so give up after a week and send anyway.
+        for _ in 0..=7 * 24 * 60 {
+            if !self.is_quiet(at) {
+                return at;
+            }
+            at = at + Duration::minutes(1);
+        }
+        at
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = BoxedError;
+
+    /// Parses rules separated by `;`. `quiet:<HH:MM>-<HH:MM>[@<days>]` adds a window, for the
+    /// given days or every day, and can be given more than once. Days are separated by commas and
+    /// can be ranges, like `mon-fri,sun`. `tz:<zone>` is the IANA time zone the times are in,
+    /// UTC by default. `deliver:each|digest` is what happens to held back posts, sent one by one
+    /// unless it says otherwise. For example
+    /// `tz:Europe/Berlin;quiet:22:00-07:00@mon-fri;quiet:00:00-00:00@sat,sun`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quiet = QuietHours {
+            windows: Vec::new(),
+            timezone: Tz::UTC,
+            delivery: QuietDelivery::Each,
+        };
+
+        for rule in s.split(';').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (name, value) = rule.split_once(':').ok_or_else(|| {
+                make_error(&format!("Quiet hours rule `{}` is missing a `:`", rule))
+            })?;
+            let value = value.trim();
+            match name.trim() {
+                "quiet" => quiet.windows.push(parse_window(value)?),
+                "tz" => quiet.timezone = value.parse().map_err(|err: String| make_error(&err))?,
+                "deliver" => {
+                    quiet.delivery = match value {
+                        "each" => QuietDelivery::Each,
+                        "digest" => QuietDelivery::Digest,
+                        _ => return Err(make_error(&format!("Unknown delivery `{}`", value))),
+                    }
+                }
+                _ => return Err(make_error(&format!("Unknown quiet hours rule `{}`", name))),
+            }
+        }
+        if quiet.windows.is_empty() {
+            return Err(make_error("Quiet hours need at least one `quiet:` window"));
+        }
+        Ok(quiet)
+    }
+}
+
+fn parse_window(value: &str) -> Result<QuietWindow, BoxedError> {
+    let (times, days) = match value.split_once('@') {
+        Some((times, days)) => (times, parse_days(days)?),
+        None => (value, EVERY_DAY.to_vec()),
+    };
+    let (start, end) = times
+        .split_once('-')
+        .ok_or_else(|| make_error(&format!("Expected <start>-<end>, got `{}`", times)))?;
+    Ok(QuietWindow {
+        start: NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+        end: NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+        days,
+    })
+}
+
+fn parse_days(days: &str) -> Result<Vec<Weekday>, BoxedError> {
+    let mut parsed = Vec::new();
+    for part in days.split(',').map(str::trim) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(part)?, parse_day(part)?),
+        };
+        let mut day = first;
+        loop {
+            parsed.push(day);
+            if day == last {
+                break;
+            }
+            day = day.succ();
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_day(day: &str) -> Result<Weekday, BoxedError> {
+    day.trim()
+        .parse()
+        .map_err(|_| make_error(&format!("Unknown day `{}`", day)))
+}
+
+/// Whether posts to the destination go into a digest right now because of its quiet hours
+pub fn holds_for_digest(destination: &Destination, now: DateTime<Utc>) -> bool {
+    destination.quiet.as_ref().map_or(false, |quiet| {
+        quiet.delivery == QuietDelivery::Digest && quiet.is_quiet(now)
+    })
+}
+
+pub fn is_quiet(destination: &Destination, now: DateTime<Utc>) -> bool {
+    destination
+        .quiet
+        .as_ref()
+        .map_or(false, |quiet| quiet.is_quiet(now))
+}
+
+/// When a post that came in `now` should go out to the destination, or None if that's now.
+///
+/// The delivery delay comes first, then if that lands in quiet hours, the post waits until
+/// they're over. Posts held for a digest don't wait here, the digest does that for them.
+pub fn send_at(destination: &Destination, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let after_delay = match destination.delay {
+        Some(delay) => now + Duration::from_std(delay).unwrap_or_else(|_| Duration::zero()),
+        None => now,
+    };
+    let at = match &destination.quiet {
+        Some(quiet) if quiet.delivery == QuietDelivery::Each => quiet.next_open(after_delay),
+        _ => after_delay,
+    };
+    Some(at).filter(|at| *at > now)
+}
+
+/// Cache variant held posts' media waits under until it goes out
+const HELD_VARIANT: &str = "held";
+/// And the originals of media that was shrunk, so they don't replace the smaller version
+const HELD_ORIGINAL_VARIANT: &str = "held-original";
+
+/// A post held back for one or more destinations, as it's kept on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HeldPost {
+    /// The latest version of it, so edits made while it waits go out too
+    text: Option<String>,
+    html: Option<String>,
+    entities: Vec<MessageEntity>,
+    buttons: MessageButtons,
+    source: MessageSource,
+    media: Vec<HeldMedia>,
+    /// Sink names of the destinations still waiting to send it, and when they should
+    waiting: Vec<(String, DateTime<Utc>)>,
+}
+
+/// An attachment of a held post. It waits in the media cache, or in the spool for this run only
+/// when there's no cache, so it isn't kept in memory either way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HeldMedia {
+    file_unique_id: String,
+    file_name: String,
+    /// The file as it was downloaded, when it was shrunk before being held
+    original: Option<Box<HeldMedia>>,
+    #[serde(skip)]
+    spooled: Option<Arc<SpooledFile>>,
+}
+
+impl HeldMedia {
+    async fn store(attachment: &DownloadedAttachment) -> MyResult<Self> {
+        let original = match &attachment.original {
+            Some(original) => Some(Box::new(
+                Self::store_file(original, HELD_ORIGINAL_VARIANT).await?,
+            )),
+            None => None,
+        };
+        Ok(HeldMedia {
+            original,
+            ..Self::store_file(attachment, HELD_VARIANT).await?
+        })
+    }
+
+    async fn store_file(attachment: &DownloadedAttachment, variant: &str) -> MyResult<Self> {
+        let mut media = HeldMedia {
+            file_unique_id: attachment.file_unique_id.clone(),
+            file_name: attachment.file_name.clone(),
+            original: None,
+            spooled: None,
+        };
+        if MEDIA_CACHE.is_some() {
+            cache_put(&attachment.file_unique_id, Some(variant), &attachment.data).await;
+        } else {
+            let spooled = SpooledFile::new(&attachment.file_name).await?;
+            match &attachment.data {
+                FileData::Memory(bytes, _) => tokio::fs::write(spooled.path(), bytes).await?,
+                FileData::Spooled(file) => link_or_copy(file.path(), spooled.path()).await?,
+            }
+            media.spooled = Some(Arc::new(spooled));
+        }
+        Ok(media)
+    }
+
+    /// Get the attachment back for sending, or None if it's gone from the cache or was only
+    /// spooled by an earlier run
+    async fn load(&self) -> Option<DownloadedAttachment> {
+        let original = match &self.original {
+            Some(original) => Self::load_file(original, HELD_ORIGINAL_VARIANT)
+                .await
+                .map(Box::new),
+            None => None,
+        };
+        Some(DownloadedAttachment {
+            original,
+            ..Self::load_file(self, HELD_VARIANT).await?
+        })
+    }
+
+    async fn load_file(media: &HeldMedia, variant: &str) -> Option<DownloadedAttachment> {
+        let data = match &media.spooled {
+            // Every send gets its own copy, the held one stays until the post is done with
+            Some(spooled) => {
+                let copy = SpooledFile::new(&media.file_name).await.ok()?;
+                link_or_copy(spooled.path(), copy.path()).await.ok()?;
+                FileData::Spooled(copy)
+            }
+            None => cache_get(&media.file_unique_id, Some(variant), &media.file_name).await?,
+        };
+        Some(DownloadedAttachment {
+            file_name: media.file_name.clone(),
+            file_unique_id: media.file_unique_id.clone(),
+            data,
+            original: None,
+        })
+    }
+}
+
+impl HeldPost {
+    async fn new(message: &ReadyMessage) -> Self {
+        let mut media = Vec::new();
+        for attachment in &message.attachments {
+            match HeldMedia::store(attachment).await {
+                Ok(held) => media.push(held),
+                Err(err) => warn!(
+                    "Failed to hold on to {}, it won't go out: {}",
+                    attachment.file_name, err
+                ),
+            }
+        }
+        HeldPost {
+            text: message.message_text.clone(),
+            html: message.message_html.clone(),
+            entities: message.entities.clone(),
+            buttons: message.buttons.clone(),
+            source: message.source.clone(),
+            media,
+            waiting: Vec::new(),
+        }
+    }
+
+    async fn ready_message(&self) -> ReadyMessage {
+        let mut attachments = Vec::new();
+        for media in &self.media {
+            match media.load().await {
+                Some(attachment) => attachments.push(attachment),
+                None => warn!(
+                    "{} of held message {} is gone, sending it without",
+                    media.file_name, self.source.message_id
+                ),
+            }
+        }
+        ReadyMessage {
+            message_text: self.text.clone(),
+            message_html: self.html.clone(),
+            attachments,
+            entities: self.entities.clone(),
+            buttons: self.buttons.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+/// Held posts by chat and message ID
+type HeldPosts = HashMap<(i64, i32), HeldPost>;
+
+lazy_static! {
+    /// Posts waiting out a delay or quiet hours, saved to `HELD_PATH` so a restart doesn't lose them
+    static ref HELD: Mutex<HeldPosts> = Mutex::new(HashMap::new());
+    /// Poked whenever a post is held, sent or edited, for the writer task
+    static ref SAVE_NEEDED: Notify = Notify::new();
+}
+
+fn held_key(source: &MessageSource) -> (i64, i32) {
+    let ChatId(chat_id) = source.chat_id;
+    (chat_id, source.message_id)
+}
+
+/// Read the held posts back in from `HELD_PATH` and start waiting for them again
+pub fn load() -> MyResult<()> {
+    spawn_state_writer("held back posts", &SAVE_NEEDED, save);
+    if !HELD_PATH.exists() {
+        return Ok(());
+    }
+
+    let posts: Vec<HeldPost> = serde_json::from_slice(&std::fs::read(&*HELD_PATH)?)?;
+    info!("Loaded {} held back posts", posts.len());
+    if MEDIA_CACHE.is_none() && posts.iter().any(|post| !post.media.is_empty()) {
+        warn!("Held posts lost their media on restart, set MEDIA_CACHE_DIR to keep it");
+    }
+
+    let mut held = HELD.lock().unwrap();
+    for post in posts {
+        let key = held_key(&post.source);
+        for (sink_name, at) in &post.waiting {
+            spawn_send(key, sink_name.clone(), *at);
+        }
+        held.insert(key, post);
+    }
+    Ok(())
+}
+
+/// Send a post to a destination later on, noting what was sent in the message log once it is
+pub async fn hold(message: &ReadyMessage, destination: &Destination, at: DateTime<Utc>) {
+    let key = held_key(&message.source);
+    let sink_name = destination.sink.name();
+    // Only the first destination to hold a post has to put its media away
+    let mut post = None;
+    loop {
+        {
+            let mut held = HELD.lock().unwrap();
+            if let Some(entry) = held.get_mut(&key) {
+                entry.waiting.push((sink_name.clone(), at));
+                SAVE_NEEDED.notify_one();
+                break;
+            }
+            if let Some(mut post) = post.take() {
+                post.waiting.push((sink_name.clone(), at));
+                held.insert(key, post);
+                SAVE_NEEDED.notify_one();
+                break;
+            }
+        }
+        post = Some(HeldPost::new(message).await);
+    }
+    info!("Holding message back for {} until {}", sink_name, at);
+    spawn_send(key, sink_name, at);
+}
+
+fn spawn_send(key: (i64, i32), sink_name: String, at: DateTime<Utc>) {
+    tokio::spawn(async move {
+        if let Ok(wait) = (at - Utc::now()).to_std() {
+            tokio::time::sleep(wait).await;
+        }
+        send_held(key, &sink_name).await;
+    });
+}
+
+/// Send a held post to one of the destinations waiting for it
+async fn send_held(key: (i64, i32), sink_name: &str) {
+    let post = {
+        let mut held = HELD.lock().unwrap();
+        let entry = match held.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(index) = entry.waiting.iter().position(|(name, _)| name == sink_name) {
+            entry.waiting.remove(index);
+        }
+        let post = entry.clone();
+        if entry.waiting.is_empty() {
+            held.remove(&key);
+        }
+        SAVE_NEEDED.notify_one();
+        post
+    };
+
+    // Routes can change while a post waits, so go by what's there now
+    let destination = routes::channel(ChatId(key.0)).and_then(|channel| {
+        channel
+            .destinations
+            .iter()
+            .find(|destination| destination.sink.name() == sink_name)
+            .cloned()
+    });
+    let destination = match destination {
+        Some(destination) => destination,
+        None => {
+            warn!(
+                "{} is gone, dropping held message {} for it",
+                sink_name, key.1
+            );
+            return;
+        }
+    };
+
+    let message = post.ready_message().await;
+    let (sent, _) = send_to_destinations(&message, &[&destination]).await;
+    for (sink_name, sent) in sent {
+        message_log::add_sent(ChatId(key.0), key.1, sink_name, sent);
+    }
+}
+
+/// Whether a post is still waiting to go out somewhere
+pub fn is_held(chat_id: ChatId, message_id: i32) -> bool {
+    let ChatId(chat_id) = chat_id;
+    HELD.lock().unwrap().contains_key(&(chat_id, message_id))
+}
+
+/// Swap in the edited version of a post that's still waiting, if it is
+pub async fn update_held(message: &ReadyMessage) {
+    let key = held_key(&message.source);
+    if !HELD.lock().unwrap().contains_key(&key) {
+        return;
+    }
+    let edited = HeldPost::new(message).await;
+
+    let mut held = HELD.lock().unwrap();
+    if let Some(entry) = held.get_mut(&key) {
+        *entry = HeldPost {
+            waiting: std::mem::take(&mut entry.waiting),
+            ..edited
+        };
+        SAVE_NEEDED.notify_one();
+    }
+}
+
+/// Save the held posts right away, for when the process is about to stop
+pub async fn flush() {
+    save_state("held back posts", save).await;
+}
+
+fn save() -> MyResult<()> {
+    let held = HELD.lock().unwrap();
+    let posts: Vec<&HeldPost> = held.values().collect();
+    write_json_atomically(&HELD_PATH, &posts)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{sinks::archive::ArchiveSink, types::MessageKind};
+
+    use super::*;
+
+    /// 2024-01-01 was a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2024, 1, day).and_hms(hour, minute, 0)
+    }
+
+    fn quiet(rules: &str) -> QuietHours {
+        rules.parse().unwrap()
+    }
+
+    fn destination(quiet: Option<&str>, delay_minutes: Option<u64>) -> Destination {
+        Destination {
+            quiet: quiet.map(|rules| rules.parse().unwrap()),
+            delay: delay_minutes.map(|minutes| std::time::Duration::from_secs(minutes * 60)),
+            ..Destination::new(Arc::new(ArchiveSink::new(std::env::temp_dir())))
+        }
+    }
+
+    #[test]
+    fn parses_quiet_hours() {
+        let parsed = quiet(" tz:Europe/Berlin ; quiet:22:00-07:00@mon-fri; deliver:digest ;");
+        assert_eq!(parsed.timezone, Tz::Europe__Berlin);
+        assert_eq!(parsed.delivery, QuietDelivery::Digest);
+        assert_eq!(parsed.windows.len(), 1);
+        assert_eq!(parsed.windows[0].start, NaiveTime::from_hms(22, 0, 0));
+        assert_eq!(parsed.windows[0].end, NaiveTime::from_hms(7, 0, 0));
+
+        let defaults = quiet("quiet:12:00-13:00;quiet:00:00-00:00@sun");
+        assert_eq!(defaults.timezone, Tz::UTC);
+        assert_eq!(defaults.delivery, QuietDelivery::Each);
+        assert_eq!(defaults.windows[0].days, EVERY_DAY);
+
+        for bad in [
+            "",
+            "tz:UTC",
+            "quiet",
+            "quiet:22-07",
+            "quiet:22:00",
+            "quiet:22:00-07:00@funday",
+            "tz:Mars/Olympus_Mons;quiet:22:00-07:00",
+            "deliver:later;quiet:22:00-07:00",
+            "loud:22:00-07:00",
+        ] {
+            assert!(bad.parse::<QuietHours>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_days() {
+        use Weekday::*;
+        assert_eq!(parse_days("mon-fri").unwrap(), [Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(parse_days("sat, sun").unwrap(), [Sat, Sun]);
+        assert_eq!(parse_days("wed").unwrap(), [Wed]);
+        // Ranges can wrap around the end of the week
+        assert_eq!(parse_days("fri-mon").unwrap(), [Fri, Sat, Sun, Mon]);
+        assert!(parse_days("mon-someday").is_err());
+        assert!(parse_days("").is_err());
+    }
+
+    #[test]
+    fn windows_crossing_midnight_belong_to_their_first_day() {
+        let friday_nights = quiet("quiet:22:00-07:00@fri");
+        assert!(friday_nights.is_quiet(at(5, 23, 0)));
+        assert!(friday_nights.is_quiet(at(6, 6, 59)));
+        assert!(!friday_nights.is_quiet(at(6, 7, 0)));
+        assert!(!friday_nights.is_quiet(at(5, 21, 59)));
+        // Neither the night before nor the one after
+        assert!(!friday_nights.is_quiet(at(4, 23, 0)));
+        assert!(!friday_nights.is_quiet(at(5, 6, 0)));
+        assert!(!friday_nights.is_quiet(at(6, 23, 0)));
+    }
+
+    #[test]
+    fn same_start_and_end_is_the_whole_day() {
+        let saturdays = quiet("quiet:00:00-00:00@sat");
+        assert!(saturdays.is_quiet(at(6, 0, 0)));
+        assert!(saturdays.is_quiet(at(6, 23, 59)));
+        assert!(!saturdays.is_quiet(at(7, 0, 0)));
+        assert!(!saturdays.is_quiet(at(5, 23, 59)));
+    }
+
+    #[test]
+    fn times_are_in_the_configured_zone() {
+        let berlin_nights = quiet("tz:Europe/Berlin;quiet:22:00-07:00");
+        // UTC+1 in winter
+        assert!(berlin_nights.is_quiet(at(1, 21, 30)));
+        assert!(!berlin_nights.is_quiet(at(1, 20, 30)));
+        assert!(!berlin_nights.is_quiet(at(2, 6, 30)));
+        // UTC+2 in summer
+        assert!(berlin_nights.is_quiet(Utc.ymd(2024, 7, 1).and_hms(20, 30, 0)));
+        assert!(!berlin_nights.is_quiet(Utc.ymd(2024, 7, 1).and_hms(5, 30, 0)));
+    }
+
+    #[test]
+    fn next_open_waits_for_the_window_to_end() {
+        let nights = quiet("quiet:22:00-07:00");
+        assert_eq!(nights.next_open(at(1, 23, 0)), at(2, 7, 0));
+        assert_eq!(nights.next_open(at(1, 12, 0)), at(1, 12, 0));
+
+        // Quiet all week long gives up after a week
+        let always = quiet("quiet:00:00-00:00");
+        assert!(always.next_open(at(1, 12, 0)) > at(8, 12, 0));
+    }
+
+    #[test]
+    fn send_at_adds_the_delay_then_waits_out_quiet_hours() {
+        let now = at(1, 21, 55);
+        assert_eq!(send_at(&destination(None, None), now), None);
+        assert_eq!(
+            send_at(&destination(None, Some(10)), now),
+            Some(at(1, 22, 5))
+        );
+        assert_eq!(
+            send_at(&destination(Some("quiet:22:00-07:00"), None), now),
+            None
+        );
+        // The delay lands in quiet hours, so it waits for the morning
+        assert_eq!(
+            send_at(&destination(Some("quiet:22:00-07:00"), Some(10)), now),
+            Some(at(2, 7, 0))
+        );
+        assert_eq!(
+            send_at(&destination(Some("quiet:21:00-07:00"), None), now),
+            Some(at(2, 7, 0))
+        );
+    }
+
+    #[test]
+    fn digest_quiet_hours_leave_it_to_the_digest() {
+        let digest = destination(Some("quiet:21:00-07:00;deliver:digest"), None);
+        assert_eq!(send_at(&digest, at(1, 21, 55)), None);
+        assert!(holds_for_digest(&digest, at(1, 21, 55)));
+        assert!(!holds_for_digest(&digest, at(1, 12, 0)));
+        assert!(!holds_for_digest(
+            &destination(Some("quiet:21:00-07:00"), None),
+            at(1, 21, 55)
+        ));
+    }
+
+    fn attachment(file_name: &str, bytes: &[u8]) -> DownloadedAttachment {
+        DownloadedAttachment {
+            file_name: file_name.to_string(),
+            file_unique_id: format!("unique-{}", file_name),
+            data: FileData::Memory(bytes.to_vec(), None),
+            original: None,
+        }
+    }
+
+    fn message(attachments: Vec<DownloadedAttachment>) -> ReadyMessage {
+        ReadyMessage {
+            message_text: Some("Later".to_string()),
+            message_html: None,
+            attachments,
+            entities: Vec::new(),
+            buttons: MessageButtons::default(),
+            source: MessageSource {
+                chat_id: ChatId(-100),
+                chat_title: Some("Test channel".to_string()),
+                chat_username: None,
+                message_id: 7,
+                date: Utc::now(),
+                kind: MessageKind::Photo,
+                forwarded: false,
+                author_signature: None,
+                silent: false,
+                protected: false,
+                edited_at: None,
+            },
+        }
+    }
+
+    fn read(attachment: &DownloadedAttachment) -> Vec<u8> {
+        match &attachment.data {
+            FileData::Memory(bytes, _) => bytes.clone(),
+            FileData::Spooled(file) => std::fs::read(file.path()).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn held_media_is_spooled_without_a_cache() {
+        let mut shrunk = attachment("photo.jpg", b"small");
+        shrunk.original = Some(Box::new(attachment("photo.png", b"large")));
+        let post = HeldPost::new(&message(vec![shrunk])).await;
+
+        // Each send gets its own copy, so loading twice works
+        for _ in 0..2 {
+            let message = post.ready_message().await;
+            assert_eq!(message.message_text.as_deref(), Some("Later"));
+            assert_eq!(message.attachments.len(), 1);
+            let loaded = &message.attachments[0];
+            assert!(matches!(loaded.data, FileData::Spooled(_)));
+            assert_eq!(read(loaded), b"small");
+            assert_eq!(read(loaded.original.as_ref().unwrap()), b"large");
+        }
+    }
+
+    #[tokio::test]
+    async fn held_posts_are_saved_by_ids() {
+        let mut post =
+            HeldPost::new(&message(vec![attachment("photo.jpg", b"secret bytes")])).await;
+        post.waiting.push(("discord".to_string(), Utc::now()));
+
+        let json = serde_json::to_string(&post).unwrap();
+        assert!(json.contains("unique-photo.jpg"));
+        assert!(!json.contains("secret bytes"));
+
+        let loaded: HeldPost = serde_json::from_str(&json).unwrap();
+        assert_eq!(held_key(&loaded.source), (-100, 7));
+        assert_eq!(loaded.waiting.len(), 1);
+        // A spooled file doesn't outlive the run, so a reloaded post has to find it in the cache
+        assert!(loaded.media[0].spooled.is_none());
+        assert!(loaded.ready_message().await.attachments.is_empty());
+    }
+}
@@ -0,0 +1,531 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serenity::model::id::WebhookId;
+use teloxide::types::{ChatId, MessageEntity};
+
+use crate::{
+    buttons::MessageButtons,
+    digest::{self, digest_text, DigestEntry},
+    schedule::holds_for_digest,
+    types::{
+        Destination, DownloadedAttachment, MessageKind, MessageSource, MyResult, OversizePolicy,
+        ProtectedContentPolicy, UnifiedMessage,
+    },
+    utils::make_error,
+    OVERSIZE_POLICY,
+};
+
+pub mod archive;
+pub mod discord;
+pub mod json_webhook;
+pub mod matrix;
+pub mod slack;
+
+/// Somewhere mirrored messages can be sent to
+#[async_trait]
+pub trait Sink: Debug + Send + Sync {
+    /// Stable name for this destination, used to find the messages it sent later on
+    fn name(&self) -> String;
+
+    fn capabilities(&self) -> SinkCapabilities;
+
+    async fn send(&self, message: &OutgoingMessage<'_>) -> MyResult<SentMessage>;
+
+    /// Replace the text and buttons of a message sent earlier. Attachments are only replaced
+    /// if `capabilities().edit_attachments` is set.
+    async fn edit(&self, sent: &SentMessage, message: &OutgoingMessage<'_>) -> MyResult<()>;
+
+    async fn delete(&self, sent: &SentMessage) -> MyResult<()>;
+
+    /// Send posts that were held back as one message. By default that's a plain text list.
+    async fn send_digest(&self, entries: &[DigestEntry]) -> MyResult<()> {
+        let first = entries
+            .first()
+            .ok_or_else(|| make_error("Can't send an empty digest"))?;
+        let source = MessageSource {
+            chat_id: ChatId(first.chat_id),
+            chat_title: first.chat_title.clone(),
+            chat_username: None,
+            message_id: first.message_id,
+            date: first.date,
+            kind: MessageKind::Text,
+            forwarded: false,
+            author_signature: None,
+            silent: false,
+            protected: false,
+            edited_at: None,
+        };
+        let buttons = MessageButtons::default();
+        self.send(&OutgoingMessage {
+            text: Some(digest_text(entries)),
+            html: None,
+            attachments: Vec::new(),
+            entities: &[],
+            buttons: &buttons,
+            source: &source,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Whether the sink has anything to show for a message, since some drop parts of it
+    fn can_render(&self, message: &OutgoingMessage<'_>) -> bool {
+        message.text.is_some() || !message.attachments.is_empty() || !message.buttons.is_empty()
+    }
+
+    /// The Discord webhook this sink posts through, so what it posts is never mirrored back
+    fn discord_webhook_id(&self) -> Option<WebhookId> {
+        None
+    }
+}
+
+/// What a sink can take, so the pipeline can fit messages to it
+#[derive(Debug, Clone, Copy)]
+pub struct SinkCapabilities {
+    /// Biggest single attachment in bytes
+    pub max_file_size: u64,
+    pub max_attachments: usize,
+    /// Whether `edit` can swap out attachments, rather than just the text
+    pub edit_attachments: bool,
+}
+
+/// A message a sink sent, enough to edit or delete it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub id: String,
+    /// Any further messages it took to get everything across, deleted along with it
+    pub extra_ids: Vec<String>,
+}
+
+/// A message fitted to one sink's limits
+#[derive(Debug)]
+pub struct OutgoingMessage<'a> {
+    pub text: Option<String>,
+    pub html: Option<&'a str>,
+    pub attachments: Vec<&'a DownloadedAttachment>,
+    pub entities: &'a [MessageEntity],
+    pub buttons: &'a MessageButtons,
+    pub source: &'a MessageSource,
+}
+
+/// A message with all of its attachments downloaded, ready to go out to sinks
+#[derive(Debug)]
+pub struct ReadyMessage {
+    pub message_text: Option<String>,
+    pub message_html: Option<String>,
+    pub attachments: Vec<DownloadedAttachment>,
+    pub entities: Vec<MessageEntity>,
+    pub buttons: MessageButtons,
+    pub source: MessageSource,
+}
+
+impl ReadyMessage {
+    /// Wait for every download to finish, dropping the ones that failed
+    pub async fn from_unified(message: UnifiedMessage) -> Self {
+        // Create the vec of pending downloads
+        let pending_downloads = message
+            .attachments
+            .into_iter()
+            .map(|attachment| attachment.download());
+
+        // wait for all to finish and discard the mistakes
+        let attachments = future::join_all(pending_downloads)
+            .await
+            .into_iter()
+            .filter_map(|attachment| match attachment {
+                Ok(attachment) => Some(attachment),
+                Err(_) => {
+                    warn!("Failed to download attachment");
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            message_text: message.message_text,
+            message_html: message.message_html,
+            attachments,
+            entities: message.entities,
+            buttons: message.buttons,
+            source: message.source,
+        }
+    }
+
+    /// The text and entities filters judge the message by
+    pub fn filter_text(&self) -> Option<(&str, &[MessageEntity])> {
+        Some((
+            self.message_text.as_deref().unwrap_or_default(),
+            &self.entities,
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.message_text.is_none() && self.attachments.is_empty() && self.buttons.is_empty()
+    }
+
+    /// Fit the message to a destination, or None if its protected content policy keeps the
+    /// message from it or the sink would have nothing left to show
+    pub fn outgoing_to(&self, destination: &Destination) -> Option<OutgoingMessage<'_>> {
+        let mut outgoing = self.outgoing_for(&destination.sink.capabilities());
+        if self.source.protected {
+            match destination.protected_content {
+                ProtectedContentPolicy::Mirror => {}
+                ProtectedContentPolicy::TextOnly => outgoing.attachments.clear(),
+                ProtectedContentPolicy::Skip => return None,
+            }
+        }
+        Some(outgoing).filter(|outgoing| destination.sink.can_render(outgoing))
+    }
+
+    /// Fit the message to a sink, dropping attachments it can't take
+    pub fn outgoing_for(&self, capabilities: &SinkCapabilities) -> OutgoingMessage<'_> {
+        let mut attachments: Vec<&DownloadedAttachment> = self
+            .attachments
+            .iter()
+            .filter(|attachment| fits_upload_limit(attachment, capabilities.max_file_size))
+            .collect();
+
+        if attachments.len() > capabilities.max_attachments {
+            warn!(
+                "Message has {} attachments but the sink only takes {}, dropping the rest",
+                attachments.len(),
+                capabilities.max_attachments
+            );
+            attachments.truncate(capabilities.max_attachments);
+        }
+
+        OutgoingMessage {
+            text: self.message_text.clone(),
+            html: self.message_html.as_deref(),
+            attachments,
+            entities: &self.entities,
+            buttons: &self.buttons,
+            source: &self.source,
+        }
+    }
+}
+
+/// Send a message to every destination, returning what each one's sink sent.
+///
+/// A failing sink doesn't stop the others, but the first error is still returned at the end.
+pub async fn send_to_destinations(
+    message: &ReadyMessage,
+    destinations: &[&Destination],
+) -> (Vec<(String, SentMessage)>, MyResult<()>) {
+    let mut sent = Vec::new();
+    let mut result = Ok(());
+
+    if message.is_empty() {
+        warn!("No message text or attachments to send, didn't send anything");
+        return (sent, result);
+    }
+
+    for destination in destinations {
+        let sink = &destination.sink;
+        let outgoing = match message.outgoing_to(destination) {
+            Some(outgoing) => outgoing,
+            None => {
+                debug!("Nothing in the message for {}, skipping it", sink.name());
+                continue;
+            }
+        };
+        if destination.digest.is_some() || holds_for_digest(destination, Utc::now()) {
+            digest::add(destination, &outgoing).await;
+            continue;
+        }
+        match sink.send(&outgoing).await {
+            Ok(sent_message) => {
+                info!("Sent message to {}", sink.name());
+                sent.push((sink.name(), sent_message));
+            }
+            Err(err) => {
+                warn!("Failed to send message to {}: {}", sink.name(), err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+    }
+
+    (sent, result)
+}
+
+/// Check an attachment against the upload limit, applying the oversize policy if it's too big
+fn fits_upload_limit(attachment: &DownloadedAttachment, max_file_size: u64) -> bool {
+    let size = match attachment.data.size() {
+        Ok(size) => size,
+        Err(_) => return true,
+    };
+
+    if size <= max_file_size {
+        return true;
+    }
+
+    match *OVERSIZE_POLICY {
+        OversizePolicy::Send => {
+            warn!(
+                "Attachment {} is {} bytes, over the limit, sending it anyway",
+                attachment.file_name, size
+            );
+            true
+        }
+        OversizePolicy::Skip => {
+            warn!(
+                "Attachment {} is {} bytes, over the limit, skipping it",
+                attachment.file_name, size
+            );
+            false
+        }
+    }
+}
+
+/// Cut text down to at most `max_chars` characters, marking where it was cut
+pub fn truncate_text(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::types::FileData;
+
+    /// Keeps whatever it's sent in memory, or fails every send if told to
+    #[derive(Debug)]
+    struct RecordingSink {
+        name: &'static str,
+        capabilities: SinkCapabilities,
+        fail: bool,
+        sent: Mutex<Vec<(Option<String>, Vec<String>)>>,
+    }
+
+    impl RecordingSink {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                capabilities: SinkCapabilities {
+                    max_file_size: 100,
+                    max_attachments: 2,
+                    edit_attachments: false,
+                },
+                fail: false,
+                sent: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn failing(name: &'static str) -> Self {
+            Self {
+                fail: true,
+                ..Self::new(name)
+            }
+        }
+
+        fn sent(&self) -> Vec<(Option<String>, Vec<String>)> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Sink for RecordingSink {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn capabilities(&self) -> SinkCapabilities {
+            self.capabilities
+        }
+
+        async fn send(&self, message: &OutgoingMessage<'_>) -> MyResult<SentMessage> {
+            if self.fail {
+                return Err(make_error("Refusing to send"));
+            }
+            let mut sent = self.sent.lock().unwrap();
+            sent.push((
+                message.text.clone(),
+                message
+                    .attachments
+                    .iter()
+                    .map(|attachment| attachment.file_name.clone())
+                    .collect(),
+            ));
+            Ok(SentMessage {
+                id: sent.len().to_string(),
+                extra_ids: Vec::new(),
+            })
+        }
+
+        async fn edit(&self, _sent: &SentMessage, _message: &OutgoingMessage<'_>) -> MyResult<()> {
+            Ok(())
+        }
+
+        async fn delete(&self, _sent: &SentMessage) -> MyResult<()> {
+            Ok(())
+        }
+    }
+
+    fn attachment(file_name: &str, size: usize) -> DownloadedAttachment {
+        DownloadedAttachment {
+            file_name: file_name.to_string(),
+            file_unique_id: file_name.to_string(),
+            data: FileData::Memory(vec![0; size], None),
+            original: None,
+        }
+    }
+
+    fn message(text: Option<&str>, attachments: Vec<DownloadedAttachment>) -> ReadyMessage {
+        ReadyMessage {
+            message_text: text.map(str::to_string),
+            message_html: None,
+            attachments,
+            entities: Vec::new(),
+            buttons: MessageButtons::default(),
+            source: MessageSource {
+                chat_id: ChatId(-100),
+                chat_title: Some("Test channel".to_string()),
+                chat_username: None,
+                message_id: 1,
+                date: Utc::now(),
+                kind: MessageKind::Text,
+                forwarded: false,
+                author_signature: None,
+                silent: false,
+                protected: false,
+                edited_at: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_to_every_destination() {
+        let (first, second) = (
+            Arc::new(RecordingSink::new("first")),
+            Arc::new(RecordingSink::new("second")),
+        );
+        let destinations = [
+            Destination::new(first.clone()),
+            Destination::new(second.clone()),
+        ];
+
+        let (sent, result) = send_to_destinations(
+            &message(Some("hello"), Vec::new()),
+            &destinations.iter().collect::<Vec<_>>(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        let names: Vec<&str> = sent.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(first.sent(), [(Some("hello".to_string()), Vec::new())]);
+        assert_eq!(second.sent(), first.sent());
+    }
+
+    #[tokio::test]
+    async fn a_failing_sink_doesnt_stop_the_others() {
+        let broken = Arc::new(RecordingSink::failing("broken"));
+        let working = Arc::new(RecordingSink::new("working"));
+        let destinations = [Destination::new(broken), Destination::new(working.clone())];
+
+        let (sent, result) = send_to_destinations(
+            &message(Some("hello"), Vec::new()),
+            &destinations.iter().collect::<Vec<_>>(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "working");
+        assert_eq!(working.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_messages_go_nowhere() {
+        let sink = Arc::new(RecordingSink::new("sink"));
+        let destinations = [Destination::new(sink.clone())];
+
+        let (sent, result) =
+            send_to_destinations(&message(None, Vec::new()), &[&destinations[0]]).await;
+
+        assert!(result.is_ok());
+        assert!(sent.is_empty());
+        assert!(sink.sent().is_empty());
+    }
+
+    #[test]
+    fn fits_attachments_to_the_sink() {
+        let ready = message(
+            Some("hello"),
+            vec![
+                attachment("a.jpg", 10),
+                attachment("too-big.mp4", 1000),
+                attachment("b.jpg", 10),
+                attachment("c.jpg", 10),
+            ],
+        );
+        let capabilities = RecordingSink::new("sink").capabilities;
+
+        let outgoing = ready.outgoing_for(&capabilities);
+        let names: Vec<&str> = outgoing
+            .attachments
+            .iter()
+            .map(|attachment| attachment.file_name.as_str())
+            .collect();
+        // Oversized ones are skipped by default, then the rest are cut to the sink's limit
+        assert_eq!(names, ["a.jpg", "b.jpg"]);
+        assert_eq!(outgoing.text.as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn nothing_left_to_show_means_nothing_to_send() {
+        let ready = message(None, vec![attachment("too-big.mp4", 1000)]);
+        let destination = Destination::new(Arc::new(RecordingSink::new("sink")));
+        assert!(ready.outgoing_to(&destination).is_none());
+    }
+
+    #[test]
+    fn protected_posts_follow_the_destination_policy() {
+        let mut ready = message(Some("hello"), vec![attachment("a.jpg", 10)]);
+        ready.source.protected = true;
+        let mut destination = Destination::new(Arc::new(RecordingSink::new("sink")));
+        assert_eq!(
+            ready.outgoing_to(&destination).unwrap().attachments.len(),
+            1
+        );
+
+        destination.protected_content = ProtectedContentPolicy::Skip;
+        assert!(ready.outgoing_to(&destination).is_none());
+
+        destination.protected_content = ProtectedContentPolicy::TextOnly;
+        let outgoing = ready.outgoing_to(&destination).unwrap();
+        assert!(outgoing.attachments.is_empty());
+        assert_eq!(outgoing.text.as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn only_mirroring_keeps_protected_media() {
+        assert!(ProtectedContentPolicy::Mirror.keeps_media(true));
+        assert!(!ProtectedContentPolicy::TextOnly.keeps_media(true));
+        assert!(!ProtectedContentPolicy::Skip.keeps_media(true));
+        assert!(ProtectedContentPolicy::Skip.keeps_media(false));
+        assert_eq!(
+            "mirror".parse::<ProtectedContentPolicy>().unwrap(),
+            ProtectedContentPolicy::default()
+        );
+    }
+
+    #[test]
+    fn truncates_on_characters() {
+        assert_eq!(truncate_text("short", 10), "short");
+        assert_eq!(truncate_text("ünïcödé text", 5), "ünïc…");
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::debug;
+use serde_json::{json, Value};
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+
+use crate::{
+    file_types::sniff_mime,
+    sinks::{OutgoingMessage, SentMessage, Sink, SinkCapabilities},
+    spool::link_or_copy,
+    types::{DownloadedAttachment, FileData, MyResult},
+    utils::make_error,
+};
+
+/// Name of the file mapping `file_unique_id`s to where they were first stored
+const MEDIA_INDEX: &str = "media_index.json";
+
+/// A permanent on-disk record of every post from the chats it's attached to.
+///
+/// Posts are stored as `<dir>/<chat_id>/<yyyy>/<mm>/<dd>/<message_id>.json`, with their media
+/// next to them in a `media` folder. Media already archived under the same `file_unique_id` isn't
+/// stored again, the JSON just points at the earlier copy.
+#[derive(Debug)]
+pub struct ArchiveSink {
+    pub dir: PathBuf,
+    /// `file_unique_id` to path relative to `dir`, per chat, loaded from disk on first use
+    media_index: Mutex<HashMap<i64, HashMap<String, String>>>,
+}
+
+impl ArchiveSink {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            media_index: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Store an attachment unless it's already archived, returning its path relative to `dir`
+    async fn store_media(
+        &self,
+        chat_id: i64,
+        day_dir: &Path,
+        attachment: &DownloadedAttachment,
+    ) -> MyResult<String> {
+        let mut index = self.media_index.lock().await;
+        let chat_index = match index.entry(chat_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(load_index(&self.index_path(chat_id)).await?),
+        };
+
+        if let Some(existing) = chat_index.get(&attachment.file_unique_id) {
+            if self.dir.join(existing).exists() {
+                debug!("{} is already archived", attachment.file_unique_id);
+                return Ok(existing.clone());
+            }
+        }
+
+        let media_dir = day_dir.join("media");
+        tokio::fs::create_dir_all(self.dir.join(&media_dir)).await?;
+        let relative = media_dir.join(format!(
+            "{}-{}",
+            attachment.file_unique_id,
+            sanitize(&attachment.file_name)
+        ));
+        let path = self.dir.join(&relative);
+
+        let partial = path.with_extension("partial");
+        match &attachment.data {
+            FileData::Memory(bytes, _) => tokio::fs::write(&partial, bytes).await?,
+            FileData::Spooled(spooled) => link_or_copy(spooled.path(), &partial).await?,
+        }
+        tokio::fs::rename(&partial, &path).await?;
+
+        let relative = relative
+            .to_str()
+            .ok_or_else(|| make_error("Archive path isn't valid UTF-8"))?
+            .to_string();
+        chat_index.insert(attachment.file_unique_id.clone(), relative.clone());
+        write_atomically(
+            &self.index_path(chat_id),
+            &serde_json::to_vec_pretty(chat_index)?,
+        )
+        .await?;
+        Ok(relative)
+    }
+
+    fn index_path(&self, chat_id: i64) -> PathBuf {
+        self.dir.join(chat_id.to_string()).join(MEDIA_INDEX)
+    }
+
+    /// Write the post's JSON, returning its path relative to `dir`
+    async fn write_post(&self, message: &OutgoingMessage<'_>, edited: bool) -> MyResult<String> {
+        let source = message.source;
+        let ChatId(chat_id) = source.chat_id;
+        let day_dir =
+            PathBuf::from(chat_id.to_string()).join(source.date.format("%Y/%m/%d").to_string());
+
+        let mut media = Vec::new();
+        for attachment in &message.attachments {
+            // Keep what telegram had, not what was shrunk to fit somewhere else
+            let attachment = attachment.original.as_deref().unwrap_or(attachment);
+            let path = self.store_media(chat_id, &day_dir, attachment).await?;
+            media.push(json!({
+                "file_unique_id": attachment.file_unique_id,
+                "file_name": attachment.file_name,
+                "mime_type": sniff_mime(&attachment.data).await,
+                "size": attachment.data.size()?,
+                "path": path,
+            }));
+        }
+
+        let buttons: Vec<Value> = message
+            .buttons
+            .link_rows
+            .iter()
+            .flatten()
+            .chain(message.buttons.overflow_links.iter())
+            .map(|button| json!({ "label": button.label, "url": button.url }))
+            .collect();
+
+        let mut post = json!({
+            "chat_id": chat_id,
+            "chat_title": source.chat_title,
+            "chat_username": source.chat_username,
+            "message_id": source.message_id,
+            "date": source.date.to_rfc3339(),
+            "edited_at": if edited {
+                Some(source.edited_at.unwrap_or_else(Utc::now).to_rfc3339())
+            } else {
+                None
+            },
+            "text": message.text,
+            "html": message.html,
+            "entities": message.entities,
+            "buttons": buttons,
+            "media": media,
+        });
+
+        let relative = day_dir.join(format!("{}.json", source.message_id));
+        if edited {
+            post["history"] = Value::Array(load_history(&self.dir.join(&relative)).await?);
+        }
+        tokio::fs::create_dir_all(self.dir.join(&day_dir)).await?;
+        write_atomically(
+            &self.dir.join(&relative),
+            &serde_json::to_vec_pretty(&post)?,
+        )
+        .await?;
+
+        relative
+            .to_str()
+            .map(|relative| relative.to_string())
+            .ok_or_else(|| make_error("Archive path isn't valid UTF-8"))
+    }
+}
+
+#[async_trait]
+impl Sink for ArchiveSink {
+    fn name(&self) -> String {
+        format!("archive:{}", self.dir.display())
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities {
+            max_file_size: u64::MAX,
+            max_attachments: usize::MAX,
+            edit_attachments: true,
+        }
+    }
+
+    async fn send(&self, message: &OutgoingMessage<'_>) -> MyResult<SentMessage> {
+        Ok(SentMessage {
+            id: self.write_post(message, false).await?,
+            extra_ids: Vec::new(),
+        })
+    }
+
+    async fn edit(&self, _sent: &SentMessage, message: &OutgoingMessage<'_>) -> MyResult<()> {
+        self.write_post(message, true).await?;
+        Ok(())
+    }
+
+    /// The archive is meant to be a permanent record, so nothing is ever removed from it
+    async fn delete(&self, sent: &SentMessage) -> MyResult<()> {
+        debug!("Keeping archived post {}", sent.id);
+        Ok(())
+    }
+}
+
+async fn load_index(path: &Path) -> MyResult<HashMap<String, String>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+/// Every earlier version of a post, oldest first, with the one currently on disk at the end
+async fn load_history(path: &Path) -> MyResult<Vec<Value>> {
+    let mut previous: Value = match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Box::new(err)),
+    };
+    let mut history = match previous
+        .as_object_mut()
+        .and_then(|post| post.remove("history"))
+    {
+        Some(Value::Array(history)) => history,
+        _ => Vec::new(),
+    };
+    history.push(previous);
+    Ok(history)
+}
+
+/// Write next to the file then rename over it, so readers never see half of it
+async fn write_atomically(path: &Path, data: &[u8]) -> MyResult<()> {
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, data).await?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+/// Keep file names to characters that are safe everywhere
+fn sanitize(file_name: &str) -> String {
+    file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{
+        buttons::MessageButtons,
+        types::{MessageKind, MessageSource},
+    };
+
+    fn source(edited_at: Option<chrono::DateTime<Utc>>) -> MessageSource {
+        MessageSource {
+            chat_id: ChatId(-100),
+            chat_title: Some("Test channel".to_string()),
+            chat_username: None,
+            message_id: 7,
+            date: Utc.ymd(2022, 3, 4).and_hms(5, 6, 7),
+            kind: MessageKind::Text,
+            forwarded: false,
+            author_signature: None,
+            silent: false,
+            protected: false,
+            edited_at,
+        }
+    }
+
+    fn outgoing<'a>(
+        text: &str,
+        attachments: Vec<&'a DownloadedAttachment>,
+        buttons: &'a MessageButtons,
+        source: &'a MessageSource,
+    ) -> OutgoingMessage<'a> {
+        OutgoingMessage {
+            text: Some(text.to_string()),
+            html: None,
+            attachments,
+            entities: &[],
+            buttons,
+            source,
+        }
+    }
+
+    fn archive(name: &str) -> ArchiveSink {
+        let dir = std::env::temp_dir().join(format!("archive-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        ArchiveSink::new(dir)
+    }
+
+    fn read_post(archive: &ArchiveSink, relative: &str) -> Value {
+        serde_json::from_slice(&std::fs::read(archive.dir.join(relative)).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn edits_keep_earlier_versions() {
+        let archive = archive("history");
+        let buttons = MessageButtons::default();
+        let original = source(None);
+        let edited_at = Utc.ymd(2022, 3, 5).and_hms(0, 0, 0);
+        let edited = source(Some(edited_at));
+
+        let sent = archive
+            .send(&outgoing("first", Vec::new(), &buttons, &original))
+            .await
+            .unwrap();
+        archive
+            .edit(&sent, &outgoing("second", Vec::new(), &buttons, &edited))
+            .await
+            .unwrap();
+        archive
+            .edit(&sent, &outgoing("third", Vec::new(), &buttons, &edited))
+            .await
+            .unwrap();
+
+        let post = read_post(&archive, &sent.id);
+        assert_eq!(post["text"], "third");
+        assert_eq!(post["edited_at"], edited_at.to_rfc3339());
+        let history = post["history"].as_array().unwrap();
+        let texts: Vec<&str> = history
+            .iter()
+            .map(|version| version["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, ["first", "second"]);
+        assert!(history
+            .iter()
+            .all(|version| version.get("history").is_none()));
+        std::fs::remove_dir_all(&archive.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_the_original_download() {
+        let archive = archive("original");
+        let buttons = MessageButtons::default();
+        let source = source(None);
+        let shrunk = DownloadedAttachment {
+            file_name: "photo.jpg".to_string(),
+            file_unique_id: "unique".to_string(),
+            data: FileData::Memory(b"small".to_vec(), None),
+            original: Some(Box::new(DownloadedAttachment {
+                file_name: "photo.png".to_string(),
+                file_unique_id: "unique".to_string(),
+                data: FileData::Memory(b"the whole thing".to_vec(), None),
+                original: None,
+            })),
+        };
+
+        let sent = archive
+            .send(&outgoing("photo", vec![&shrunk], &buttons, &source))
+            .await
+            .unwrap();
+
+        let post = read_post(&archive, &sent.id);
+        let media = &post["media"][0];
+        assert_eq!(media["file_name"], "photo.png");
+        let stored = std::fs::read(archive.dir.join(media["path"].as_str().unwrap())).unwrap();
+        assert_eq!(stored, b"the whole thing");
+        std::fs::remove_dir_all(&archive.dir).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use serenity::{
+    builder::CreateAllowedMentions,
+    model::id::{MessageId, RoleId, UserId, WebhookId},
+};
+
+use crate::{
+    digest::DigestEntry,
+    formatting::escape_discord_markdown,
+    pings::{neutralize_mass_mentions, Mention},
+    sinks::{truncate_text, OutgoingMessage, SentMessage, Sink, SinkCapabilities},
+    types::{MyResult, WebhookData},
+    utils::make_error,
+    DISCORD_MAX_FILE_SIZE, HTTP,
+};
+
+/// Discord rejects message content longer than this
+const MAX_CONTENT_LENGTH: usize = 2000;
+/// And more than this many files on one message
+const MAX_ATTACHMENTS: usize = 10;
+/// Message flag that posts without pinging or playing a sound for anyone
+const SUPPRESS_NOTIFICATIONS: u64 = 1 << 12;
+/// Or more than this many embeds
+const MAX_EMBEDS: usize = 10;
+/// Longest an embed title can be
+const MAX_EMBED_TITLE_LENGTH: usize = 256;
+/// And an embed description
+const MAX_EMBED_DESCRIPTION_LENGTH: usize = 4096;
+/// And all the text in a message's embeds put together
+const MAX_TOTAL_EMBED_LENGTH: usize = 6000;
+/// Kept free for the embed listing the posts that didn't fit into a digest
+const OVERFLOW_EMBED_RESERVE: usize = 500;
+
+impl WebhookData {
+    /// The message text, or the route's template filled in, with any buttons Discord can't show
+    /// tacked onto the end and the route's pings in front
+    fn content(&self, message: &OutgoingMessage<'_>, mentions: &[Mention]) -> Option<String> {
+        let text = match &self.template {
+            Some(template) => Some(template.render(message)).filter(|text| !text.trim().is_empty()),
+            None => message.text.clone(),
+        };
+        let content = match (text, message.buttons.text_suffix(self.callback_buttons)) {
+            (Some(text), Some(suffix)) => Some(format!("{}\n\n{}", text, suffix)),
+            (None, Some(suffix)) => Some(suffix),
+            (text, None) => text,
+        }
+        .map(|content| neutralize_mass_mentions(&content));
+
+        let pings: Vec<String> = mentions.iter().map(|mention| mention.markup()).collect();
+        let content = match (content, pings.is_empty()) {
+            (content, true) => content,
+            (Some(content), false) => Some(format!("{}\n{}", pings.join(" "), content)),
+            (None, false) => Some(pings.join(" ")),
+        };
+        content.map(|content| truncate_text(&content, MAX_CONTENT_LENGTH))
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookData {
+    fn name(&self) -> String {
+        format!("discord:{}", self.raw_webhook.id)
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities {
+            max_file_size: *DISCORD_MAX_FILE_SIZE,
+            max_attachments: MAX_ATTACHMENTS,
+            edit_attachments: false,
+        }
+    }
+
+    /// Discord rejects a message with no content, files or components, which is all that's left
+    /// of one with only callback buttons when they're skipped
+    fn can_render(&self, message: &OutgoingMessage<'_>) -> bool {
+        let mentions = self.pings.mentions_for(message);
+        self.content(message, &mentions).is_some()
+            || !message.attachments.is_empty()
+            || !message.buttons.link_rows.is_empty()
+    }
+
+    async fn send(&self, message: &OutgoingMessage<'_>) -> MyResult<SentMessage> {
+        let mentions = self.pings.mentions_for(message);
+        let content = self.content(message, &mentions);
+
+        let sent = self
+            .raw_webhook
+            .execute(&*HTTP, true, |hook| {
+                let hook = match content {
+                    Some(text) => hook.content(text),
+                    None => hook,
+                };
+                for file in &message.attachments {
+                    hook.add_file(file.to_discord_attachment());
+                }
+                if let Some(components) = message.buttons.to_components() {
+                    hook.set_components(components);
+                }
+                if message.source.silent {
+                    // Serenity has no setter for message flags on webhooks yet
+                    hook.0.insert("flags", Value::from(SUPPRESS_NOTIFICATIONS));
+                }
+                hook.allowed_mentions(|allowed| allow_only(allowed, &mentions))
+                    .avatar_url(&self.icon_url)
+                    .username(&self.webhook_username)
+            })
+            .await?
+            .ok_or_else(|| make_error("Discord didn't return the message it created"))?;
+
+        Ok(SentMessage {
+            id: sent.id.to_string(),
+            extra_ids: Vec::new(),
+        })
+    }
+
+    async fn edit(&self, sent: &SentMessage, message: &OutgoingMessage<'_>) -> MyResult<()> {
+        let mentions = self.pings.mentions_for(message);
+        let content = self.content(message, &mentions).unwrap_or_default();
+
+        self.raw_webhook
+            .edit_message(&*HTTP, MessageId(sent.id.parse()?), |edit| {
+                let edit = edit
+                    .content(content)
+                    .allowed_mentions(|allowed| allow_only(allowed, &mentions));
+                match message.buttons.to_components() {
+                    Some(components) => edit.components(|c| {
+                        *c = components;
+                        c
+                    }),
+                    None => edit.components(|c| c),
+                }
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, sent: &SentMessage) -> MyResult<()> {
+        self.raw_webhook
+            .delete_message(&*HTTP, MessageId(sent.id.parse()?))
+            .await?;
+        Ok(())
+    }
+
+    /// One embed per post with its link and thumbnail. Past what fits on a message, the rest
+    /// are listed as links in the last embed.
+    async fn send_digest(&self, entries: &[DigestEntry]) -> MyResult<()> {
+        let embeds = digest_embeds(entries);
+        self.raw_webhook
+            .execute(&*HTTP, true, |hook| {
+                // The embeds are already JSON, so they go straight into the request
+                hook.0.insert("embeds", Value::from(embeds));
+                hook.content(format!("{} new posts", entries.len()))
+                    .allowed_mentions(|allowed| allowed.empty_parse())
+                    .avatar_url(&self.icon_url)
+                    .username(&self.webhook_username)
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn discord_webhook_id(&self) -> Option<WebhookId> {
+        Some(self.raw_webhook.id)
+    }
+}
+
+/// An embed per post, as many as fit into one message, then one listing the rest
+fn digest_embeds(entries: &[DigestEntry]) -> Vec<Value> {
+    let mut embeds = Vec::new();
+    let mut used = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let embed = digest_embed(entry);
+        let length = embed_length(&embed);
+        // The last post can have the room the list of the rest would have taken
+        let (slots, budget) = if index + 1 == entries.len() {
+            (MAX_EMBEDS, MAX_TOTAL_EMBED_LENGTH)
+        } else {
+            (
+                MAX_EMBEDS - 1,
+                MAX_TOTAL_EMBED_LENGTH - OVERFLOW_EMBED_RESERVE,
+            )
+        };
+        if embeds.len() >= slots || used + length > budget {
+            break;
+        }
+        used += length;
+        embeds.push(embed);
+    }
+
+    let rest = &entries[embeds.len()..];
+    if !rest.is_empty() {
+        let links: Vec<String> = rest
+            .iter()
+            .map(|entry| {
+                let title = entry.chat_title.as_deref().unwrap_or("Telegram");
+                match &entry.link {
+                    Some(link) => format!("[{}]({})", escape_discord_markdown(title), link),
+                    None => escape_discord_markdown(title),
+                }
+            })
+            .collect();
+        let title = format!("And {} more", rest.len());
+        let room = (MAX_TOTAL_EMBED_LENGTH - used - title.chars().count())
+            .min(MAX_EMBED_DESCRIPTION_LENGTH);
+        embeds.push(json!({
+            "title": title,
+            "description": truncate_text(&links.join("\n"), room),
+        }));
+    }
+    embeds
+}
+
+/// How much of an embed counts towards `MAX_TOTAL_EMBED_LENGTH`
+fn embed_length(embed: &Value) -> usize {
+    ["title", "description"]
+        .iter()
+        .filter_map(|key| embed[*key].as_str())
+        .map(|text| text.chars().count())
+        .sum()
+}
+
+fn digest_embed(entry: &DigestEntry) -> Value {
+    let mut embed = json!({
+        "title": truncate_text(
+            entry.chat_title.as_deref().unwrap_or("Telegram"),
+            MAX_EMBED_TITLE_LENGTH
+        ),
+        "timestamp": entry.date.to_rfc3339(),
+    });
+    if let Some(link) = &entry.link {
+        embed["url"] = json!(link);
+    }
+    if let Some(excerpt) = &entry.excerpt {
+        embed["description"] = json!(truncate_text(excerpt, MAX_EMBED_DESCRIPTION_LENGTH));
+    }
+    if let Some(thumbnail) = &entry.thumbnail {
+        embed["thumbnail"] = json!({ "url": thumbnail });
+    }
+    embed
+}
+
+/// Only let the route's own pings through, never `@everyone`, `@here` or anything in the text
+fn allow_only<'a>(
+    allowed: &'a mut CreateAllowedMentions,
+    mentions: &[Mention],
+) -> &'a mut CreateAllowedMentions {
+    let roles: Vec<RoleId> = mentions
+        .iter()
+        .filter_map(|mention| match mention {
+            Mention::Role(id) => Some(RoleId(*id)),
+            Mention::User(_) => None,
+        })
+        .collect();
+    let users: Vec<UserId> = mentions
+        .iter()
+        .filter_map(|mention| match mention {
+            Mention::User(id) => Some(UserId(*id)),
+            Mention::Role(_) => None,
+        })
+        .collect();
+    allowed.empty_parse().roles(roles).users(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn entry(message_id: i32, excerpt_length: usize) -> DigestEntry {
+        DigestEntry {
+            chat_id: -100,
+            chat_title: Some("Channel".to_string()),
+            message_id,
+            date: Utc::now(),
+            link: Some(format!("https://t.me/channel/{}", message_id)),
+            excerpt: Some("x".repeat(excerpt_length)),
+            thumbnail: None,
+        }
+    }
+
+    fn total_length(embeds: &[Value]) -> usize {
+        embeds.iter().map(embed_length).sum()
+    }
+
+    #[test]
+    fn small_digests_get_an_embed_per_post() {
+        let entries: Vec<DigestEntry> = (0..MAX_EMBEDS as i32).map(|id| entry(id, 100)).collect();
+        let embeds = digest_embeds(&entries);
+        assert_eq!(embeds.len(), MAX_EMBEDS);
+        assert!(embeds.iter().all(|embed| embed["title"] == "Channel"));
+    }
+
+    #[test]
+    fn too_many_posts_are_listed_at_the_end() {
+        let entries: Vec<DigestEntry> = (0..15).map(|id| entry(id, 100)).collect();
+        let embeds = digest_embeds(&entries);
+        assert_eq!(embeds.len(), MAX_EMBEDS);
+        assert_eq!(embeds[MAX_EMBEDS - 1]["title"], "And 6 more");
+    }
+
+    #[test]
+    fn long_posts_stay_under_the_total_limit() {
+        let entries: Vec<DigestEntry> = (0..8).map(|id| entry(id, 2000)).collect();
+        let embeds = digest_embeds(&entries);
+        assert!(total_length(&embeds) <= MAX_TOTAL_EMBED_LENGTH);
+        // Two posts fit next to the list of the other six
+        assert_eq!(embeds.len(), 3);
+        assert_eq!(embeds[2]["title"], "And 6 more");
+
+        let huge = [entry(1, 10_000), entry(2, 10_000)];
+        let embeds = digest_embeds(&huge);
+        assert!(total_length(&embeds) <= MAX_TOTAL_EMBED_LENGTH);
+        assert_eq!(embeds.len(), 2);
+    }
+}
@@ -0,0 +1,610 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use reqwest::{header::CONTENT_LENGTH, Method, Url};
+use serde_json::{json, Value};
+
+use crate::{
+    file_types::sniff_mime,
+    formatting::escape_html,
+    sinks::{OutgoingMessage, SentMessage, Sink, SinkCapabilities},
+    types::{DownloadedAttachment, MyResult},
+    utils::{file_body, make_error},
+    WEB_CLIENT,
+};
+
+/// Format name for HTML bodies, the only one clients understand
+const HTML_FORMAT: &str = "org.matrix.custom.html";
+
+/// Counter to keep transaction IDs unique within one millisecond
+static TXN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A Matrix room, posted to through the client-server API as a regular user or bot account.
+///
+/// The homeserver URL is just a base URL, so it can point at a local stand-in for testing.
+#[derive(Debug)]
+pub struct MatrixSink {
+    pub homeserver: Url,
+    pub access_token: String,
+    pub room_id: String,
+    /// Biggest upload the homeserver takes, see `m.upload.size` in its media config
+    pub max_file_size: u64,
+}
+
+impl MatrixSink {
+    /// Build a client-server API URL out of path segments, escaping each one
+    fn url(&self, segments: &[&str]) -> MyResult<Url> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| make_error("Matrix homeserver URL can't be a base"))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    async fn request(&self, method: Method, url: Url, body: Option<&Value>) -> MyResult<Value> {
+        let mut request = WEB_CLIENT
+            .request(method, url)
+            .bearer_auth(&self.access_token);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+
+        let status = response.status();
+        let response: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(make_error(&format!(
+                "Matrix homeserver returned {}: {}",
+                status,
+                response["error"].as_str().unwrap_or("unknown error")
+            )));
+        }
+        Ok(response)
+    }
+
+    /// Send a `m.room.message` event, returning its event ID
+    async fn send_event(&self, content: &Value) -> MyResult<String> {
+        let txn_id = txn_id();
+        let url = self.url(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &self.room_id,
+            "send",
+            "m.room.message",
+            &txn_id,
+        ])?;
+
+        let response = self.request(Method::PUT, url, Some(content)).await?;
+        response["event_id"]
+            .as_str()
+            .map(|event_id| event_id.to_string())
+            .ok_or_else(|| make_error("Matrix homeserver didn't return an event ID"))
+    }
+
+    async fn redact(&self, event_id: &str) -> MyResult<()> {
+        let txn_id = txn_id();
+        let url = self.url(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &self.room_id,
+            "redact",
+            event_id,
+            &txn_id,
+        ])?;
+
+        self.request(
+            Method::PUT,
+            url,
+            Some(&json!({ "reason": "Deleted on Telegram" })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The content of an event we sent earlier
+    async fn event_content(&self, event_id: &str) -> MyResult<Value> {
+        let url = self.url(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &self.room_id,
+            "event",
+            event_id,
+        ])?;
+
+        let mut event = self.request(Method::GET, url, None).await?;
+        Ok(event["content"].take())
+    }
+
+    /// Put a file in the homeserver's media repo, returning its `mxc://` URI. Spooled files are
+    /// streamed rather than read into memory.
+    async fn upload(&self, attachment: &DownloadedAttachment, mime: &str) -> MyResult<String> {
+        let (body, length) = file_body(&attachment.data).await?;
+
+        let mut url = self.url(&["_matrix", "media", "v3", "upload"])?;
+        url.query_pairs_mut()
+            .append_pair("filename", &attachment.file_name);
+
+        let response = WEB_CLIENT
+            .post(url)
+            .bearer_auth(&self.access_token)
+            .header(reqwest::header::CONTENT_TYPE, mime)
+            .header(CONTENT_LENGTH, length)
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let response: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(make_error(&format!(
+                "Matrix media upload returned {}: {}",
+                status,
+                response["error"].as_str().unwrap_or("unknown error")
+            )));
+        }
+
+        response["content_uri"]
+            .as_str()
+            .map(|uri| uri.to_string())
+            .ok_or_else(|| make_error("Matrix homeserver didn't return a content URI"))
+    }
+
+    /// Upload an attachment and send it as an event of the matching type
+    async fn send_attachment(&self, attachment: &DownloadedAttachment) -> MyResult<String> {
+        let mime = sniff_mime(&attachment.data).await;
+        let content_uri = self.upload(attachment, mime).await?;
+        debug!("Uploaded {} to {}", attachment.file_name, content_uri);
+
+        let msgtype = match mime.split('/').next() {
+            Some("image") => "m.image",
+            Some("video") => "m.video",
+            Some("audio") => "m.audio",
+            _ => "m.file",
+        };
+
+        self.send_event(&json!({
+            "msgtype": msgtype,
+            "body": attachment.file_name,
+            "filename": attachment.file_name,
+            "url": content_uri,
+            "info": {
+                "mimetype": mime,
+                "size": attachment.data.size().unwrap_or_default(),
+            },
+        }))
+        .await
+    }
+}
+
+#[async_trait]
+impl Sink for MatrixSink {
+    fn name(&self) -> String {
+        format!("matrix:{}", self.room_id)
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities {
+            max_file_size: self.max_file_size,
+            // Every attachment is its own event, so there's no real cap
+            max_attachments: usize::MAX,
+            edit_attachments: false,
+        }
+    }
+
+    async fn send(&self, message: &OutgoingMessage<'_>) -> MyResult<SentMessage> {
+        let mut event_ids = Vec::new();
+
+        if let Some(content) = text_content(message) {
+            event_ids.push(self.send_event(&content).await?);
+        }
+
+        for attachment in &message.attachments {
+            match self.send_attachment(attachment).await {
+                Ok(event_id) => event_ids.push(event_id),
+                Err(err) => warn!("Failed to send {} to Matrix: {}", attachment.file_name, err),
+            }
+        }
+
+        // The text event comes first if there is one, so edits can find it
+        let mut event_ids = event_ids.into_iter();
+        let id = event_ids
+            .next()
+            .ok_or_else(|| make_error("Nothing could be sent to Matrix"))?;
+        Ok(SentMessage {
+            id,
+            extra_ids: event_ids.collect(),
+        })
+    }
+
+    async fn edit(&self, sent: &SentMessage, message: &OutgoingMessage<'_>) -> MyResult<()> {
+        let original = self.event_content(&sent.id).await?;
+        let new_content = match original["msgtype"].as_str() {
+            // Posts without text were sent as just their media, so the text becomes its caption
+            Some(msgtype) if msgtype != "m.text" => caption_content(original, message),
+            _ => text_content(message).unwrap_or_else(|| {
+                json!({
+                    "msgtype": "m.text",
+                    "body": "",
+                })
+            }),
+        };
+
+        // Clients without edit support show the fallback, marked with a star like they expect
+        let mut content = new_content.clone();
+        content["body"] = json!(format!("* {}", new_content["body"].as_str().unwrap_or("")));
+        if let Some(html) = new_content["formatted_body"].as_str() {
+            content["formatted_body"] = json!(format!("* {}", html));
+        }
+        content["m.new_content"] = new_content;
+        content["m.relates_to"] = json!({
+            "rel_type": "m.replace",
+            "event_id": sent.id,
+        });
+
+        self.send_event(&content).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, sent: &SentMessage) -> MyResult<()> {
+        self.redact(&sent.id).await?;
+        for event_id in &sent.extra_ids {
+            self.redact(event_id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A media event's content with the message text as its caption, keeping its type and file.
+/// Captions are bodies that differ from the file name, so no text goes back to the file name.
+fn caption_content(mut content: Value, message: &OutgoingMessage<'_>) -> Value {
+    if let Some(object) = content.as_object_mut() {
+        object.remove("m.new_content");
+        object.remove("m.relates_to");
+        object.remove("format");
+        object.remove("formatted_body");
+    }
+    match &message.text {
+        Some(text) => {
+            content["body"] = json!(text);
+            content["format"] = json!(HTML_FORMAT);
+            content["formatted_body"] = json!(text_html(message, text));
+        }
+        None => {
+            if let Some(file_name) = content.get("filename").cloned() {
+                content["body"] = file_name;
+            }
+        }
+    }
+    content
+}
+
+/// The message text as HTML, with line breaks everywhere but in code blocks
+fn text_html(message: &OutgoingMessage<'_>, text: &str) -> String {
+    match message.html {
+        Some(html) => newlines_to_br(html),
+        None => newlines_to_br(&escape_html(text)),
+    }
+}
+
+/// Turn newlines into `<br>`, leaving `<pre>` blocks alone since they keep their own
+fn newlines_to_br(html: &str) -> String {
+    let mut converted = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<pre") {
+        converted.push_str(&rest[..start].replace('\n', "<br>"));
+        let end = rest[start..]
+            .find("</pre>")
+            .map_or(rest.len(), |end| start + end + "</pre>".len());
+        converted.push_str(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    converted.push_str(&rest.replace('\n', "<br>"));
+    converted
+}
+
+/// The `m.text` content for the message text and its buttons, or None if it has neither
+fn text_content(message: &OutgoingMessage<'_>) -> Option<Value> {
+    let mut body_lines = Vec::new();
+    let mut html_lines = Vec::new();
+
+    if let Some(text) = &message.text {
+        body_lines.push(text.clone());
+        html_lines.push(text_html(message, text));
+    }
+
+    // Matrix has no buttons, so links go under the text
+    let links: Vec<_> = message
+        .buttons
+        .link_rows
+        .iter()
+        .flatten()
+        .chain(message.buttons.overflow_links.iter())
+        .collect();
+    if !links.is_empty() {
+        body_lines.push(
+            links
+                .iter()
+                .map(|button| format!("{}: {}", button.label, button.url))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+        html_lines.push(
+            links
+                .iter()
+                .map(|button| {
+                    format!(
+                        "<a href=\"{}\">{}</a>",
+                        escape_html(&button.url),
+                        escape_html(&button.label)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" · "),
+        );
+    }
+
+    if body_lines.is_empty() {
+        return None;
+    }
+
+    Some(json!({
+        "msgtype": "m.text",
+        "body": body_lines.join("\n\n"),
+        "format": HTML_FORMAT,
+        "formatted_body": html_lines.join("<br><br>"),
+    }))
+}
+
+/// A transaction ID nothing else this process sends will reuse
+fn txn_id() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default();
+    format!(
+        "tgmirror.{}.{}",
+        millis,
+        TXN_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Bytes,
+        extract::{Extension, Path},
+        routing::{get, post, put},
+        Json, Router,
+    };
+    use chrono::Utc;
+    use teloxide::types::ChatId;
+
+    use super::*;
+    use crate::{
+        buttons::MessageButtons,
+        spool::SpooledFile,
+        types::{FileData, MessageKind, MessageSource},
+    };
+
+    const PNG_HEADER: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    /// What the stand-in homeserver was sent
+    #[derive(Default)]
+    struct Homeserver {
+        events: Vec<Value>,
+        uploads: Vec<Vec<u8>>,
+    }
+
+    type Shared = Arc<Mutex<Homeserver>>;
+
+    async fn send_event(
+        Extension(server): Extension<Shared>,
+        Json(content): Json<Value>,
+    ) -> Json<Value> {
+        let mut server = server.lock().unwrap();
+        server.events.push(content);
+        Json(json!({ "event_id": format!("${}", server.events.len() - 1) }))
+    }
+
+    async fn get_event(
+        Extension(server): Extension<Shared>,
+        Path((_room, event_id)): Path<(String, String)>,
+    ) -> Json<Value> {
+        let index: usize = event_id.trim_start_matches('$').parse().unwrap();
+        Json(json!({ "content": server.lock().unwrap().events[index] }))
+    }
+
+    async fn upload(Extension(server): Extension<Shared>, body: Bytes) -> Json<Value> {
+        let mut server = server.lock().unwrap();
+        server.uploads.push(body.to_vec());
+        Json(json!({ "content_uri": format!("mxc://example.org/{}", server.uploads.len()) }))
+    }
+
+    /// A sink pointed at a fresh stand-in homeserver
+    async fn stand_in() -> (MatrixSink, Shared) {
+        let server = Shared::default();
+        let app = Router::new()
+            .route(
+                "/_matrix/client/v3/rooms/:room/send/m.room.message/:txn",
+                put(send_event),
+            )
+            .route(
+                "/_matrix/client/v3/rooms/:room/event/:event",
+                get(get_event),
+            )
+            .route("/_matrix/media/v3/upload", post(upload))
+            .layer(Extension(server.clone()));
+        let listener = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let homeserver = format!("http://{}/", listener.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(listener);
+
+        let sink = MatrixSink {
+            homeserver,
+            access_token: "token".to_string(),
+            room_id: "!room:example.org".to_string(),
+            max_file_size: 1024 * 1024,
+        };
+        (sink, server)
+    }
+
+    fn source() -> MessageSource {
+        MessageSource {
+            chat_id: ChatId(-100),
+            chat_title: None,
+            chat_username: None,
+            message_id: 1,
+            date: Utc::now(),
+            kind: MessageKind::Photo,
+            forwarded: false,
+            author_signature: None,
+            silent: false,
+            protected: false,
+            edited_at: None,
+        }
+    }
+
+    fn outgoing<'a>(
+        text: Option<&str>,
+        html: Option<&'a str>,
+        attachments: Vec<&'a DownloadedAttachment>,
+        buttons: &'a MessageButtons,
+        source: &'a MessageSource,
+    ) -> OutgoingMessage<'a> {
+        OutgoingMessage {
+            text: text.map(str::to_string),
+            html,
+            attachments,
+            entities: &[],
+            buttons,
+            source,
+        }
+    }
+
+    fn photo() -> DownloadedAttachment {
+        DownloadedAttachment {
+            file_name: "photo.png".to_string(),
+            file_unique_id: "photo".to_string(),
+            data: FileData::Memory(PNG_HEADER.to_vec(), None),
+            original: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_text_then_media() {
+        let (sink, server) = stand_in().await;
+        let (buttons, source, photo) = (MessageButtons::default(), source(), photo());
+
+        let sent = sink
+            .send(&outgoing(
+                Some("hello"),
+                None,
+                vec![&photo],
+                &buttons,
+                &source,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(sent.id, "$0");
+        assert_eq!(sent.extra_ids, ["$1"]);
+        let server = server.lock().unwrap();
+        assert_eq!(server.events[0]["msgtype"], "m.text");
+        assert_eq!(server.events[0]["body"], "hello");
+        assert_eq!(server.events[1]["msgtype"], "m.image");
+        assert_eq!(server.events[1]["url"], "mxc://example.org/1");
+        assert_eq!(server.uploads, [PNG_HEADER.to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn captions_keep_the_media() {
+        let (sink, server) = stand_in().await;
+        let (buttons, source, photo) = (MessageButtons::default(), source(), photo());
+        let sent = sink
+            .send(&outgoing(None, None, vec![&photo], &buttons, &source))
+            .await
+            .unwrap();
+
+        sink.edit(
+            &sent,
+            &outgoing(Some("a caption"), None, Vec::new(), &buttons, &source),
+        )
+        .await
+        .unwrap();
+
+        let server = server.lock().unwrap();
+        let edit = &server.events[1];
+        assert_eq!(edit["m.relates_to"]["event_id"], "$0");
+        let new_content = &edit["m.new_content"];
+        assert_eq!(new_content["msgtype"], "m.image");
+        assert_eq!(new_content["url"], "mxc://example.org/1");
+        assert_eq!(new_content["body"], "a caption");
+        assert_eq!(new_content["filename"], "photo.png");
+    }
+
+    #[tokio::test]
+    async fn text_edits_replace_the_text() {
+        let (sink, server) = stand_in().await;
+        let (buttons, source) = (MessageButtons::default(), source());
+        let sent = sink
+            .send(&outgoing(Some("typo"), None, Vec::new(), &buttons, &source))
+            .await
+            .unwrap();
+
+        sink.edit(
+            &sent,
+            &outgoing(Some("fixed"), None, Vec::new(), &buttons, &source),
+        )
+        .await
+        .unwrap();
+
+        let server = server.lock().unwrap();
+        assert_eq!(server.events[1]["body"], "* fixed");
+        assert_eq!(server.events[1]["m.new_content"]["msgtype"], "m.text");
+        assert_eq!(server.events[1]["m.new_content"]["body"], "fixed");
+    }
+
+    #[tokio::test]
+    async fn streams_spooled_uploads() {
+        let (sink, server) = stand_in().await;
+        let spooled = SpooledFile::new("big.png").await.unwrap();
+        let mut contents = PNG_HEADER.to_vec();
+        contents.resize(256 * 1024, 7);
+        std::fs::write(spooled.path(), &contents).unwrap();
+        let attachment = DownloadedAttachment {
+            file_name: "big.png".to_string(),
+            file_unique_id: "big".to_string(),
+            data: FileData::Spooled(spooled),
+            original: None,
+        };
+
+        sink.upload(&attachment, "image/png").await.unwrap();
+        assert_eq!(server.lock().unwrap().uploads, [contents]);
+    }
+
+    #[test]
+    fn code_blocks_keep_their_newlines() {
+        assert_eq!(
+            newlines_to_br("one\ntwo<pre>let a;\nlet b;</pre>\nthree"),
+            "one<br>two<pre>let a;\nlet b;</pre><br>three"
+        );
+        assert_eq!(newlines_to_br("<pre>open\nended"), "<pre>open\nended");
+    }
+}
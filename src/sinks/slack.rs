@@ -0,0 +1,394 @@
+use async_trait::async_trait;
+use log::{debug, warn};
+use reqwest::header::CONTENT_LENGTH;
+use serde_json::{json, Value};
+
+use crate::{
+    buttons::CallbackButtonPolicy,
+    sinks::{truncate_text, OutgoingMessage, SentMessage, Sink, SinkCapabilities},
+    types::{DownloadedAttachment, MyResult},
+    utils::{file_body, make_error},
+    WEB_CLIENT,
+};
+
+/// Where Slack's Web API lives, for file uploads
+const SLACK_API: &str = "https://slack.com/api";
+/// Slack refuses messages with more blocks than this
+const MAX_BLOCKS: usize = 50;
+/// Longest text a single section block can hold
+const MAX_SECTION_LENGTH: usize = 3000;
+/// Longest top level `text`, which is what shows up in notifications
+const MAX_FALLBACK_LENGTH: usize = 40000;
+/// Most elements one actions block can hold
+const MAX_ACTIONS: usize = 25;
+/// Button labels longer than this are rejected
+const MAX_BUTTON_LABEL_LENGTH: usize = 75;
+/// Files over 1 GB can't be uploaded at all
+const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+/// Keep image blocks from eating the whole block budget
+const MAX_ATTACHMENTS: usize = 10;
+
+/// A Slack channel, posted to through an incoming webhook
+#[derive(Debug)]
+pub struct SlackSink {
+    pub webhook_url: String,
+    /// Bot token with `files:write`, without it attachments are left off
+    pub bot_token: Option<String>,
+    /// Channel the webhook posts to, needed to share non-image files there. Required along with a
+    /// bot token.
+    pub channel_id: Option<String>,
+    /// How to render inline keyboard buttons that aren't plain links
+    pub callback_buttons: CallbackButtonPolicy,
+}
+
+/// A file uploaded to Slack, not necessarily shared anywhere yet
+struct UploadedFile {
+    id: String,
+    title: String,
+    is_image: bool,
+}
+
+impl SlackSink {
+    /// Turn the message into Block Kit, with image blocks for the uploaded images
+    fn blocks(&self, message: &OutgoingMessage<'_>, uploaded: &[UploadedFile]) -> Vec<Value> {
+        let mut blocks = Vec::new();
+
+        if let Some(text) = &message.text {
+            for chunk in split_text(&escape_mrkdwn(text), MAX_SECTION_LENGTH) {
+                blocks.push(json!({
+                    "type": "section",
+                    "text": { "type": "mrkdwn", "text": chunk },
+                }));
+            }
+        }
+
+        for file in uploaded.iter().filter(|file| file.is_image) {
+            blocks.push(json!({
+                "type": "image",
+                "slack_file": { "id": file.id },
+                "alt_text": file.title,
+            }));
+        }
+
+        let buttons: Vec<Value> = message
+            .buttons
+            .link_rows
+            .iter()
+            .flatten()
+            .take(MAX_ACTIONS)
+            .map(|button| {
+                json!({
+                    "type": "button",
+                    "text": {
+                        "type": "plain_text",
+                        "text": truncate_text(&button.label, MAX_BUTTON_LABEL_LENGTH),
+                    },
+                    "url": button.url,
+                })
+            })
+            .collect();
+        if !buttons.is_empty() {
+            blocks.push(json!({ "type": "actions", "elements": buttons }));
+        }
+
+        let mut extra_lines: Vec<String> = message
+            .buttons
+            .overflow_links
+            .iter()
+            .map(|button| format!("<{}|{}>", button.url, escape_mrkdwn(&button.label)))
+            .collect();
+        if self.callback_buttons == CallbackButtonPolicy::Text {
+            extra_lines.extend(
+                message
+                    .buttons
+                    .callback_labels
+                    .iter()
+                    .map(|label| format!("[{}]", escape_mrkdwn(label))),
+            );
+        }
+        if !extra_lines.is_empty() {
+            blocks.push(json!({
+                "type": "section",
+                "text": {
+                    "type": "mrkdwn",
+                    "text": truncate_text(&extra_lines.join("\n"), MAX_SECTION_LENGTH),
+                },
+            }));
+        }
+
+        let mut context = Vec::new();
+        if let Some(chat_title) = &message.source.chat_title {
+            context.push(format!("From *{}* on Telegram", escape_mrkdwn(chat_title)));
+        }
+        let left_off = message.attachments.len() - uploaded.len();
+        if left_off > 0 {
+            context.push(format!("{} attachment(s) not mirrored", left_off));
+        }
+        if !context.is_empty() {
+            blocks.push(json!({
+                "type": "context",
+                "elements": [{ "type": "mrkdwn", "text": context.join(" · ") }],
+            }));
+        }
+
+        if blocks.len() > MAX_BLOCKS {
+            warn!(
+                "Slack message has {} blocks, dropping all past {}",
+                blocks.len(),
+                MAX_BLOCKS
+            );
+            blocks.truncate(MAX_BLOCKS);
+        }
+        blocks
+    }
+
+    /// Upload every attachment through the external upload flow, skipping the ones that fail
+    async fn upload_files(&self, token: &str, message: &OutgoingMessage<'_>) -> Vec<UploadedFile> {
+        let mut uploaded = Vec::new();
+        for attachment in &message.attachments {
+            match upload_file(token, attachment).await {
+                Ok(file) => uploaded.push(file),
+                Err(err) => warn!(
+                    "Failed to upload {} to Slack: {}",
+                    attachment.file_name, err
+                ),
+            }
+        }
+        uploaded
+    }
+
+    /// Finish the uploads. Images are left unshared since the image blocks already show them,
+    /// everything else gets shared into the channel.
+    async fn complete_uploads(
+        &self,
+        token: &str,
+        uploaded: &[UploadedFile],
+        shared: bool,
+    ) -> MyResult<()> {
+        let files: Vec<Value> = uploaded
+            .iter()
+            .filter(|file| file.is_image != shared)
+            .map(|file| json!({ "id": file.id, "title": file.title }))
+            .collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let mut body = json!({ "files": files });
+        if shared {
+            match &self.channel_id {
+                Some(channel_id) => body["channel_id"] = json!(channel_id),
+                None => {
+                    return Err(make_error(
+                        "No Slack channel ID set, can't share non-image files",
+                    ))
+                }
+            }
+        }
+
+        call_api(token, "files.completeUploadExternal", &body).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for SlackSink {
+    fn name(&self) -> String {
+        // The last part of the webhook URL is its secret, so only use the stable IDs before it
+        let id = self
+            .webhook_url
+            .rsplit_once('/')
+            .map(|(rest, _)| rest.trim_start_matches("https://hooks.slack.com/services/"))
+            .unwrap_or_default();
+        format!("slack:{}", id)
+    }
+
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities {
+            // Without a bot token nothing can be uploaded
+            max_file_size: if self.bot_token.is_some() {
+                MAX_FILE_SIZE
+            } else {
+                0
+            },
+            max_attachments: if self.bot_token.is_some() {
+                MAX_ATTACHMENTS
+            } else {
+                0
+            },
+            edit_attachments: false,
+        }
+    }
+
+    async fn send(&self, message: &OutgoingMessage<'_>) -> MyResult<SentMessage> {
+        let uploaded = match &self.bot_token {
+            Some(token) => {
+                let uploaded = self.upload_files(token, message).await;
+                // Image blocks can only point at files whose upload is complete
+                if let Err(err) = self.complete_uploads(token, &uploaded, false).await {
+                    warn!("Failed to complete Slack image uploads: {}", err);
+                }
+                uploaded
+            }
+            None => Vec::new(),
+        };
+
+        let fallback = message
+            .text
+            .as_deref()
+            .map(|text| truncate_text(&escape_mrkdwn(text), MAX_FALLBACK_LENGTH))
+            .unwrap_or_else(|| "New post from Telegram".to_string());
+
+        let response = WEB_CLIENT
+            .post(&self.webhook_url)
+            .json(&json!({
+                "text": fallback,
+                "blocks": self.blocks(message, &uploaded),
+            }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(make_error(&format!(
+                "Slack webhook returned {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )));
+        }
+
+        // The post is already out, so failing now would only get it sent twice
+        if let Some(token) = &self.bot_token {
+            if let Err(err) = self.complete_uploads(token, &uploaded, true).await {
+                warn!("Failed to share files in the Slack channel: {}", err);
+            }
+        }
+
+        // Incoming webhooks don't say what they posted, so there's nothing to point back at
+        Ok(SentMessage {
+            id: String::new(),
+            extra_ids: Vec::new(),
+        })
+    }
+
+    async fn edit(&self, _sent: &SentMessage, _message: &OutgoingMessage<'_>) -> MyResult<()> {
+        Err(make_error("Slack incoming webhooks can't edit messages"))
+    }
+
+    async fn delete(&self, _sent: &SentMessage) -> MyResult<()> {
+        Err(make_error("Slack incoming webhooks can't delete messages"))
+    }
+}
+
+/// Upload one file, returning its ID. It won't show up anywhere until the upload is completed.
+async fn upload_file(token: &str, attachment: &DownloadedAttachment) -> MyResult<UploadedFile> {
+    let (body, length) = file_body(&attachment.data).await?;
+    let length = length.to_string();
+    let response = WEB_CLIENT
+        .get(format!("{}/files.getUploadURLExternal", SLACK_API))
+        .bearer_auth(token)
+        .query(&[
+            ("filename", attachment.file_name.as_str()),
+            ("length", length.as_str()),
+        ])
+        .send()
+        .await?
+        .json::<Value>()
+        .await?;
+    check_ok(&response)?;
+
+    let upload_url = response["upload_url"]
+        .as_str()
+        .ok_or_else(|| make_error("Slack didn't return an upload URL"))?;
+    let id = response["file_id"]
+        .as_str()
+        .ok_or_else(|| make_error("Slack didn't return a file ID"))?
+        .to_string();
+
+    // Big files are streamed, which would otherwise go out chunked
+    let upload = WEB_CLIENT
+        .post(upload_url)
+        .header(CONTENT_LENGTH, &length)
+        .body(body)
+        .send()
+        .await?;
+    if !upload.status().is_success() {
+        return Err(make_error(&format!(
+            "Slack file upload returned {}",
+            upload.status()
+        )));
+    }
+    debug!("Uploaded {} to Slack as {}", attachment.file_name, id);
+
+    Ok(UploadedFile {
+        id,
+        title: attachment.file_name.clone(),
+        is_image: is_image(&attachment.file_name),
+    })
+}
+
+/// Call a Web API method with a JSON body
+async fn call_api(token: &str, method: &str, body: &Value) -> MyResult<Value> {
+    let response = WEB_CLIENT
+        .post(format!("{}/{}", SLACK_API, method))
+        .bearer_auth(token)
+        .json(body)
+        .send()
+        .await?
+        .json::<Value>()
+        .await?;
+    check_ok(&response)?;
+    Ok(response)
+}
+
+/// The Web API always answers 200, with errors flagged in the body
+fn check_ok(response: &Value) -> MyResult<()> {
+    if response["ok"].as_bool() == Some(true) {
+        Ok(())
+    } else {
+        Err(make_error(&format!(
+            "Slack API error: {}",
+            response["error"].as_str().unwrap_or("unknown")
+        )))
+    }
+}
+
+/// Whether Slack can show the file in an image block
+fn is_image(file_name: &str) -> bool {
+    let lower = file_name.to_ascii_lowercase();
+    [".png", ".jpg", ".jpeg", ".gif"]
+        .iter()
+        .any(|ext| lower.ends_with(ext))
+}
+
+/// Escape the characters mrkdwn treats as control characters
+fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Break text into pieces of at most `max_chars`, preferring to break on newlines
+fn split_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+
+    for line in text.split_inclusive('\n') {
+        for c in line.chars() {
+            if current_len == max_chars {
+                chunks.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+            current.push(c);
+            current_len += 1;
+        }
+        // Start a new chunk at the line break if the next line probably won't fit
+        if current_len > max_chars * 3 / 4 {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
@@ -0,0 +1,151 @@
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::{debug, warn};
+
+use crate::{SPOOL_DIR, SPOOL_ROOT};
+
+/// Every process spools into `<SPOOL_DIR>/spool-<pid>`
+const PROCESS_DIR_PREFIX: &str = "spool-";
+
+/// Each spooled file gets its own directory, so the file inside can keep its real name
+static NEXT_SPOOL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A file sitting in the spool directory, which is deleted again once this is dropped
+#[derive(Debug)]
+pub struct SpooledFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl SpooledFile {
+    /// Reserve a fresh path in the spool directory whose file name is `file_name`
+    pub async fn new(file_name: &str) -> io::Result<Self> {
+        let id = NEXT_SPOOL_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = SPOOL_DIR.join(id.to_string());
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let path = dir.join(safe_file_name(file_name));
+        debug!("Spooling to {}", path.display());
+
+        Ok(Self { dir, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Give the file a new name, for sinks that upload it under the name it has on disk
+    pub async fn rename(&mut self, file_name: &str) -> io::Result<()> {
+        let path = self.dir.join(safe_file_name(file_name));
+        tokio::fs::rename(&self.path, &path).await?;
+        self.path = path;
+        Ok(())
+    }
+}
+
+/// Document names come straight from telegram users, so don't let them escape the dir
+fn safe_file_name(file_name: &str) -> String {
+    file_name.replace(['/', '\\'], "_")
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            if err.kind() != ErrorKind::NotFound {
+                warn!(
+                    "Failed to clean up spooled file {}: {}",
+                    self.dir.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// This process's own directory under the configured spool root
+pub fn process_spool_dir(root: &Path) -> PathBuf {
+    root.join(format!("{}{}", PROCESS_DIR_PREFIX, std::process::id()))
+}
+
+/// Remove this process's spool directory, along with ones left behind by processes that are gone
+/// after a crash or an interrupted shutdown. Nothing else under the spool root is touched.
+pub fn clear_spool_dir() {
+    clear_spool_dirs(&SPOOL_ROOT, &SPOOL_DIR);
+}
+
+fn clear_spool_dirs(root: &Path, own: &Path) {
+    remove_spool_dir(own);
+
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) => {
+            warn!("Failed to read spool root {}: {}", root.display(), err);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let pid = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(PROCESS_DIR_PREFIX))
+            .and_then(|pid| pid.parse::<u32>().ok());
+        if let Some(pid) = pid {
+            if !process_is_running(pid) {
+                remove_spool_dir(&entry.path());
+            }
+        }
+    }
+}
+
+/// Whether a process still exists, erring on the side of yes where there's no `/proc` to ask, so a
+/// running process never loses its spool
+pub fn process_is_running(pid: u32) -> bool {
+    !Path::new("/proc/self").exists() || Path::new("/proc").join(pid.to_string()).exists()
+}
+
+fn remove_spool_dir(dir: &Path) {
+    match std::fs::remove_dir_all(dir) {
+        Ok(_) => debug!("Cleared spool directory {}", dir.display()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => warn!("Failed to clear spool directory {}: {}", dir.display(), err),
+    }
+}
+
+/// Hard link `from` to `to` when they're on the same filesystem, and copy it otherwise
+pub async fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if tokio::fs::hard_link(from, to).await.is_err() {
+        tokio::fs::copy(from, to).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clearing_leaves_everything_else_alone() {
+        let root = std::env::temp_dir().join(format!("spool-root-test-{}", std::process::id()));
+        let own = process_spool_dir(&root);
+        // Pids are capped far below this, so it can't be a running process
+        let stale = root.join(format!("{}{}", PROCESS_DIR_PREFIX, u32::MAX));
+        let running = root.join(format!("{}1", PROCESS_DIR_PREFIX));
+        let unrelated = root.join("keep-me");
+        for dir in [&own, &stale, &running, &unrelated] {
+            std::fs::create_dir_all(dir.join("file")).unwrap();
+        }
+
+        clear_spool_dirs(&root, &own);
+
+        assert!(!own.exists());
+        assert!(!stale.exists() || !Path::new("/proc/self").exists());
+        assert!(running.exists());
+        assert!(unrelated.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
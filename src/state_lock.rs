@@ -0,0 +1,115 @@
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+use crate::{spool::process_is_running, types::MyResult, utils::make_error};
+
+/// Held by whichever process writes the state files (message log, digests, held posts), so a
+/// backfill can't run next to the mirror and have the two overwrite each other's copies.
+/// The lock file holds the owner's pid, a lock left behind by a process that's gone is taken over.
+#[derive(Debug)]
+pub struct StateLock {
+    path: PathBuf,
+}
+
+impl StateLock {
+    pub fn acquire(path: &Path) -> MyResult<Self> {
+        // Once to take over a stale lock, once more in case someone else got there first
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    debug!("Took the state lock {}", path.display());
+                    return Ok(StateLock {
+                        path: path.to_path_buf(),
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(Box::new(err)),
+            }
+
+            if let Some(pid) = lock_owner(path) {
+                return Err(make_error(&format!(
+                    "Process {} is using the state files, stop it first or remove {} if it isn't \
+                    this bot",
+                    pid,
+                    path.display()
+                )));
+            }
+            warn!("Taking over the stale state lock {}", path.display());
+            if let Err(err) = std::fs::remove_file(path) {
+                if err.kind() != ErrorKind::NotFound {
+                    return Err(Box::new(err));
+                }
+            }
+        }
+        Err(make_error(&format!(
+            "Couldn't take the state lock {}",
+            path.display()
+        )))
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove the state lock {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// The still running process holding a lock file. A restarted container can get the pid its last
+/// run had, so a lock with our own pid counts as stale.
+fn lock_owner(path: &Path) -> Option<u32> {
+    std::fs::read_to_string(path)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|pid| *pid != std::process::id() && process_is_running(*pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("state-lock-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn only_one_holder_at_a_time() {
+        let path = lock_path("held");
+        // Pid 1 is always running where there's a /proc to ask
+        std::fs::write(&path, "1").unwrap();
+        assert!(StateLock::acquire(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let lock = StateLock::acquire(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let path = lock_path("stale");
+        // Pids are capped far below this, so it can't be a running process
+        std::fs::write(&path, u32::MAX.to_string()).unwrap();
+        let lock = StateLock::acquire(&path);
+        assert!(lock.is_ok() || !Path::new("/proc/self").exists());
+        drop(lock);
+        let _ = std::fs::remove_file(&path);
+    }
+}
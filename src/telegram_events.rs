@@ -1,131 +1,403 @@
-use std::borrow::Borrow;
-use std::io;
-use std::path::Path;
+use std::{collections::VecDeque, sync::Mutex};
 
+use chrono::Utc;
 use log::{debug, warn};
 
-use teloxide::types::Message;
-
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use teloxide::types::{ChatId, Message, MessageEntity, MessageKind as TgMessageKind};
 
 use crate::attachments::{
     get_audio_attachments, get_file_attachments, get_gif_attachments, get_photo_attachments,
     get_sticker_attachments, get_video_attachments,
 };
-use crate::types::{MyResult, TelegramMessageData, UnifiedMessage};
-use crate::utils::send_all_webhooks;
-use crate::CHANNEL_DATA_WEBHOOK;
+use crate::buttons::get_message_buttons;
+use crate::formatting::{entities_to_html, hashtags};
+use crate::media_server::media_server_enabled;
+use crate::message_log::{self, LoggedMessage};
+use crate::routes;
+use crate::schedule;
+use crate::sinks::{send_to_destinations, ReadyMessage};
+use crate::types::{
+    Destination, MessageKind, MessageSource, MyResult, TelegramMessageData, TgChannelData,
+    UnifiedMessage,
+};
+use crate::{DISCORD_MAX_FILE_SIZE, SILENT_HASHTAG};
+
+/// How many album captions to remember, albums arrive all at once so only the latest matter
+const ALBUM_CAPTIONS_KEPT: usize = 64;
+
+lazy_static! {
+    /// Captions of recent albums, so the items without one get filtered like the one that has it
+    static ref ALBUM_CAPTIONS: Mutex<VecDeque<(ChatId, String, String, Vec<MessageEntity>)>> =
+        Mutex::new(VecDeque::new());
+}
 
 /// Parse the text wrote on Telegram and check if that text is a valid command
 /// or not, then match the command. If the command is `/start` it writes a
 /// markup with the `InlineKeyboardMarkup`.
 pub async fn message_handler(m: Message) -> MyResult<()> {
-    // Gets the discord webhook data if the chat is one of the tracked channels
-    let webhook_hashmap = CHANNEL_DATA_WEBHOOK.get().unwrap();
-
-    if let Some(webhook) = webhook_hashmap.get(&m.chat.id) {
-        // Pulls required data off the message
-        // See this: https://docs.rs/teloxide/latest/teloxide/prelude/struct.Message.html
-        let text = if m.text().is_none() {
-            m.caption()
+    // Gets the destinations if the chat is one of the tracked channels
+    if let Some(channel) = routes::channel(m.chat.id) {
+        let filter_text = filter_text(&m);
+        let source = message_source(&m);
+        let destinations = channel.destinations_for(
+            filter_text
+                .as_ref()
+                .map(|(text, entities)| (text.as_str(), entities.as_slice())),
+            &source,
+        );
+
+        // Only download when something is going to use the media. Feeds serve it out of the
+        // cache, so they want every post's, as long as telegram lets it be passed around.
+        let wanted =
+            wants_media(&destinations, &source) || (media_server_enabled() && !source.protected);
+        let message = if !wanted {
+            debug!(
+                "Nothing takes the media of message {}, not downloading it",
+                m.id
+            );
+            text_only_message(&m)
         } else {
-            m.text()
-        };
-        let message_data = TelegramMessageData {
-            text,
-            photos: m.photo(),
-            audio: m.audio(),
-            file: m.document(),
-            sticker: m.sticker(),
-            video: m.video(),
-            gif: m.animation(),
+            ReadyMessage::from_unified(build_unified_message(&m, &channel)).await
         };
 
-        let mut message: UnifiedMessage = UnifiedMessage {
-            attachments: Vec::new(),
-            message_text: text.map(|s| s.to_string()),
-        };
+        // Destinations with a delay or quiet hours get it later on
+        let now = Utc::now();
+        let mut right_away = Vec::new();
+        let mut later = Vec::new();
+        for destination in destinations {
+            match schedule::send_at(destination, now) {
+                Some(at) => later.push((destination, at)),
+                None => right_away.push(destination),
+            }
+        }
 
-        // Generate the photo attachments
-        if let Some(photo_attachment) = get_photo_attachments(&message_data) {
-            message.attachments.extend(photo_attachment.into_iter());
+        // Fire the sinks
+        let (sent, result) = send_to_destinations(&message, &right_away).await;
+        message_log::record(
+            m.chat.id,
+            m.id,
+            LoggedMessage::new(&message, media_ids(&m), sent).await,
+        );
+        for (destination, at) in later {
+            schedule::hold(&message, destination, at).await;
         }
+        result?;
+    }
+    Ok(())
+}
 
-        // Generate the audio attachments
-        match get_audio_attachments(&message_data) {
-            Ok(audio_attachments) => message.attachments.extend(audio_attachments.into_iter()),
-            Err(_) => warn!("Failed to parse audio attachments"),
-        };
-        // Generate the file attachments
-        match get_file_attachments(&message_data) {
-            Ok(file_attachments) => message.attachments.extend(file_attachments.into_iter()),
-            Err(_) => warn!("Failed to parse file attachments"),
-        };
+/// Mirror an edit to a channel post we already sent.
+///
+/// Text edits are applied in place. Sinks that can't swap attachments get the old message
+/// deleted and the new one sent instead when the media changed.
+pub async fn edited_message_handler(m: Message) -> MyResult<()> {
+    let channel = match routes::channel(m.chat.id) {
+        Some(channel) => channel,
+        None => return Ok(()),
+    };
+    let logged = match message_log::lookup(m.chat.id, m.id) {
+        Some(logged) => logged,
+        None => {
+            debug!("Edited message {} was never mirrored, ignoring", m.id);
+            return Ok(());
+        }
+    };
 
-        // Generate the sticker attachments
-        match get_sticker_attachments(&message_data) {
-            Ok(sticker_attachments) => message.attachments.extend(sticker_attachments.into_iter()),
-            Err(_) => warn!("Failed to parse sticker attachments"),
-        };
+    let new_media_ids = media_ids(&m);
+    let media_changed = new_media_ids != logged.media_ids;
 
-        // Generate the video attachments
-        match get_video_attachments(&message_data) {
-            Ok(video_attachments) => message.attachments.extend(video_attachments.into_iter()),
-            Err(_) => warn!("Failed to parse video attachments"),
+    // Only download again if something is going to need the new media, which includes posts that
+    // are still waiting to go out somewhere
+    let held = schedule::is_held(m.chat.id, m.id);
+    let destinations: Vec<&Destination> = channel.destinations.iter().collect();
+    let source = message_source(&m);
+    let message = if (media_changed || held) && wants_media(&destinations, &source) {
+        ReadyMessage::from_unified(build_unified_message(&m, &channel)).await
+    } else {
+        text_only_message(&m)
+    };
+
+    let mut sent_after_edit = Vec::new();
+    // Edits follow the post wherever it went, whatever the filters think of the new version
+    for destination in &channel.destinations {
+        let sink = &destination.sink;
+        let sink_name = sink.name();
+        let previous = match logged.sent.iter().find(|(name, _)| *name == sink_name) {
+            Some((_, previous)) => previous,
+            None => continue,
         };
 
-        // Generate the sticker attachments
-        match get_gif_attachments(&message_data) {
-            Ok(gif_attachments) => message.attachments.extend(gif_attachments.into_iter()),
-            Err(_) => warn!("Failed to parse gif attachments"),
+        let capabilities = sink.capabilities();
+        let outgoing = match message.outgoing_to(destination) {
+            Some(outgoing) => outgoing,
+            None => {
+                debug!(
+                    "Nothing in the edit for {}, keeping the old message",
+                    sink_name
+                );
+                sent_after_edit.push((sink_name, previous.clone()));
+                continue;
+            }
         };
 
-        // Sort to start with the smallest files first
-        message
-            .attachments
-            .sort_by(|a, b| a.file_size.cmp(&b.file_size));
+        let result = if media_changed && !capabilities.edit_attachments {
+            // Replace the whole message, since the attachments can't be swapped in place
+            match sink.delete(previous).await {
+                Ok(_) => sink.send(&outgoing).await,
+                Err(err) => Err(err),
+            }
+        } else {
+            sink.edit(previous, &outgoing)
+                .await
+                .map(|_| previous.clone())
+        };
 
-        // Test save all attachments
-        // _download_attachments(&message).await?;
+        match result {
+            Ok(sent) => sent_after_edit.push((sink_name, sent)),
+            Err(err) => {
+                warn!("Failed to mirror edit to {}: {}", sink_name, err);
+                sent_after_edit.push((sink_name, previous.clone()));
+            }
+        }
+    }
 
-        // Fire the webhooks
-        send_all_webhooks(message, &webhook.webhooks).await?;
+    let mut logged_after_edit = LoggedMessage::new(&message, new_media_ids, sent_after_edit).await;
+    logged_after_edit.updated = m.edit_date().copied().unwrap_or_else(Utc::now);
+    if !media_changed {
+        // Nothing was downloaded, so the media from before still stands
+        logged_after_edit.media = logged.media;
+    }
+    message_log::record(m.chat.id, m.id, logged_after_edit);
+    if held {
+        schedule::update_held(&message).await;
     }
     Ok(())
 }
 
-async fn _download_attachments(message: UnifiedMessage<'_>) -> MyResult<()> {
-    for attachment in message.attachments {
-        let path = Path::new("test_media").join(&attachment.file_name);
-        debug!("Saving file {}", path.display());
-        let mut file = File::create(&path).await?;
-        let file_size = attachment.file_size;
-        let data = attachment.get_file_or_wait().await?;
+/// Whether any of the destinations will take the media of a post, protected posts only keep it
+/// where the policy says to mirror them
+fn wants_media(destinations: &[&Destination], source: &MessageSource) -> bool {
+    destinations
+        .iter()
+        .any(|destination| destination.protected_content.keeps_media(source.protected))
+}
 
-        if let Some(file_size) = file_size {
-            debug!(
-                "Saving attachment: {:?}, downloaded {}/{} bytes",
-                &path,
-                data.len(),
-                file_size
-            );
-        } else {
-            debug!(
-                "Saving attachment: {:?}, downloaded {} bytes",
-                &path,
-                data.len()
-            );
-        }
+/// Everything but the media, for when nothing needs it downloaded
+fn text_only_message(m: &Message) -> ReadyMessage {
+    ReadyMessage {
+        message_text: message_text(m).map(|s| s.to_string()),
+        message_html: message_html(m),
+        attachments: Vec::new(),
+        entities: message_entities(m).to_vec(),
+        buttons: get_message_buttons(m.reply_markup()),
+        source: message_source(m),
+    }
+}
+
+/// The text and entities filters judge a post by. Album items without a caption go by the caption
+/// of the album, or by none at all if it hasn't been seen.
+fn filter_text(m: &Message) -> Option<(String, Vec<MessageEntity>)> {
+    let text = message_text(m).map(|text| (text.to_string(), message_entities(m).to_vec()));
+    let group = match m.media_group_id() {
+        Some(group) => group,
+        None => return Some(text.unwrap_or_default()),
+    };
 
-        if data.len() == 0 {
-            return Err(Box::new(io::Error::new(
-                io::ErrorKind::Other,
-                format!("File `{}` had no length", path.display()),
-            )));
+    let mut captions = ALBUM_CAPTIONS.lock().unwrap();
+    match text {
+        Some((text, entities)) => {
+            if captions.len() >= ALBUM_CAPTIONS_KEPT {
+                captions.pop_front();
+            }
+            captions.push_back((m.chat.id, group.to_string(), text.clone(), entities.clone()));
+            Some((text, entities))
         }
+        None => captions
+            .iter()
+            .rev()
+            .find(|(chat_id, album, _, _)| *chat_id == m.chat.id && album == group)
+            .map(|(_, _, text, entities)| (text.clone(), entities.clone())),
+    }
+}
 
-        file.write_all(data.borrow()).await?;
+fn message_text(m: &Message) -> Option<&str> {
+    if m.text().is_none() {
+        m.caption()
+    } else {
+        m.text()
     }
-    Ok(())
+}
+
+/// Formatting entities of the text or caption, whichever `message_text` picked
+fn message_entities(m: &Message) -> &[MessageEntity] {
+    let entities = if m.text().is_none() {
+        m.caption_entities()
+    } else {
+        m.entities()
+    };
+    entities.unwrap_or_default()
+}
+
+/// The text or caption as HTML, keeping its bold, links and so on
+fn message_html(m: &Message) -> Option<String> {
+    message_text(m).map(|text| entities_to_html(text, message_entities(m)))
+}
+
+fn message_source(m: &Message) -> MessageSource {
+    MessageSource {
+        chat_id: m.chat.id,
+        chat_title: m.chat.title().map(|s| s.to_string()),
+        chat_username: m.chat.username().map(|s| s.to_string()),
+        message_id: m.id,
+        date: m.date,
+        kind: message_kind(m),
+        forwarded: m.forward_date().is_some(),
+        author_signature: m.author_signature().map(|s| s.to_string()),
+        silent: is_silent(m),
+        protected: is_protected(m),
+        edited_at: m.edit_date().copied(),
+    }
+}
+
+/// Telegram doesn't tell bots whether a post was sent without sound, so posts opt in by carrying
+/// `SILENT_HASHTAG`
+fn is_silent(m: &Message) -> bool {
+    let tag = match SILENT_HASHTAG.as_deref() {
+        Some(tag) => tag,
+        None => return false,
+    };
+    let text = message_text(m).unwrap_or_default();
+    hashtags(text, message_entities(m))
+        .iter()
+        .any(|found| found.trim_start_matches('#').eq_ignore_ascii_case(tag))
+}
+
+fn is_protected(m: &Message) -> bool {
+    match &m.kind {
+        TgMessageKind::Common(common) => common.has_protected_content,
+        _ => false,
+    }
+}
+
+fn message_kind(m: &Message) -> MessageKind {
+    if m.photo().is_some() {
+        MessageKind::Photo
+    } else if m.video().is_some() {
+        MessageKind::Video
+    } else if m.animation().is_some() {
+        MessageKind::Animation
+    } else if m.audio().is_some() {
+        MessageKind::Audio
+    } else if m.voice().is_some() {
+        MessageKind::Voice
+    } else if m.sticker().is_some() {
+        MessageKind::Sticker
+    } else if m.document().is_some() {
+        MessageKind::Document
+    } else if m.text().is_some() {
+        MessageKind::Text
+    } else {
+        MessageKind::Other
+    }
+}
+
+/// Unique IDs of the media on a message, in a stable order
+fn media_ids(m: &Message) -> Vec<String> {
+    let mut ids = Vec::new();
+    if let Some(photo) = m.photo().and_then(|photos| photos.last()) {
+        ids.push(photo.file_unique_id.clone());
+    }
+    if let Some(audio) = m.audio() {
+        ids.push(audio.file_unique_id.clone());
+    }
+    if let Some(file) = m.document() {
+        ids.push(file.file_unique_id.clone());
+    }
+    if let Some(sticker) = m.sticker() {
+        ids.push(sticker.file_unique_id.clone());
+    }
+    if let Some(video) = m.video() {
+        ids.push(video.file_unique_id.clone());
+    }
+    if let Some(gif) = m.animation() {
+        ids.push(gif.file_unique_id.clone());
+    }
+    ids
+}
+
+/// Pull everything we mirror off a telegram message, starting the attachment downloads
+fn build_unified_message(m: &Message, channel: &TgChannelData) -> UnifiedMessage {
+    // Pulls required data off the message
+    // See this: https://docs.rs/teloxide/latest/teloxide/prelude/struct.Message.html
+    let text = message_text(m);
+    let message_data = TelegramMessageData {
+        text,
+        photos: m.photo(),
+        audio: m.audio(),
+        file: m.document(),
+        sticker: m.sticker(),
+        video: m.video(),
+        gif: m.animation(),
+        reply_markup: m.reply_markup(),
+    };
+
+    let mut message: UnifiedMessage = UnifiedMessage {
+        attachments: Vec::new(),
+        message_text: text.map(|s| s.to_string()),
+        message_html: message_html(m),
+        entities: message_entities(m).to_vec(),
+        buttons: get_message_buttons(message_data.reply_markup),
+        source: message_source(m),
+    };
+
+    // Generate the audio attachments
+    match get_audio_attachments(&message_data) {
+        Ok(audio_attachments) => message.attachments.extend(audio_attachments.into_iter()),
+        Err(_) => warn!("Failed to parse audio attachments"),
+    };
+    // Generate the file attachments
+    match get_file_attachments(&message_data) {
+        Ok(file_attachments) => message.attachments.extend(file_attachments.into_iter()),
+        Err(_) => warn!("Failed to parse file attachments"),
+    };
+
+    // Generate the sticker attachments
+    match get_sticker_attachments(&message_data) {
+        Ok(sticker_attachments) => message.attachments.extend(sticker_attachments.into_iter()),
+        Err(_) => warn!("Failed to parse sticker attachments"),
+    };
+
+    // Generate the video attachments
+    match get_video_attachments(&message_data) {
+        Ok(video_attachments) => message.attachments.extend(video_attachments.into_iter()),
+        Err(_) => warn!("Failed to parse video attachments"),
+    };
+
+    // Generate the sticker attachments
+    match get_gif_attachments(&message_data) {
+        Ok(gif_attachments) => message.attachments.extend(gif_attachments.into_iter()),
+        Err(_) => warn!("Failed to parse gif attachments"),
+    };
+
+    // Generate the photo attachments last, so the size can be picked to fit around the rest
+    let used_budget: u64 = message
+        .attachments
+        .iter()
+        .filter_map(|attachment| attachment.file_size)
+        .map(|size| size as u64)
+        .sum();
+    if let Some(photo_attachment) = get_photo_attachments(
+        &message_data,
+        channel.photo_size,
+        DISCORD_MAX_FILE_SIZE.saturating_sub(used_budget),
+    ) {
+        message.attachments.extend(photo_attachment.into_iter());
+    }
+
+    // Sort to start with the smallest files first
+    message
+        .attachments
+        .sort_by(|a, b| a.file_size.cmp(&b.file_size));
+
+    message
 }
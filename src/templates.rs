@@ -0,0 +1,286 @@
+use std::str::FromStr;
+
+use teloxide::types::ChatId;
+
+use crate::{
+    formatting::{entities_to_discord_markdown, escape_discord_markdown, hashtags},
+    sinks::OutgoingMessage,
+    types::{BoxedError, MessageSource},
+    utils::make_error,
+};
+
+/// How a route lays out the posts it mirrors.
+///
+/// `{{name}}` is replaced with a variable, and `{{#if name}}...{{else}}...{{/if}}` only keeps
+/// one side depending on whether the variable is empty. `\n` starts a new line. Variables are:
+///
+/// - `channel`: title of the telegram chat
+/// - `link`: link to the post on telegram, empty for private groups
+/// - `signature`: who posted it, in channels that sign their posts
+/// - `date`: when it was posted, as a Discord timestamp so everyone sees their own time zone
+/// - `text`: the text as it was written, without any formatting
+/// - `formatted`: the text with its bold, links and so on as Discord markdown
+/// - `hashtags`: the post's hashtags, separated by spaces
+///
+/// `channel`, `signature` and `text` are escaped, so they show up as written instead of being
+/// read as Discord markdown. Nothing can be run from a template, and unknown variables are
+/// rejected when it's parsed.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+    If {
+        variable: Variable,
+        then: Vec<Part>,
+        otherwise: Vec<Part>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    Channel,
+    Link,
+    Signature,
+    Date,
+    Text,
+    Formatted,
+    Hashtags,
+}
+
+impl FromStr for Variable {
+    type Err = BoxedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "channel" => Ok(Variable::Channel),
+            "link" => Ok(Variable::Link),
+            "signature" => Ok(Variable::Signature),
+            "date" => Ok(Variable::Date),
+            "text" => Ok(Variable::Text),
+            "formatted" => Ok(Variable::Formatted),
+            "hashtags" => Ok(Variable::Hashtags),
+            _ => Err(make_error(&format!("Unknown template variable `{}`", s))),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = BoxedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        let (parts, end) = parse_parts(&mut rest)?;
+        match end {
+            None => Ok(Template { parts }),
+            Some(tag) => Err(make_error(&format!("Unexpected `{{{{{}}}}}`", tag))),
+        }
+    }
+}
+
+/// Parse until the end of the template or an `else`/`/if` tag, which is returned
+fn parse_parts<'a>(rest: &mut &'a str) -> Result<(Vec<Part>, Option<&'a str>), BoxedError> {
+    let mut parts = Vec::new();
+
+    loop {
+        let current: &'a str = *rest;
+        let start = match current.find("{{") {
+            Some(start) => start,
+            None => {
+                push_literal(&mut parts, current);
+                *rest = "";
+                return Ok((parts, None));
+            }
+        };
+        push_literal(&mut parts, &current[..start]);
+        let end = current[start..]
+            .find("}}")
+            .ok_or_else(|| make_error("A `{{` in the template is never closed"))?;
+        let tag = current[start + 2..start + end].trim();
+        *rest = &current[start + end + 2..];
+
+        if tag == "else" || tag == "/if" {
+            return Ok((parts, Some(tag)));
+        }
+        if let Some(name) = tag.strip_prefix("#if ") {
+            let variable = name.parse()?;
+            let (then, end) = parse_parts(rest)?;
+            let otherwise = match end {
+                Some("else") => match parse_parts(rest)? {
+                    (otherwise, Some("/if")) => otherwise,
+                    _ => return Err(make_error("An `{{else}}` is missing its `{{/if}}`")),
+                },
+                Some("/if") => Vec::new(),
+                _ => return Err(make_error("An `{{#if}}` is missing its `{{/if}}`")),
+            };
+            parts.push(Part::If {
+                variable,
+                then,
+                otherwise,
+            });
+        } else {
+            parts.push(Part::Variable(tag.parse()?));
+        }
+    }
+}
+
+fn push_literal(parts: &mut Vec<Part>, literal: &str) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal.replace("\\n", "\n")));
+    }
+}
+
+impl Template {
+    pub fn render(&self, message: &OutgoingMessage<'_>) -> String {
+        let mut out = String::new();
+        render_parts(&self.parts, message, &mut out);
+        out
+    }
+}
+
+fn render_parts(parts: &[Part], message: &OutgoingMessage<'_>, out: &mut String) {
+    for part in parts {
+        match part {
+            Part::Literal(literal) => out.push_str(literal),
+            Part::Variable(variable) => out.push_str(&value(*variable, message)),
+            Part::If {
+                variable,
+                then,
+                otherwise,
+            } => {
+                if value(*variable, message).trim().is_empty() {
+                    render_parts(otherwise, message, out);
+                } else {
+                    render_parts(then, message, out);
+                }
+            }
+        }
+    }
+}
+
+fn value(variable: Variable, message: &OutgoingMessage<'_>) -> String {
+    let source = message.source;
+    let text = message.text.as_deref().unwrap_or_default();
+    match variable {
+        Variable::Channel => {
+            escape_discord_markdown(source.chat_title.as_deref().unwrap_or_default())
+        }
+        Variable::Link => post_link(source).unwrap_or_default(),
+        Variable::Signature => {
+            escape_discord_markdown(source.author_signature.as_deref().unwrap_or_default())
+        }
+        Variable::Date => format!("<t:{}:f>", source.date.timestamp()),
+        Variable::Text => escape_discord_markdown(text),
+        Variable::Formatted => entities_to_discord_markdown(text, message.entities),
+        Variable::Hashtags => hashtags(text, message.entities).join(" "),
+    }
+}
+
+/// Link to a post, public chats go by username and private channels by their ID
+pub fn post_link(source: &MessageSource) -> Option<String> {
+    if let Some(username) = &source.chat_username {
+        return Some(format!("https://t.me/{}/{}", username, source.message_id));
+    }
+    // Channel and supergroup IDs are the internal ID with -100 in front
+    let ChatId(chat_id) = source.chat_id;
+    chat_id
+        .to_string()
+        .strip_prefix("-100")
+        .map(|internal_id| format!("https://t.me/c/{}/{}", internal_id, source.message_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use teloxide::types::{MessageEntity, MessageEntityKind};
+
+    use super::*;
+    use crate::{buttons::MessageButtons, types::MessageKind};
+
+    fn source() -> MessageSource {
+        MessageSource {
+            chat_id: ChatId(-1001234),
+            chat_title: Some("*News* [daily]".to_string()),
+            chat_username: None,
+            message_id: 42,
+            date: Utc.timestamp(1_600_000_000, 0),
+            kind: MessageKind::Text,
+            forwarded: false,
+            author_signature: Some("_Ziah_".to_string()),
+            silent: false,
+            protected: false,
+            edited_at: None,
+        }
+    }
+
+    fn render(
+        template: &str,
+        text: &str,
+        entities: &[MessageEntity],
+        source: &MessageSource,
+    ) -> String {
+        let template: Template = template.parse().unwrap();
+        let buttons = MessageButtons::default();
+        template.render(&OutgoingMessage {
+            text: Some(text.to_string()),
+            html: None,
+            attachments: Vec::new(),
+            entities,
+            buttons: &buttons,
+            source,
+        })
+    }
+
+    #[test]
+    fn rejects_broken_templates() {
+        for bad in [
+            "{{nope}}",
+            "{{text",
+            "{{#if text}}unclosed",
+            "{{#if text}}a{{else}}b",
+            "stray {{/if}}",
+            "stray {{else}}",
+            "{{#if nope}}a{{/if}}",
+        ] {
+            assert!(bad.parse::<Template>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn fills_in_variables() {
+        let bold = [MessageEntity::new(MessageEntityKind::Bold, 0, 5)];
+        let rendered = render(
+            "{{channel}} by {{signature}} at {{date}}\\n{{text}} / {{formatted}}\\n{{link}}",
+            "Hello [world] #tag",
+            &bold,
+            &source(),
+        );
+        assert_eq!(
+            rendered,
+            "\\*News\\* \\[daily\\] by \\_Ziah\\_ at <t:1600000000:f>\n\
+            Hello \\[world\\] \\#tag / **Hello** \\[world\\] \\#tag\n\
+            https://t.me/c/1234/42"
+        );
+    }
+
+    #[test]
+    fn picks_a_side_of_if() {
+        let template = "{{#if signature}}by {{signature}}{{else}}unsigned{{/if}}: {{hashtags}}";
+        let tags = [MessageEntity::new(MessageEntityKind::Hashtag, 3, 4)];
+        let mut unsigned = source();
+        unsigned.author_signature = None;
+
+        assert_eq!(
+            render(template, "hi #one", &tags, &source()),
+            "by \\_Ziah\\_: #one"
+        );
+        assert_eq!(
+            render(template, "hi #one", &tags, &unsigned),
+            "unsigned: #one"
+        );
+    }
+}
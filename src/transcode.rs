@@ -0,0 +1,248 @@
+use std::{path::Path, process::Stdio, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::{process::Command, sync::OnceCell, time::timeout};
+
+use crate::{
+    env_flag, env_or,
+    media_cache::{cache_get, cache_put},
+    spool::SpooledFile,
+    types::{DownloadedAttachment, FileData, MyResult},
+    utils::{file_stem, make_error},
+    DISCORD_MAX_FILE_SIZE,
+};
+
+/// Cache variant for videos that were shrunk to fit under the upload limit
+const DISCORD_VIDEO_VARIANT: &str = "discord-video";
+/// Bitrate given to the audio track, in kbit/s
+const AUDIO_KBPS: u64 = 96;
+/// Below this there's no point in trying, the result would be unwatchable
+const MIN_VIDEO_KBPS: u64 = 150;
+
+lazy_static! {
+    static ref TRANSCODE_VIDEOS: bool = env_flag("TRANSCODE_VIDEOS");
+    static ref FFMPEG_PATH: String =
+        std::env::var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string());
+    static ref TRANSCODE_TIMEOUT: Duration =
+        Duration::from_secs(env_or("TRANSCODE_TIMEOUT_SECS", 300) as u64);
+    /// Checked once, so a missing ffmpeg only gets complained about the first time
+    static ref FFMPEG_AVAILABLE: OnceCell<bool> = OnceCell::new();
+}
+
+async fn ffmpeg_available() -> bool {
+    *FFMPEG_AVAILABLE
+        .get_or_init(|| async {
+            let available = Command::new(&*FFMPEG_PATH)
+                .arg("-version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .await
+                .map(|status| status.success())
+                .unwrap_or(false);
+            if !available {
+                warn!(
+                    "TRANSCODE_VIDEOS is set but `{}` couldn't be run, videos won't be shrunk",
+                    *FFMPEG_PATH
+                );
+            }
+            available
+        })
+        .await
+}
+
+/// Re-encode a video that's over the Discord upload limit so it fits.
+///
+/// Anything going wrong just hands the original back, leaving it to the oversize policy.
+pub async fn shrink_video(
+    attachment: DownloadedAttachment,
+    file_unique_id: &str,
+    duration: u32,
+    width: u32,
+    height: u32,
+) -> DownloadedAttachment {
+    let size = match attachment.data.size() {
+        Ok(size) => size,
+        Err(_) => return attachment,
+    };
+
+    if size <= *DISCORD_MAX_FILE_SIZE || !*TRANSCODE_VIDEOS || !ffmpeg_available().await {
+        return attachment;
+    }
+
+    let file_name = format!("{}.mp4", file_stem(&attachment.file_name));
+
+    if let Some(data) = cache_get(file_unique_id, Some(DISCORD_VIDEO_VARIANT), &file_name).await {
+        return DownloadedAttachment {
+            file_name,
+            file_unique_id: file_unique_id.to_string(),
+            data,
+            original: Some(Box::new(attachment)),
+        };
+    }
+
+    match transcode(&attachment.data, &file_name, duration, width, height).await {
+        Ok(data) => {
+            cache_put(file_unique_id, Some(DISCORD_VIDEO_VARIANT), &data).await;
+            DownloadedAttachment {
+                file_name,
+                file_unique_id: file_unique_id.to_string(),
+                data,
+                original: Some(Box::new(attachment)),
+            }
+        }
+        Err(err) => {
+            warn!("Failed to shrink video {}: {}", attachment.file_name, err);
+            attachment
+        }
+    }
+}
+
+async fn transcode(
+    input: &FileData,
+    file_name: &str,
+    duration: u32,
+    width: u32,
+    height: u32,
+) -> MyResult<FileData> {
+    // ffmpeg wants a file to read, so spool the input out if it's only in memory
+    let spooled_input;
+    let input_path = match input {
+        FileData::Spooled(file) => file.path(),
+        FileData::Memory(data, _) => {
+            spooled_input = SpooledFile::new("input").await?;
+            tokio::fs::write(spooled_input.path(), data).await?;
+            spooled_input.path()
+        }
+    };
+
+    let (video_kbps, max_height) = pick_encoding(duration, *DISCORD_MAX_FILE_SIZE)
+        .ok_or_else(|| make_error("Video is too long to fit under the upload limit"))?;
+    // Never upscale, only cap the height
+    let target_height = if height == 0 {
+        max_height
+    } else {
+        max_height.min(height)
+    };
+    info!(
+        "Shrinking {}x{} video to {}p at {} kbit/s",
+        width, height, target_height, video_kbps
+    );
+
+    let output = SpooledFile::new(file_name).await?;
+    run_ffmpeg(input_path, output.path(), video_kbps, target_height).await?;
+
+    let output = FileData::Spooled(output);
+    let output_size = output.size()?;
+    if output_size > *DISCORD_MAX_FILE_SIZE {
+        return Err(make_error(&format!(
+            "Transcoded video is still {} bytes",
+            output_size
+        )));
+    }
+    debug!("Shrunk video to {} bytes", output_size);
+    Ok(output)
+}
+
+/// Work out the video bitrate that lands the whole file under `max_bytes`, and the resolution that
+/// bitrate can reasonably carry
+fn pick_encoding(duration: u32, max_bytes: u64) -> Option<(u64, u32)> {
+    // Leave some room for the container and rate control overshoot
+    let budget_kbit = max_bytes * 8 / 1000 * 90 / 100;
+    let total_kbps = budget_kbit / duration.max(1) as u64;
+    let video_kbps = total_kbps.checked_sub(AUDIO_KBPS)?;
+
+    if video_kbps < MIN_VIDEO_KBPS {
+        return None;
+    }
+
+    let max_height = match video_kbps {
+        kbps if kbps >= 2500 => 1080,
+        kbps if kbps >= 1200 => 720,
+        kbps if kbps >= 600 => 480,
+        _ => 360,
+    };
+    Some((video_kbps, max_height))
+}
+
+async fn run_ffmpeg(input: &Path, output: &Path, video_kbps: u64, height: u32) -> MyResult<()> {
+    let mut child = Command::new(&*FFMPEG_PATH)
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(input)
+        .args(["-c:v", "libx264", "-preset", "veryfast"])
+        .arg("-b:v")
+        .arg(format!("{}k", video_kbps))
+        .arg("-maxrate")
+        .arg(format!("{}k", video_kbps))
+        .arg("-bufsize")
+        .arg(format!("{}k", video_kbps * 2))
+        // -2 keeps the aspect ratio while making the width even, which x264 needs
+        .arg("-vf")
+        .arg(format!("scale=-2:{}", height - height % 2))
+        .args(["-c:a", "aac"])
+        .arg("-b:a")
+        .arg(format!("{}k", AUDIO_KBPS))
+        .args(["-movflags", "+faststart"])
+        .arg(output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let status = match timeout(*TRANSCODE_TIMEOUT, child.wait()).await {
+        Ok(status) => status?,
+        Err(_) => {
+            // Dropping the child kills it, but don't leave it running until then
+            child.kill().await.ok();
+            return Err(make_error("ffmpeg timed out"));
+        }
+    };
+
+    if !status.success() {
+        return Err(make_error(&format!("ffmpeg exited with {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EIGHT_MIB: u64 = 8 * 1024 * 1024;
+
+    #[test]
+    fn lower_bitrates_get_lower_resolutions() {
+        assert_eq!(pick_encoding(10, EIGHT_MIB), Some((5943, 1080)));
+        assert_eq!(pick_encoding(30, EIGHT_MIB), Some((1917, 720)));
+        assert_eq!(pick_encoding(60, EIGHT_MIB), Some((910, 480)));
+        assert_eq!(pick_encoding(120, EIGHT_MIB), Some((407, 360)));
+        assert_eq!(pick_encoding(240, EIGHT_MIB), Some((155, 360)));
+    }
+
+    #[test]
+    fn encodings_fit_under_the_limit() {
+        for duration in [1, 5, 10, 45, 90, 200] {
+            for max_bytes in [EIGHT_MIB, 25 * 1024 * 1024, 100 * 1024 * 1024] {
+                if let Some((video_kbps, _)) = pick_encoding(duration, max_bytes) {
+                    let bytes = (video_kbps + AUDIO_KBPS) * duration as u64 * 1000 / 8;
+                    assert!(bytes <= max_bytes, "{}s into {} bytes", duration, max_bytes);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn too_long_videos_are_given_up_on() {
+        // Under the minimum video bitrate
+        assert_eq!(pick_encoding(300, EIGHT_MIB), None);
+        // Not even enough for the audio
+        assert_eq!(pick_encoding(600, EIGHT_MIB), None);
+        // A bigger limit buys more time
+        assert!(pick_encoding(300, 25 * 1024 * 1024).is_some());
+    }
+
+    #[test]
+    fn unknown_durations_count_as_one_second() {
+        assert_eq!(pick_encoding(0, EIGHT_MIB), pick_encoding(1, EIGHT_MIB));
+    }
+}
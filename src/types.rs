@@ -1,21 +1,160 @@
-use std::{borrow::Cow, error::Error as DynError};
+use std::{borrow::Cow, error::Error as DynError, str::FromStr, sync::Arc, time::Duration};
 
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serenity::model::{channel::AttachmentType, webhook::Webhook};
-use teloxide::types::{Animation, Audio, ChatId, Document, PhotoSize, Sticker, Video};
-use tokio::task::{self, JoinHandle};
+use teloxide::types::{
+    Animation, Audio, ChatId, Document, InlineKeyboardMarkup, MessageEntity, PhotoSize, Sticker,
+    Video,
+};
+use tokio::{
+    sync::OwnedSemaphorePermit,
+    task::{self, JoinHandle},
+};
 
-use crate::utils::download_file;
+use crate::{
+    buttons::{CallbackButtonPolicy, MessageButtons},
+    digest::DigestPolicy,
+    file_types::resolve_sniffed_extension,
+    filters::RouteFilter,
+    images::shrink_image,
+    pings::PingRules,
+    schedule::QuietHours,
+    sinks::Sink,
+    spool::SpooledFile,
+    templates::Template,
+    transcode::shrink_video,
+    utils::{download_file, make_error},
+};
 
 #[derive(Debug)]
 pub struct WebhookData {
     pub raw_webhook: Webhook,
     pub icon_url: String,
     pub webhook_username: String,
+    /// How to render inline keyboard buttons that can't become Discord link buttons
+    pub callback_buttons: CallbackButtonPolicy,
+    /// How to lay out the content, it's just the text without one
+    pub template: Option<Template>,
+    /// Who to ping for which posts
+    pub pings: PingRules,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TgChannelData {
-    pub webhooks: Vec<WebhookData>,
+    /// Everywhere posts from this chat get mirrored to
+    pub destinations: Vec<Destination>,
     pub chat_id: ChatId,
+    /// Which of telegram's sizes of a photo to mirror
+    pub photo_size: PhotoSizePolicy,
+}
+
+impl TgChannelData {
+    /// The destinations whose filters let a post through, see `RouteFilter::allows`
+    pub fn destinations_for(
+        &self,
+        text: Option<(&str, &[MessageEntity])>,
+        source: &MessageSource,
+    ) -> Vec<&Destination> {
+        self.destinations
+            .iter()
+            .filter(|destination| destination.filter.allows(text, source))
+            .collect()
+    }
+}
+
+/// A sink a chat is mirrored to, along with which of the chat's posts it gets
+#[derive(Debug, Clone)]
+pub struct Destination {
+    pub sink: Arc<dyn Sink>,
+    pub filter: RouteFilter,
+    /// What to do with posts that have forwarding and saving turned off
+    pub protected_content: ProtectedContentPolicy,
+    /// Collect posts into digests instead of sending them one by one
+    pub digest: Option<DigestPolicy>,
+    /// When posts are held back until later
+    pub quiet: Option<QuietHours>,
+    /// How long posts wait before going out, so typos can still be fixed
+    pub delay: Option<Duration>,
+}
+
+impl Destination {
+    /// A destination that gets every post
+    pub fn new(sink: Arc<dyn Sink>) -> Self {
+        Self {
+            sink,
+            filter: RouteFilter::default(),
+            protected_content: ProtectedContentPolicy::default(),
+            digest: None,
+            quiet: None,
+            delay: None,
+        }
+    }
+}
+
+/// What to do with posts telegram marks as protected content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProtectedContentPolicy {
+    /// Mirror them like any other post, which is what happened before protection was looked at
+    Mirror,
+    /// Mirror the text, leaving the media behind
+    TextOnly,
+    /// Don't mirror them at all
+    Skip,
+}
+
+impl Default for ProtectedContentPolicy {
+    fn default() -> Self {
+        ProtectedContentPolicy::Mirror
+    }
+}
+
+impl ProtectedContentPolicy {
+    /// Whether posts with this policy keep their media
+    pub fn keeps_media(self, protected: bool) -> bool {
+        !protected || self == ProtectedContentPolicy::Mirror
+    }
+}
+
+impl FromStr for ProtectedContentPolicy {
+    type Err = BoxedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mirror" => Ok(ProtectedContentPolicy::Mirror),
+            "skip" => Ok(ProtectedContentPolicy::Skip),
+            "text-only" => Ok(ProtectedContentPolicy::TextOnly),
+            _ => Err(make_error(&format!(
+                "Unknown protected content policy `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+/// How to choose between the sizes telegram offers for a photo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhotoSizePolicy {
+    /// Always the biggest one
+    Largest,
+    /// The biggest one that fits in what's left of the upload limit after the other attachments
+    FitBudget,
+    /// The biggest one with neither side over this many pixels
+    MaxDimension(u32),
+}
+
+impl FromStr for PhotoSizePolicy {
+    type Err = BoxedError;
+
+    /// Parses `largest`, `fit-budget` or `max-dimension:<pixels>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "largest" => Ok(PhotoSizePolicy::Largest),
+            None if s == "fit-budget" => Ok(PhotoSizePolicy::FitBudget),
+            Some(("max-dimension", pixels)) => Ok(PhotoSizePolicy::MaxDimension(pixels.parse()?)),
+            _ => Err(make_error(&format!("Unknown photo size policy `{}`", s))),
+        }
+    }
 }
 
 /// Data comes from https://docs.rs/teloxide/latest/teloxide/types/struct.MessageEntity.html
@@ -29,54 +168,234 @@ pub struct TelegramMessageData<'a> {
     pub sticker: Option<&'a Sticker>,
     pub video: Option<&'a Video>,
     pub gif: Option<&'a Animation>,
+    pub reply_markup: Option<&'a InlineKeyboardMarkup>,
+}
+
+/// The contents of a downloaded file, small ones stay in memory and big ones get spooled to disk
+#[derive(Debug)]
+pub enum FileData {
+    /// The bytes, and the `DOWNLOAD_BYTES` permits they were downloaded under, so the memory they
+    /// take up counts against the limit for as long as they're around
+    Memory(Vec<u8>, Option<OwnedSemaphorePermit>),
+    Spooled(SpooledFile),
+}
+
+impl FileData {
+    /// Size of the data in bytes
+    pub fn size(&self) -> MyResult<u64> {
+        match self {
+            FileData::Memory(data, _) => Ok(data.len() as u64),
+            FileData::Spooled(file) => Ok(std::fs::metadata(file.path())?.len()),
+        }
+    }
 }
 
-pub type InMemoryFile<'a> = Cow<'a, [u8]>;
+/// What to do with a file that's still too big for Discord after any processing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Leave it off the message
+    Skip,
+    /// Send it anyway and let Discord decide, for servers with boosted upload limits
+    Send,
+}
+
+/// Extra work to do on a file once it's downloaded
+#[derive(Debug, Clone, Copy)]
+pub enum PostProcess {
+    None,
+    /// Re-encode the video if it's over the upload limit
+    Video {
+        duration: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Recompress the image if it's over the upload limit, and strip its metadata
+    Image,
+}
 
 pub type BoxedError = Box<dyn DynError + Send + Sync>;
 pub type MyResult<T> = Result<T, BoxedError>;
 
-pub struct Attachment<'a> {
+pub struct Attachment {
     pub file_name: String,
     pub file_id: String,
-    pub file_data: JoinHandle<MyResult<InMemoryFile<'a>>>,
+    pub file_unique_id: String,
+    pub file_data: JoinHandle<MyResult<DownloadedAttachment>>,
     pub file_size: Option<u32>,
 }
 
-pub struct UnifiedMessage<'a> {
-    pub attachments: Vec<Attachment<'a>>,
+/// An attachment whose download has finished
+#[derive(Debug)]
+pub struct DownloadedAttachment {
+    pub file_name: String,
+    pub file_unique_id: String,
+    pub data: FileData,
+    /// The file as it was downloaded, when post-processing replaced it with a smaller one
+    pub original: Option<Box<DownloadedAttachment>>,
+}
+
+pub struct UnifiedMessage {
+    pub attachments: Vec<Attachment>,
     pub message_text: Option<String>,
+    /// The text as HTML, with telegram's formatting entities applied
+    pub message_html: Option<String>,
+    /// Telegram's formatting entities for the text
+    pub entities: Vec<MessageEntity>,
+    pub buttons: MessageButtons,
+    pub source: MessageSource,
+}
+
+/// Where on telegram a message was posted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageSource {
+    pub chat_id: ChatId,
+    pub chat_title: Option<String>,
+    pub chat_username: Option<String>,
+    pub message_id: i32,
+    pub date: DateTime<Utc>,
+    pub kind: MessageKind,
+    /// Whether the post was forwarded from somewhere else
+    pub forwarded: bool,
+    /// Who posted it, in channels that sign their posts
+    pub author_signature: Option<String>,
+    /// Whether it should go out without notifying anyone
+    pub silent: bool,
+    /// Whether telegram has forwarding and saving turned off for it
+    pub protected: bool,
+    /// When it was last edited on telegram, if it ever was
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+/// What sort of post a message is, going by its main content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Text,
+    Photo,
+    Video,
+    Animation,
+    Audio,
+    Voice,
+    Document,
+    Sticker,
+    Other,
+}
+
+impl MessageKind {
+    pub fn has_media(self) -> bool {
+        !matches!(self, MessageKind::Text | MessageKind::Other)
+    }
+}
+
+impl FromStr for MessageKind {
+    type Err = BoxedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(MessageKind::Text),
+            "photo" => Ok(MessageKind::Photo),
+            "video" => Ok(MessageKind::Video),
+            "animation" | "gif" => Ok(MessageKind::Animation),
+            "audio" => Ok(MessageKind::Audio),
+            "voice" => Ok(MessageKind::Voice),
+            "document" | "file" => Ok(MessageKind::Document),
+            "sticker" => Ok(MessageKind::Sticker),
+            "other" => Ok(MessageKind::Other),
+            _ => Err(make_error(&format!("Unknown message kind `{}`", s))),
+        }
+    }
 }
-impl<'a> Attachment<'a> {
+impl Attachment {
     // Create a new file, starting the download but not joining it so we can download while doing other things.
-    pub fn new(file_name: String, file_id: String, file_size: Option<u32>) -> Self {
+    pub fn new(
+        file_name: String,
+        file_id: String,
+        file_unique_id: String,
+        file_size: Option<u32>,
+    ) -> Self {
+        Self::with_post_process(
+            file_name,
+            file_id,
+            file_unique_id,
+            file_size,
+            PostProcess::None,
+        )
+    }
+
+    /// Same as `new`, but also runs `post_process` on the file in the background once it's downloaded
+    pub fn with_post_process(
+        file_name: String,
+        file_id: String,
+        file_unique_id: String,
+        file_size: Option<u32>,
+        post_process: PostProcess,
+    ) -> Self {
         let cloned_file_id = file_id.clone();
-        let future = task::spawn(async move { download_file(cloned_file_id, file_size).await });
+        let cloned_file_unique_id = file_unique_id.clone();
+        let cloned_file_name = file_name.clone();
+        let future = task::spawn(async move {
+            let data = download_file(
+                cloned_file_id,
+                cloned_file_unique_id.clone(),
+                cloned_file_name.clone(),
+                file_size,
+            )
+            .await?;
+
+            let attachment = resolve_sniffed_extension(DownloadedAttachment {
+                file_name: cloned_file_name,
+                file_unique_id: cloned_file_unique_id.clone(),
+                data,
+                original: None,
+            })
+            .await;
+
+            match post_process {
+                PostProcess::None => Ok(attachment),
+                PostProcess::Video {
+                    duration,
+                    width,
+                    height,
+                } => Ok(
+                    shrink_video(attachment, &cloned_file_unique_id, duration, width, height).await,
+                ),
+                PostProcess::Image => Ok(shrink_image(attachment, &cloned_file_unique_id).await),
+            }
+        });
 
         Self {
             file_name,
             file_id,
+            file_unique_id,
             file_size,
             file_data: future,
         }
     }
 
-    pub async fn to_discord_attachment(self: Attachment<'a>) -> MyResult<AttachmentType<'a>> {
-        Ok(AttachmentType::Bytes {
-            filename: self.file_name.clone(),
-            data: self.get_file_or_wait().await?,
-        })
-    }
-
-    pub async fn get_file_or_wait(self) -> MyResult<Cow<'a, [u8]>> {
+    /// Wait for the download and any post-processing to finish
+    pub async fn download(self) -> MyResult<DownloadedAttachment> {
         let task_result = self.file_data.await;
 
         match task_result {
             Ok(request_result) => match request_result {
-                Ok(file_data) => Ok(file_data),
+                Ok(attachment) => Ok(attachment),
                 Err(err) => Err(err),
             },
             Err(e) => Err(Box::new(e)),
         }
     }
 }
+
+impl DownloadedAttachment {
+    /// Borrow the data as something serenity can upload. Spooled files are handed over by path,
+    /// so they're only read back in when the webhook actually goes out.
+    pub fn to_discord_attachment(&self) -> AttachmentType<'_> {
+        match &self.data {
+            FileData::Memory(data, _) => AttachmentType::Bytes {
+                data: Cow::Borrowed(data),
+                filename: self.file_name.clone(),
+            },
+            FileData::Spooled(file) => AttachmentType::Path(file.path()),
+        }
+    }
+}
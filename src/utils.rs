@@ -1,32 +1,97 @@
-use std::{borrow::Cow, error::Error, io};
+use std::{error::Error, io, path::Path, sync::Arc, time::Duration};
 
-use futures::future;
-use log::{debug, info, warn};
-use serenity::{
-    http,
-    model::{channel::AttachmentType, webhook::Webhook},
-};
+use log::{debug, warn};
+use serde::Serialize;
+use serenity::model::webhook::Webhook;
 use teloxide::{net::Download, prelude::*};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Notify};
+use tokio_util::io::ReaderStream;
 
 use crate::{
-    types::{InMemoryFile, UnifiedMessage, WebhookData},
-    BOT, HTTP,
+    media_cache::{cache_get, cache_put},
+    spool::{link_or_copy, SpooledFile},
+    types::{FileData, MyResult},
+    BOT, DOWNLOAD_BYTES, DOWNLOAD_SLOTS, HTTP, MAX_DOWNLOAD_KIB_IN_FLIGHT, SPOOL_THRESHOLD_BYTES,
+    TELEGRAM_LOCAL_MODE, WEB_CLIENT,
 };
 
-/// Download a file given it's file ID and a bot instance
-pub async fn download_file<'a>(
+/// Download a file given it's file ID and a bot instance.
+///
+/// The media cache is checked first, and fresh downloads are added to it. Files bigger than
+/// `SPOOL_THRESHOLD_BYTES` are streamed into the spool directory instead of memory, and every
+/// download waits for a free slot and room under the bytes in flight cap.
+pub async fn download_file(
+    file_id: String,
+    file_unique_id: String,
+    file_name: String,
+    file_size: Option<u32>,
+) -> Result<FileData, Box<dyn Error + Send + Sync>> {
+    if let Some(cached) = cache_get(&file_unique_id, None, &file_name).await {
+        return Ok(cached);
+    }
+
+    let data = fetch_file(file_id, &file_name, file_size).await?;
+    cache_put(&file_unique_id, None, &data).await;
+    Ok(data)
+}
+
+/// Actually pull a file down from telegram
+async fn fetch_file(
     file_id: String,
+    file_name: &str,
     file_size: Option<u32>,
-) -> Result<InMemoryFile<'a>, Box<dyn Error + Send + Sync>> {
+) -> MyResult<FileData> {
     // Pull the bot reference
     let bot = BOT
         .get()
         .ok_or_else(|| make_error("Failed to get ref to Bot"))?;
+    fetch_from(bot, file_id, file_name, file_size, *TELEGRAM_LOCAL_MODE).await
+}
+
+/// Pull a file down through `bot`. In `local_mode`, absolute paths from the Bot API server are
+/// read straight off the disk.
+async fn fetch_from(
+    bot: &AutoSend<Bot>,
+    file_id: String,
+    file_name: &str,
+    file_size: Option<u32>,
+    local_mode: bool,
+) -> MyResult<FileData> {
+    // Wait our turn. The slot is given back once the download is done, but files kept in memory
+    // hold on to their bytes until they're dropped.
+    let _slot = DOWNLOAD_SLOTS.acquire().await?;
+    let bytes = DOWNLOAD_BYTES
+        .clone()
+        .acquire_many_owned(bytes_in_flight_permits(file_size))
+        .await?;
 
     // Get the file info from telegram
     let tg_file = bot.get_file(file_id).send().await?;
     debug!("File data: {:#?}", tg_file);
 
+    let spool = file_size
+        .map(|size| size > *SPOOL_THRESHOLD_BYTES)
+        .unwrap_or(false);
+
+    // A local Bot API server already has the file on disk, so skip the HTTP round trip
+    if local_mode && Path::new(&tg_file.file_path).is_absolute() {
+        if spool {
+            let spooled = SpooledFile::new(file_name).await?;
+            link_or_copy(Path::new(&tg_file.file_path), spooled.path()).await?;
+            return Ok(FileData::Spooled(spooled));
+        }
+        let im_file = tokio::fs::read(&tg_file.file_path).await?;
+        return Ok(FileData::Memory(im_file, Some(bytes)));
+    }
+
+    if spool {
+        let spooled = SpooledFile::new(file_name).await?;
+        let mut file = File::create(spooled.path()).await?;
+        bot.download_file(&tg_file.file_path, &mut file).await?;
+        file.flush().await?;
+        return Ok(FileData::Spooled(spooled));
+    }
+
     // Create the vec of bytes, either empty or with known size
     let mut im_file = if let Some(size) = file_size {
         Vec::with_capacity(size as usize)
@@ -36,73 +101,44 @@ pub async fn download_file<'a>(
 
     // Download the actual file
     bot.download_file(&tg_file.file_path, &mut im_file).await?;
-    // Return a cow that owns the data
-    Ok(Cow::from(im_file))
+    Ok(FileData::Memory(im_file, Some(bytes)))
 }
 
-/// Create and fire off a single webhook
-pub async fn send_one_webhook<'a>(
-    message_text: Option<String>,
-    attachment_slice: Vec<AttachmentType<'a>>,
-    webhook: &WebhookData,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let http = http::Http::new("e");
-
-    webhook.raw_webhook
-        .execute(http, true, |hook| {
-            let hook = match message_text {
-                Some(text) => hook.content(text),
-                None => hook,
-            };
-            for file in attachment_slice {
-                hook.add_file(file);
-            }
-            hook.avatar_url(&webhook.icon_url)
-                .username(&webhook.webhook_username)
-        })
+/// Stream a file from a URL into the spool, under the same download limits as telegram files
+pub async fn download_to_spool(url: &str, file_name: &str, size: u64) -> MyResult<SpooledFile> {
+    let _slot = DOWNLOAD_SLOTS.acquire().await?;
+    let _bytes = DOWNLOAD_BYTES
+        .clone()
+        .acquire_many_owned(bytes_in_flight_permits(u32::try_from(size).ok()))
         .await?;
 
-    Ok(())
+    let mut response = WEB_CLIENT.get(url).send().await?.error_for_status()?;
+    let spooled = SpooledFile::new(file_name).await?;
+    let mut file = File::create(spooled.path()).await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(spooled)
 }
 
-/// Coordinate sending many webhooks if we need to to fit under the filesize limit
-pub async fn send_all_webhooks<'a>(
-    attachments: UnifiedMessage<'a>,
-    webhooks: &[WebhookData],
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Create the vec of pending downloads
-    let discord_attachment = attachments
-        .attachments
-        .into_iter()
-        .map(|attachment| attachment.to_discord_attachment());
-
-    // wait for all to finish and discard the mistakes
-    let discord_attachments: Vec<AttachmentType> = future::join_all(discord_attachment)
-        .await
-        .into_iter()
-        .filter_map(|attachment| match attachment {
-            Ok(attachment) => Some(attachment),
-            Err(_) => {
-                warn!("Failed to convert attachment to discord attachment");
-                None
-            }
-        })
-        .collect();
-
-    if attachments.message_text.is_some() || !discord_attachments.is_empty() {
-        for webhook in webhooks {
-            send_one_webhook(
-                attachments.message_text.clone(),
-                discord_attachments.clone(),
-                webhook,
-            )
-            .await?;
-            info!("Sent one webhook");
+/// A request body for a file and its length. Spooled files are streamed off the disk instead of
+/// being read into memory first.
+pub async fn file_body(data: &FileData) -> MyResult<(reqwest::Body, u64)> {
+    match data {
+        FileData::Memory(bytes, _) => Ok((reqwest::Body::from(bytes.clone()), bytes.len() as u64)),
+        FileData::Spooled(spooled) => {
+            let file = File::open(spooled.path()).await?;
+            let length = file.metadata().await?.len();
+            Ok((reqwest::Body::wrap_stream(ReaderStream::new(file)), length))
         }
-    } else {
-        warn!("No message text or attachments to send, didn't send webhook");
     }
-    Ok(())
+}
+
+/// How many KiB permits a download takes from `DOWNLOAD_BYTES`, never more than exist in total
+fn bytes_in_flight_permits(file_size: Option<u32>) -> u32 {
+    let kib = file_size.unwrap_or(0) / 1024 + 1;
+    kib.min(*MAX_DOWNLOAD_KIB_IN_FLIGHT)
 }
 
 /// Makes a boxed error object with the message
@@ -111,6 +147,168 @@ pub fn make_error(message: &str) -> Box<dyn Error + Send + Sync> {
     Box::new(io::Error::new(io::ErrorKind::Other, message.to_string()))
 }
 
+/// The part of a file name before the extension, for when we change the extension
+pub fn file_stem(file_name: &str) -> &str {
+    Path::new(file_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file_name)
+}
+
 pub async fn make_webhook(webhook_url: &str) -> Result<Webhook, Box<dyn Error + Send + Sync>> {
-    Ok(HTTP.get_webhook_from_url(webhook_url).await.unwrap())
+    Ok(HTTP.get_webhook_from_url(webhook_url).await?)
+}
+
+/// Parse a duration written as a number and a unit, `s`, `m`, `h` or `d`, like `90s` or `6h`
+pub fn parse_duration(s: &str) -> MyResult<Duration> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| make_error(&format!("Duration `{}` is missing its unit", s)))?;
+    let amount: u64 = s[..unit_start].parse()?;
+    let seconds = match &s[unit_start..] {
+        "s" => amount,
+        "m" => amount * 60,
+        "h" => amount * 60 * 60,
+        "d" => amount * 60 * 60 * 24,
+        unit => return Err(make_error(&format!("Unknown duration unit `{}`", unit))),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// How long a state writer waits for more changes before saving, so a burst of them is one write
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// Run `save` a little while after `changed` is notified, off the async runtime
+pub fn spawn_state_writer<F>(what: &'static str, changed: &'static Notify, save: F)
+where
+    F: Fn() -> MyResult<()> + Send + Sync + 'static,
+{
+    let save = Arc::new(save);
+    tokio::spawn(async move {
+        loop {
+            changed.notified().await;
+            tokio::time::sleep(SAVE_DELAY).await;
+            let save = save.clone();
+            save_state(what, move || save()).await;
+        }
+    });
+}
+
+/// Run `save` on the blocking pool, only logging when it fails
+pub async fn save_state<F>(what: &str, save: F)
+where
+    F: FnOnce() -> MyResult<()> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(save).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!("Failed to save the {}: {}", what, err),
+        Err(err) => warn!("Saving the {} panicked: {}", what, err),
+    }
+}
+
+/// Write JSON next to its final path then rename it over, so a crash can't leave half a file
+pub fn write_json_atomically<T: Serialize + ?Sized>(path: &Path, value: &T) -> MyResult<()> {
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, serde_json::to_vec(value)?)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        routing::{get, post},
+        Json, Router,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    /// Start a stand-in Bot API server whose `getFile` answers with `file_path` and that serves
+    /// `contents` for downloads of it when it is relative
+    async fn stand_in(file_path: &str, contents: &'static str) -> AutoSend<Bot> {
+        let path = file_path.to_string();
+        let get_file = post(move || {
+            let path = path.clone();
+            async move {
+                Json(json!({
+                    "ok": true,
+                    "result": {
+                        "file_id": "file-id",
+                        "file_unique_id": "unique-id",
+                        "file_size": contents.len(),
+                        "file_path": path,
+                    }
+                }))
+            }
+        });
+        let mut app = Router::new()
+            .route("/botTOKEN/GetFile", get_file.clone())
+            .route("/botTOKEN/getFile", get_file);
+        if !file_path.starts_with('/') {
+            app = app.route(
+                &format!("/file/botTOKEN/{}", file_path),
+                get(move || async move { contents }),
+            );
+        }
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        Bot::new("TOKEN")
+            .set_api_url(url.parse().unwrap())
+            .auto_send()
+    }
+
+    fn memory(data: FileData) -> Vec<u8> {
+        match data {
+            FileData::Memory(bytes, _) => bytes,
+            FileData::Spooled(_) => panic!("Expected the file in memory"),
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_over_http() {
+        let bot = stand_in("documents/report.txt", "from the server").await;
+        let data = fetch_from(&bot, "file-id".to_string(), "report.txt", Some(15), false)
+            .await
+            .unwrap();
+        // The bytes keep counting against the download limit for as long as they're around
+        assert!(matches!(data, FileData::Memory(_, Some(_))));
+        assert_eq!(memory(data), b"from the server");
+    }
+
+    #[tokio::test]
+    async fn reads_local_files_straight_off_the_disk() {
+        let path = std::env::temp_dir().join(format!("local-mode-{}.txt", std::process::id()));
+        std::fs::write(&path, "from the disk").unwrap();
+
+        // The stand-in has no download route for absolute paths, so this proves the disk was read
+        let bot = stand_in(path.to_str().unwrap(), "from the server").await;
+        let data = fetch_from(&bot, "file-id".to_string(), "report.txt", Some(13), true)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(memory(data), b"from the disk");
+    }
+
+    #[tokio::test]
+    async fn spooled_files_are_streamed() {
+        let (body, length) = file_body(&FileData::Memory(b"in memory".to_vec(), None))
+            .await
+            .unwrap();
+        assert_eq!(body.as_bytes(), Some(&b"in memory"[..]));
+        assert_eq!(length, 9);
+
+        let spooled = SpooledFile::new("big.bin").await.unwrap();
+        std::fs::write(spooled.path(), vec![0; 4096]).unwrap();
+        let (body, length) = file_body(&FileData::Spooled(spooled)).await.unwrap();
+        assert_eq!(body.as_bytes(), None);
+        assert_eq!(length, 4096);
+    }
 }
@@ -0,0 +1,360 @@
+use std::{convert::Infallible, env::var, net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use log::{error, info, warn};
+use reqwest::Url;
+use serde_json::json;
+use teloxide::{
+    dispatching::{
+        stop_token::AsyncStopToken,
+        update_listeners::{StatefulListener, UpdateListener},
+    },
+    prelude::*,
+    types::Update,
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{types::MyResult, utils::make_error, WEB_CLIENT};
+
+/// Header Telegram puts the `secret_token` from `setWebhook` into on every request
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// How we get updates from Telegram
+#[derive(Debug)]
+pub enum UpdateMode {
+    /// `getUpdates` long polling, the default
+    Polling,
+    /// Telegram pushes updates to our own HTTP listener
+    Webhook(WebhookConfig),
+}
+
+#[derive(Debug)]
+pub struct WebhookConfig {
+    /// The public URL Telegram should send updates to, usually our reverse proxy
+    pub public_url: Url,
+    /// Where the embedded HTTP server listens, TLS is expected to end at the proxy
+    pub listen_addr: SocketAddr,
+    /// Shared secret Telegram sends back in the `X-Telegram-Bot-Api-Secret-Token` header
+    pub secret_token: String,
+}
+
+struct WebhookState {
+    secret_token: String,
+    updates: UnboundedSender<Result<Update, Infallible>>,
+}
+
+impl UpdateMode {
+    /// Read the update mode from the environment.
+    ///
+    /// `TELEGRAM_UPDATE_MODE` is either `polling` (default) or `webhook`. Webhook mode also needs
+    /// `TELEGRAM_WEBHOOK_URL` and `TELEGRAM_WEBHOOK_SECRET`, and can take `TELEGRAM_WEBHOOK_LISTEN`.
+    pub fn from_env() -> MyResult<Self> {
+        Self::from_vars(|key| var(key).ok())
+    }
+
+    /// Like `from_env`, but reading the settings from `get`
+    fn from_vars(get: impl Fn(&str) -> Option<String>) -> MyResult<Self> {
+        let mode = get("TELEGRAM_UPDATE_MODE").unwrap_or_else(|| "polling".to_string());
+
+        match mode.to_lowercase().as_str() {
+            "polling" => Ok(UpdateMode::Polling),
+            "webhook" => {
+                let public_url = get("TELEGRAM_WEBHOOK_URL")
+                    .ok_or_else(|| make_error("TELEGRAM_WEBHOOK_URL must be set in webhook mode"))?
+                    .parse::<Url>()
+                    .map_err(|err| {
+                        make_error(&format!("TELEGRAM_WEBHOOK_URL is invalid: {}", err))
+                    })?;
+                let listen_addr = get("TELEGRAM_WEBHOOK_LISTEN")
+                    .unwrap_or_else(|| "0.0.0.0:8080".to_string())
+                    .parse::<SocketAddr>()
+                    .map_err(|err| {
+                        make_error(&format!("TELEGRAM_WEBHOOK_LISTEN is invalid: {}", err))
+                    })?;
+                let secret_token = get("TELEGRAM_WEBHOOK_SECRET").ok_or_else(|| {
+                    make_error("TELEGRAM_WEBHOOK_SECRET must be set in webhook mode")
+                })?;
+
+                // Telegram only accepts 1-256 characters from this set
+                let valid_secret = !secret_token.is_empty()
+                    && secret_token.len() <= 256
+                    && secret_token
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if !valid_secret {
+                    return Err(make_error(
+                        "TELEGRAM_WEBHOOK_SECRET must be 1-256 characters of A-Z, a-z, 0-9, _ and -",
+                    ));
+                }
+
+                Ok(UpdateMode::Webhook(WebhookConfig {
+                    public_url,
+                    listen_addr,
+                    secret_token,
+                }))
+            }
+            other => Err(make_error(&format!(
+                "Unknown TELEGRAM_UPDATE_MODE `{}`, expected `polling` or `webhook`",
+                other
+            ))),
+        }
+    }
+}
+
+/// Register our webhook with Telegram and start the HTTP listener, returning an update listener
+/// the dispatcher can consume exactly like the polling one
+pub async fn webhook_listener(
+    bot: &AutoSend<Bot>,
+    config: WebhookConfig,
+) -> MyResult<impl UpdateListener<Infallible>> {
+    register_webhook(bot, &config).await?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = Arc::new(WebhookState {
+        secret_token: config.secret_token,
+        updates: tx,
+    });
+
+    let app = Router::new()
+        .route(config.public_url.path(), post(receive_update))
+        .layer(Extension(state));
+
+    let (stop_token, stop_flag) = AsyncStopToken::new_pair();
+
+    let server = axum::Server::try_bind(&config.listen_addr)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(stop_flag);
+
+    info!(
+        "Listening for Telegram updates on {} at path {}",
+        config.listen_addr,
+        config.public_url.path()
+    );
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("Webhook server failed: {}", err);
+        }
+    });
+
+    let stream = UnboundedReceiverStream::new(rx);
+
+    fn stream_of<S, T>(state: &mut (S, T)) -> &mut S {
+        &mut state.0
+    }
+
+    Ok(StatefulListener::new(
+        (stream, stop_token),
+        stream_of,
+        |state: &mut (_, AsyncStopToken)| state.1.clone(),
+    ))
+}
+
+/// Call `setWebhook` ourselves, since it's the only way to pass along the secret token
+async fn register_webhook(bot: &AutoSend<Bot>, config: &WebhookConfig) -> MyResult<()> {
+    let bot = bot.inner();
+    let endpoint = bot
+        .api_url()
+        .join(&format!("bot{}/setWebhook", bot.token()))?;
+
+    let response: serde_json::Value = WEB_CLIENT
+        .post(endpoint)
+        .json(&json!({
+            "url": config.public_url.as_str(),
+            "secret_token": config.secret_token,
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if response["ok"].as_bool() != Some(true) {
+        return Err(make_error(&format!(
+            "Telegram refused to set the webhook: {}",
+            response["description"].as_str().unwrap_or("no description")
+        )));
+    }
+
+    info!("Registered webhook {}", config.public_url);
+    Ok(())
+}
+
+async fn receive_update(
+    Extension(state): Extension<Arc<WebhookState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let secret_matches = headers
+        .get(SECRET_HEADER)
+        .map(|value| constant_time_eq(value.as_bytes(), state.secret_token.as_bytes()))
+        .unwrap_or(false);
+
+    if !secret_matches {
+        warn!("Rejected a webhook request with a missing or wrong secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => {
+            if state.updates.send(Ok(update)).is_err() {
+                // The dispatcher is shutting down, let telegram retry later
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+        }
+        // Telegram will keep retrying an update we reject, so swallow ones we can't parse
+        Err(err) => warn!("Failed to parse update from webhook: {}", err),
+    }
+
+    StatusCode::OK
+}
+
+/// Compare secrets without leaking how much of them matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::HeaderValue;
+    use teloxide::types::UpdateKind;
+
+    use super::*;
+
+    fn mode(vars: &[(&str, &str)]) -> MyResult<UpdateMode> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        UpdateMode::from_vars(|key| vars.get(key).cloned())
+    }
+
+    fn webhook_vars(secret: &str) -> Vec<(&str, &str)> {
+        vec![
+            ("TELEGRAM_UPDATE_MODE", "webhook"),
+            ("TELEGRAM_WEBHOOK_URL", "https://example.com/telegram"),
+            ("TELEGRAM_WEBHOOK_SECRET", secret),
+        ]
+    }
+
+    #[test]
+    fn polls_by_default() {
+        assert!(matches!(mode(&[]), Ok(UpdateMode::Polling)));
+    }
+
+    #[test]
+    fn reads_webhook_settings() {
+        let config = match mode(&webhook_vars("s3cret_token-1")) {
+            Ok(UpdateMode::Webhook(config)) => config,
+            other => panic!("Expected webhook mode, got {:?}", other),
+        };
+        assert_eq!(config.public_url.path(), "/telegram");
+        assert_eq!(
+            config.listen_addr,
+            "0.0.0.0:8080".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(config.secret_token, "s3cret_token-1");
+    }
+
+    #[test]
+    fn webhook_needs_a_valid_secret() {
+        let mut vars = webhook_vars("");
+        vars.pop();
+        assert!(mode(&vars).is_err());
+        assert!(mode(&webhook_vars("")).is_err());
+        assert!(mode(&webhook_vars("no spaces allowed")).is_err());
+        assert!(mode(&webhook_vars(&"a".repeat(257))).is_err());
+    }
+
+    #[test]
+    fn unknown_mode_is_rejected() {
+        assert!(mode(&[("TELEGRAM_UPDATE_MODE", "carrier-pigeon")]).is_err());
+    }
+
+    #[test]
+    fn secrets_compare_equal_only_when_identical() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    /// Run the handler with an optional secret header, returning the status and the update that
+    /// was passed on, if any
+    async fn handle(secret: Option<&str>, body: &str) -> (StatusCode, Option<Update>) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let state = Arc::new(WebhookState {
+            secret_token: "right".to_string(),
+            updates: tx,
+        });
+        let mut headers = HeaderMap::new();
+        if let Some(secret) = secret {
+            headers.insert(SECRET_HEADER, HeaderValue::from_str(secret).unwrap());
+        }
+
+        let status = receive_update(Extension(state), headers, Bytes::from(body.to_string())).await;
+        (status, rx.try_recv().ok().map(|update| update.unwrap()))
+    }
+
+    /// Like `handle`, but only saying whether an update was passed on
+    async fn deliver(secret: Option<&str>, body: &str) -> (StatusCode, bool) {
+        let (status, update) = handle(secret, body).await;
+        (status, update.is_some())
+    }
+
+    #[tokio::test]
+    async fn rejects_a_missing_secret() {
+        assert_eq!(deliver(None, "{}").await, (StatusCode::UNAUTHORIZED, false));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_secret() {
+        assert_eq!(
+            deliver(Some("wrong"), "{}").await,
+            (StatusCode::UNAUTHORIZED, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_the_right_secret() {
+        let body = json!({
+            "update_id": 42,
+            "channel_post": {
+                "message_id": 7,
+                "date": 1646370367,
+                "chat": { "id": -100123, "type": "channel", "title": "Test channel" },
+                "text": "hello",
+            },
+        });
+        let (status, update) = handle(Some("right"), &body.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let update = update.expect("update wasn't passed on");
+        assert_eq!(update.id, 42);
+        match update.kind {
+            UpdateKind::ChannelPost(message) => {
+                assert_eq!(message.chat.id, ChatId(-100123));
+                assert_eq!(message.id, 7);
+                assert_eq!(message.text(), Some("hello"));
+            }
+            other => panic!("Expected a channel post, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn swallows_garbage_with_the_right_secret() {
+        // Garbage is swallowed so telegram doesn't keep retrying it, but never passed on
+        assert_eq!(
+            deliver(Some("right"), "not json").await,
+            (StatusCode::OK, false)
+        );
+    }
+}